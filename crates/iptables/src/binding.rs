@@ -1,6 +1,13 @@
 use super::*;
 use anyhow::Result;
-use std::process::Command;
+use runner::{CommandLog, CommandRunner};
+use std::{
+    collections::HashMap,
+    fmt,
+    process::Command,
+    sync::{Arc, Mutex},
+    thread,
+};
 use tracing::debug;
 
 macro_rules! add_value_flag {
@@ -14,18 +21,228 @@ macro_rules! add_value_flag {
     };
 }
 
+/// Flag methods of [`IptablesBinding`] shared by all bindings with an `arg` method
+macro_rules! add_flags {
+    () => {
+        add_value_flag!(module, "--match");
+        add_value_flag!(insert, "--insert");
+        add_value_flag!(append, "--append");
+        add_value_flag!(new_chain, "--new");
+        add_value_flag!(delete_chain, "--delete-chain");
+        add_value_flag!(delete, "--delete");
+        add_value_flag!(check, "--check");
+        add_value_flag!(flush, "--flush");
+        add_value_flag!(table, "--table");
+        add_value_flag!(protocol, "--protocol");
+        add_value_flag!(jump, "--jump");
+        add_value_flag!(dport, "--dport");
+
+        fn insert_at(&mut self, chain: &str, rulenum: usize) -> &mut Self {
+            debug!(
+                flag = "--insert",
+                value = chain,
+                rulenum = rulenum,
+                "Add flag to iptables command",
+            );
+            let rulenum = rulenum.to_string();
+            self.arg("--insert");
+            self.arg(chain);
+            self.arg(rulenum);
+            self
+        }
+
+        fn mark(&mut self, value: &str, invert: Option<bool>) -> &mut Self {
+            debug!(
+                flag = "--mark",
+                value = value,
+                invert = invert,
+                "Add flag to iptables command",
+            );
+            if let Some(true) = invert {
+                self.arg("!");
+            }
+            self.arg("--mark");
+            self.arg(value);
+            self
+        }
+
+        fn out_interface(&mut self, name: &str, invert: Option<bool>) -> &mut Self {
+            debug!(
+                flag = "--out-interface",
+                value = name,
+                invert = invert,
+                "Add flag to iptables command",
+            );
+            if let Some(true) = invert {
+                self.arg("!");
+            }
+            self.arg("--out-interface");
+            self.arg(name);
+            self
+        }
+
+        fn in_interface(&mut self, name: &str, invert: Option<bool>) -> &mut Self {
+            debug!(
+                flag = "--in-interface",
+                value = name,
+                invert = invert,
+                "Add flag to iptables command",
+            );
+            if let Some(true) = invert {
+                self.arg("!");
+            }
+            self.arg("--in-interface");
+            self.arg(name);
+            self
+        }
+
+        fn destination(&mut self, network: &str, invert: Option<bool>) -> &mut Self {
+            debug!(
+                flag = "--destination",
+                value = network,
+                invert = invert,
+                "Add flag to iptables command",
+            );
+            if let Some(true) = invert {
+                self.arg("!");
+            }
+            self.arg("--destination");
+            self.arg(network);
+            self
+        }
+
+        fn uid_owner(&mut self, uid: &str, invert: Option<bool>) -> &mut Self {
+            debug!(
+                flag = "--uid-owner",
+                value = uid,
+                invert = invert,
+                "Add flag to iptables command",
+            );
+            if let Some(true) = invert {
+                self.arg("!");
+            }
+            self.arg("--uid-owner");
+            self.arg(uid);
+            self
+        }
+
+        fn socket_exists(&mut self) -> &mut Self {
+            debug!(flag = "--socket-exists", "Add flag to iptables command");
+            self.arg("--socket-exists");
+            self
+        }
+
+        fn match_set(&mut self, name: &str, flags: &str, invert: Option<bool>) -> &mut Self {
+            debug!(
+                flag = "--match-set",
+                value = name,
+                flags = flags,
+                invert = invert,
+                "Add flag to iptables command",
+            );
+            if let Some(true) = invert {
+                self.arg("!");
+            }
+            self.arg("--match-set");
+            self.arg(name);
+            self.arg(flags);
+            self
+        }
+
+        fn connbytes(&mut self, value: &str, invert: Option<bool>) -> &mut Self {
+            debug!(
+                flag = "--connbytes",
+                value = value,
+                invert = invert,
+                "Add flag to iptables command",
+            );
+            if let Some(true) = invert {
+                self.arg("!");
+            }
+            self.arg("--connbytes");
+            self.arg(value);
+            self
+        }
+
+        add_value_flag!(connbytes_dir, "--connbytes-dir");
+        add_value_flag!(connbytes_mode, "--connbytes-mode");
+
+        fn queue_num(&mut self, value: u16) -> &mut Self {
+            debug!(
+                flag = "--queue-num",
+                value = value,
+                "Add flag to iptables command",
+            );
+            let value = value.to_string();
+            self.arg("--queue-num");
+            self.arg(value);
+            self
+        }
+
+        fn queue_bypass(&mut self) -> &mut Self {
+            debug!(flag = "--queue-bypass", "Add flag to iptables command");
+            self.arg("--queue-bypass");
+            self
+        }
+
+        fn to_ports(&mut self, value: &str) -> &mut Self {
+            debug!(
+                flag = "--to-ports",
+                value = value,
+                "Add flag to iptables command",
+            );
+            self.arg("--to-ports");
+            self.arg(value);
+            self
+        }
+
+        fn ttl_set(&mut self, value: u8) -> &mut Self {
+            debug!(
+                flag = "--ttl-set",
+                value = value,
+                "Add flag to iptables command",
+            );
+            self.arg("--ttl-set");
+            self.arg(value.to_string());
+            self
+        }
+
+        fn hl_set(&mut self, value: u8) -> &mut Self {
+            debug!(
+                flag = "--hl-set",
+                value = value,
+                "Add flag to iptables command",
+            );
+            self.arg("--hl-set");
+            self.arg(value.to_string());
+            self
+        }
+    };
+}
+
 #[derive(Debug)]
 pub struct IptablesCmd {
     path: String,
     command: Vec<String>,
+    lock: XtablesLock,
+    wait_support: WaitSupport,
+    runner: CommandRunner,
 }
 
 impl IptablesCmd {
-    pub(crate) fn new<S: AsRef<str>>(iptables_path: S) -> Self {
+    pub(crate) fn new<S: AsRef<str>>(
+        iptables_path: S,
+        lock: XtablesLock,
+        wait_support: WaitSupport,
+        runner: CommandRunner,
+    ) -> Self {
         let iptables_path = iptables_path.as_ref();
         Self {
             path: iptables_path.to_string(),
             command: Vec::new(),
+            lock,
+            wait_support,
+            runner,
         }
     }
 
@@ -33,19 +250,19 @@ impl IptablesCmd {
         let arg = arg.as_ref();
         self.command.push(arg.to_string());
     }
-}
 
-impl IptablesBinding for IptablesCmd {
-    fn run(self) -> Result<(), BindingError> {
+    fn run_once(&self) -> Result<(), BindingError> {
         let mut cmd = Command::new(&self.path);
+        let lock_args = self.lock.args(self.wait_support);
         tracing::info!(
             path = self.path,
             args = ?self.command,
+            lock_args = ?lock_args,
             "Running iptables"
         );
 
-        cmd.args(&self.command);
-        let output = cmd.output()?;
+        cmd.args(&lock_args).args(&self.command);
+        let output = self.runner.output(&mut cmd)?;
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
             let stdout = String::from_utf8_lossy(&output.stdout);
@@ -63,6 +280,18 @@ impl IptablesBinding for IptablesCmd {
                     stdout: stdout_trim.to_owned(),
                 });
             }
+            if stderr_trim.contains("xtables lock") {
+                return Err(BindingError::XtablesLock {
+                    stderr: stderr_trim.to_owned(),
+                    stdout: stdout_trim.to_owned(),
+                });
+            }
+            if stderr_trim.contains("Bad rule") {
+                return Err(BindingError::BadRule {
+                    stderr: stderr_trim.to_owned(),
+                    stdout: stdout_trim.to_owned(),
+                });
+            }
             if stderr_trim.contains("No chain/target/match by that name") {
                 return Err(BindingError::NotFoundByThatName {
                     stderr: stderr_trim.to_owned(),
@@ -76,78 +305,212 @@ impl IptablesBinding for IptablesCmd {
         }
         Ok(())
     }
+}
 
-    add_value_flag!(module, "--match");
-    add_value_flag!(insert, "--insert");
-    add_value_flag!(new_chain, "--new");
-    add_value_flag!(delete_chain, "--delete-chain");
-    add_value_flag!(delete, "--delete");
-    add_value_flag!(flush, "--flush");
-    add_value_flag!(table, "--table");
-    add_value_flag!(protocol, "--protocol");
-    add_value_flag!(jump, "--jump");
-    add_value_flag!(dport, "--dport");
-
-    fn mark(&mut self, value: &str, invert: Option<bool>) -> &mut Self {
-        debug!(
-            flag = "--mark",
-            value = value,
-            invert = invert,
-            "Add flag to iptables command",
-        );
-        if let Some(true) = invert {
-            self.arg("!");
+impl IptablesBinding for IptablesCmd {
+    fn run(self) -> Result<(), BindingError> {
+        let mut attempt = 0;
+        loop {
+            match self.run_once() {
+                Err(BindingError::XtablesLock { stderr, .. }) if attempt < self.lock.retries => {
+                    let delay = self.lock.backoff(attempt);
+                    attempt += 1;
+                    tracing::warn!(
+                        stderr = stderr,
+                        attempt = attempt,
+                        retries = self.lock.retries,
+                        delay_ms = delay.as_millis() as u64,
+                        "xtables lock is busy. Retrying.."
+                    );
+                    thread::sleep(delay);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    add_flags!();
+}
+
+#[derive(Default, Debug)]
+pub struct IptablesCmdFactory {
+    lock: XtablesLock,
+    runner: CommandRunner,
+    wait_support: WaitSupportCache,
+}
+
+impl IptablesCmdFactory {
+    pub fn new(lock: XtablesLock, runner: CommandRunner) -> Self {
+        Self {
+            lock,
+            runner,
+            wait_support: WaitSupportCache::new(runner),
         }
-        self.arg("--mark");
-        self.arg(value);
-        self
     }
+}
 
-    fn connbytes(&mut self, value: &str, invert: Option<bool>) -> &mut Self {
+impl IptablesBindingFactory for IptablesCmdFactory {
+    type Binding = IptablesCmd;
+    fn create(&self, iptables_file: &str) -> Self::Binding {
         debug!(
-            flag = "--connbytes",
-            value = value,
-            invert = invert,
-            "Add flag to iptables command",
+            iptables_file = iptables_file,
+            "Creating new IptablesCmd instance with factory"
         );
-        if let Some(true) = invert {
-            self.arg("!");
+        let wait_support = self.wait_support.get(iptables_file);
+        IptablesCmd::new(iptables_file, self.lock, wait_support, self.runner)
+    }
+}
+
+/// Lock flags of every iptables binary, detected once
+#[derive(Default, Debug)]
+struct WaitSupportCache {
+    runner: CommandRunner,
+    cache: Mutex<HashMap<String, WaitSupport>>,
+}
+
+impl WaitSupportCache {
+    fn new(runner: CommandRunner) -> Self {
+        Self {
+            runner,
+            cache: Mutex::new(HashMap::new()),
         }
-        self.arg("--connbytes");
-        self.arg(value);
-        self
     }
 
-    add_value_flag!(connbytes_dir, "--connbytes-dir");
-    add_value_flag!(connbytes_mode, "--connbytes-mode");
+    fn get(&self, iptables_file: &str) -> WaitSupport {
+        let mut cache = self
+            .cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *cache
+            .entry(iptables_file.to_string())
+            .or_insert_with(|| probe_wait_support(&self.runner, iptables_file))
+    }
+}
 
-    fn queue_num(&mut self, value: u16) -> &mut Self {
-        debug!(
-            flag = "--queue-num",
-            value = value,
-            "Add flag to iptables command",
+fn probe_wait_support(runner: &CommandRunner, iptables_file: &str) -> WaitSupport {
+    let output = match runner.output(Command::new(iptables_file).arg("--version")) {
+        Ok(output) => output,
+        Err(e) => {
+            tracing::warn!(
+                iptables_file = iptables_file,
+                error = e.to_string(),
+                "Failed to get iptables version. Lock flags are disabled"
+            );
+            return WaitSupport::default();
+        }
+    };
+    let version = String::from_utf8_lossy(&output.stdout);
+    let wait_support = WaitSupport::from_version(version.trim());
+    tracing::info!(
+        iptables_file = iptables_file,
+        version = version.trim(),
+        wait = wait_support.wait,
+        wait_secs = wait_support.wait_secs,
+        wait_interval = wait_support.wait_interval,
+        "Detected iptables lock flags"
+    );
+    wait_support
+}
+
+type OutcomeFn = dyn Fn(&[String]) -> Result<(), BindingError> + Send + Sync;
+
+/// Result of a recorded command decided from its argument vector (program first)
+#[derive(Clone)]
+pub(crate) struct Outcome(pub Arc<OutcomeFn>);
+
+impl fmt::Debug for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Outcome")
+    }
+}
+
+/// Binding that records the command line to a [`CommandLog`] instead of running it
+#[derive(Debug)]
+pub struct IptablesRecorder {
+    path: String,
+    lock_args: Vec<String>,
+    command: Vec<String>,
+    log: CommandLog,
+    outcome: Option<Outcome>,
+}
+
+impl IptablesRecorder {
+    fn arg<S: AsRef<str>>(&mut self, arg: S) {
+        let arg = arg.as_ref();
+        self.command.push(arg.to_string());
+    }
+}
+
+impl IptablesBinding for IptablesRecorder {
+    fn run(self) -> Result<(), BindingError> {
+        tracing::info!(
+            path = self.path,
+            args = ?self.command,
+            lock_args = ?self.lock_args,
+            "Recording iptables"
         );
-        let value = value.to_string();
-        self.arg("--queue-num");
-        self.arg(value);
+        let argv: Vec<String> = std::iter::once(self.path)
+            .chain(self.lock_args)
+            .chain(self.command)
+            .collect();
+        self.log.record(&argv[0], &argv[1..]);
+        match self.outcome {
+            Some(Outcome(outcome)) => outcome(&argv),
+            None => Ok(()),
+        }
+    }
+
+    add_flags!();
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct IptablesRecorderFactory {
+    log: CommandLog,
+    lock: Option<(XtablesLock, Arc<WaitSupportCache>)>,
+    outcome: Option<Outcome>,
+}
+
+impl IptablesRecorderFactory {
+    pub fn new(log: CommandLog) -> Self {
+        Self {
+            log,
+            lock: None,
+            outcome: None,
+        }
+    }
+
+    /// Let `outcome` decide the result of every recorded command instead of succeeding
+    #[cfg(feature = "testing")]
+    pub(crate) fn with_outcome(mut self, outcome: Outcome) -> Self {
+        self.outcome = Some(outcome);
         self
     }
 
-    fn queue_bypass(&mut self) -> &mut Self {
-        debug!(flag = "--queue-bypass", "Add flag to iptables command");
-        self.arg("--queue-bypass");
+    /// Record the lock arguments [`IptablesCmdFactory`] would pass,
+    /// detecting the supported ones from `iptables --version` the same way
+    pub fn with_xtables_lock(mut self, lock: XtablesLock, runner: CommandRunner) -> Self {
+        self.lock = Some((lock, Arc::new(WaitSupportCache::new(runner))));
         self
     }
 }
 
-pub struct IptablesCmdFactory;
-impl IptablesBindingFactory for IptablesCmdFactory {
-    type Binding = IptablesCmd;
+impl IptablesBindingFactory for IptablesRecorderFactory {
+    type Binding = IptablesRecorder;
     fn create(&self, iptables_file: &str) -> Self::Binding {
         debug!(
             iptables_file = iptables_file,
-            "Creating new IptablesCmd instance with factory"
+            "Creating new IptablesRecorder instance with factory"
         );
-        IptablesCmd::new(iptables_file)
+        let lock_args = match &self.lock {
+            Some((lock, wait_support)) => lock.args(wait_support.get(iptables_file)),
+            None => Vec::new(),
+        };
+        IptablesRecorder {
+            path: iptables_file.to_string(),
+            lock_args,
+            command: Vec::new(),
+            log: self.log.clone(),
+            outcome: self.outcome.clone(),
+        }
     }
 }
@@ -0,0 +1,87 @@
+use super::{
+    FirewallProvider, Iptables, IptablesBindingFactory, IptablesCmdFactory, PortSpec, RuleStatus,
+};
+use anyhow::Result;
+
+/// iptables rules with an optional ip6tables copy
+#[derive(Debug)]
+pub struct DualStack<F = IptablesCmdFactory>
+where
+    F: IptablesBindingFactory,
+{
+    pub v4: Iptables<F>,
+    pub v6: Option<Iptables<F>>,
+}
+
+impl<F> DualStack<F>
+where
+    F: IptablesBindingFactory,
+{
+    pub fn new(v4: Iptables<F>, v6: Option<Iptables<F>>) -> Self {
+        Self { v4, v6 }
+    }
+
+    /// Providers in setup order
+    pub fn families(&self) -> impl Iterator<Item = &Iptables<F>> {
+        std::iter::once(&self.v4).chain(&self.v6)
+    }
+}
+
+impl<F> FirewallProvider for DualStack<F>
+where
+    F: IptablesBindingFactory,
+{
+    fn setup_rules<I>(&self, ports_spec: I) -> Result<()>
+    where
+        I: IntoIterator<Item = PortSpec>,
+    {
+        let ports_spec: Vec<PortSpec> = ports_spec.into_iter().collect();
+        for iptables in self.families() {
+            iptables.setup_rules(ports_spec.iter().copied())?;
+        }
+        Ok(())
+    }
+
+    /// Clean every family even when one of them fails, returning the first error
+    fn clean_rules(&self) -> Result<()> {
+        let mut result = Ok(());
+        for iptables in self.families() {
+            if let Err(error) = iptables.clean_rules() {
+                tracing::error!(
+                    iptables = iptables.iptables_file(),
+                    error = format!("{:#}", error),
+                    "Failed to clean rules"
+                );
+                if result.is_ok() {
+                    result = Err(error);
+                }
+            }
+        }
+        result
+    }
+
+    fn update_rules<I, J>(&self, from: I, to: J) -> Result<()>
+    where
+        I: IntoIterator<Item = PortSpec>,
+        J: IntoIterator<Item = PortSpec>,
+    {
+        let from: Vec<PortSpec> = from.into_iter().collect();
+        let to: Vec<PortSpec> = to.into_iter().collect();
+        for iptables in self.families() {
+            iptables.update_rules(from.iter().copied(), to.iter().copied())?;
+        }
+        Ok(())
+    }
+
+    fn rule_status<I>(&self, ports_spec: I) -> Result<Vec<RuleStatus>>
+    where
+        I: IntoIterator<Item = PortSpec>,
+    {
+        let ports_spec: Vec<PortSpec> = ports_spec.into_iter().collect();
+        let mut status = Vec::new();
+        for iptables in self.families() {
+            status.extend(iptables.rule_status(ports_spec.iter().copied())?);
+        }
+        Ok(status)
+    }
+}
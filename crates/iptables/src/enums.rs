@@ -1,5 +1,9 @@
+use anyhow::{Context, Result, bail};
 use serde::{Deserialize, Serialize};
-use std::fmt::Display;
+use std::{fmt::Display, net::IpAddr, str::FromStr};
+
+/// Longest interface name accepted by the kernel (IFNAMSIZ without the trailing NUL)
+const IFNAME_MAX_LEN: usize = 15;
 
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
 #[serde(rename_all = "snake_case")]
@@ -78,3 +82,250 @@ impl AsRef<Protocol> for PortSpec {
         &self.protocol
     }
 }
+
+/// Outgoing interfaces the rules apply to.
+/// A trailing `+` is a wildcard, e.g. "rmnet+" matches "rmnet0", "rmnet_data1"
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Default, Debug)]
+#[serde(default)]
+pub struct Interfaces {
+    /// Process only traffic leaving these interfaces. Empty means every interface
+    pub include: Vec<String>,
+    /// Never process traffic leaving these interfaces
+    pub exclude: Vec<String>,
+}
+
+impl Interfaces {
+    pub fn validate(&self) -> Result<()> {
+        self.include
+            .iter()
+            .chain(&self.exclude)
+            .try_for_each(|name| validate_interface_name(name))
+    }
+}
+
+/// Router mode: process traffic of devices connected to the hotspot
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[serde(default)]
+pub struct Tethering {
+    pub enabled: bool,
+    /// Interfaces the clients are connected to
+    pub downstream: Vec<String>,
+    /// TTL (IPv4) or hop limit (IPv6) of packets forwarded from downstream
+    /// interfaces, hides tethering from the carrier. Only applied when `enabled`
+    pub ttl: Option<u8>,
+}
+
+impl Tethering {
+    pub fn validate(&self) -> Result<()> {
+        if self.ttl == Some(0) {
+            bail!("Invalid tethering TTL: must be 1-255");
+        }
+        self.downstream
+            .iter()
+            .try_for_each(|name| validate_interface_name(name))
+    }
+
+    /// TTL to set on forwarded packets, `None` while tethering is disabled
+    pub fn forwarded_ttl(&self) -> Option<u8> {
+        self.ttl.filter(|_| self.enabled)
+    }
+}
+
+impl Default for Tethering {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            // Wi-Fi hotspot, USB, Bluetooth and NCM tethering on Android
+            downstream: ["ap+", "swlan+", "wlan1", "rndis+", "bt-pan", "ncm+"]
+                .map(String::from)
+                .to_vec(),
+            ttl: None,
+        }
+    }
+}
+
+fn validate_interface_name(name: &str) -> Result<()> {
+    let base = name.strip_suffix('+').unwrap_or(name);
+    if name.is_empty() || name.len() > IFNAME_MAX_LEN {
+        bail!(
+            "Invalid interface name '{}': must be 1-{} characters long",
+            name,
+            IFNAME_MAX_LEN
+        );
+    }
+    if let Some(invalid) = base
+        .chars()
+        .find(|c| matches!(c, '+' | '/' | '!') || c.is_whitespace())
+    {
+        if invalid == '+' {
+            bail!(
+                "Invalid interface name '{}': '+' is only allowed at the end",
+                name
+            );
+        }
+        bail!(
+            "Invalid interface name '{}': must not contain {:?}",
+            name,
+            invalid
+        );
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AppMode {
+    /// Process only traffic of the listed apps
+    Include,
+    /// Process traffic of every app except the listed ones
+    #[default]
+    Exclude,
+}
+
+/// Apps (by UID) the rules apply to. The default excludes nothing
+#[derive(Clone, PartialEq, Eq, Hash, Default, Debug)]
+pub struct AppScope {
+    pub mode: AppMode,
+    pub uids: Vec<u32>,
+}
+
+/// IP protocol version handled by one iptables binary
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Family {
+    /// iptables
+    #[default]
+    V4,
+    /// ip6tables
+    V6,
+}
+
+impl Family {
+    /// nftables address family of tables and `daddr` matches
+    pub fn nft_name(&self) -> &'static str {
+        match self {
+            Self::V4 => "ip",
+            Self::V6 => "ip6",
+        }
+    }
+}
+
+/// Destination network in CIDR notation, e.g. "10.0.0.0/8" or "fe80::/10".
+/// A bare address is a single host
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(try_from = "String", into = "String")]
+pub struct Network {
+    pub address: IpAddr,
+    pub prefix: u8,
+}
+
+impl Network {
+    pub fn family(&self) -> Family {
+        match self.address {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .with_context(|| format!("Invalid network '{}'", value))?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .with_context(|| format!("Invalid network '{}'", value))?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            bail!(
+                "Invalid network '{}': prefix must be at most {}",
+                value,
+                max_prefix
+            );
+        }
+        Ok(Self { address, prefix })
+    }
+}
+
+impl TryFrom<String> for Network {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<Network> for String {
+    fn from(network: Network) -> Self {
+        network.to_string()
+    }
+}
+
+impl Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum QuicAction {
+    /// Silently drop, clients fall back after a timeout
+    Drop,
+    /// Answer with ICMP port unreachable, clients fall back at once
+    #[default]
+    Reject,
+}
+
+/// Block QUIC to force clients onto TCP
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Default, Debug)]
+#[serde(default)]
+pub struct BlockQuic {
+    pub enabled: bool,
+    pub action: QuicAction,
+    /// Block only destinations in this ipset (e.g. filled from the hostlist)
+    pub ipset: Option<String>,
+}
+
+/// Redirect DNS queries to a local resolver (dnscrypt-proxy, etc.)
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[serde(default)]
+pub struct DnsRedirect {
+    pub enabled: bool,
+    /// Port the resolver listens on. Tethered clients are redirected to
+    /// the address of the incoming interface, so it should listen on all
+    pub port: u16,
+    /// UID the resolver runs as. Its own queries are not redirected
+    pub resolver_uid: Option<u32>,
+}
+
+impl Default for DnsRedirect {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 5353,
+            resolver_uid: None,
+        }
+    }
+}
+
+/// What happens to matched traffic while nfqws is not running
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum FailMode {
+    /// Traffic passes unmodified (`--queue-bypass`)
+    #[default]
+    Open,
+    /// Traffic is dropped until nfqws is back
+    Closed,
+}
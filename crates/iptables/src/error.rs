@@ -1,4 +1,5 @@
-use std::io;
+use runner::RunError;
+use std::{io, time::Duration};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -7,13 +8,37 @@ pub enum BindingError {
     DirectoryNotEmpty { stderr: String, stdout: String },
     #[error("Chain already exists. stderr: '{stderr}' stdout: '{stdout}'")]
     ChainAlreadyExists { stderr: String, stdout: String },
+    #[error("Another app is holding the xtables lock. stderr: '{stderr}' stdout: '{stdout}'")]
+    XtablesLock { stderr: String, stdout: String },
     #[error("Not found chain/target/match by that name. stderr: '{stderr}' stdout: '{stdout}'")]
     NotFoundByThatName { stderr: String, stdout: String },
+    #[error("Rule does not exist. stderr: '{stderr}' stdout: '{stdout}'")]
+    BadRule { stderr: String, stdout: String },
     #[error("Unknown iptables error (IO). error: {error}")]
     UnknownIO {
         #[from]
         error: io::Error,
     },
+    #[error("iptables command timed out after {timeout:?}. command: '{command_line}'")]
+    Timeout {
+        command_line: String,
+        timeout: Duration,
+    },
     #[error("Unknown iptables error. stderr: '{stderr}' stdout: '{stdout}'")]
     Unknown { stderr: String, stdout: String },
 }
+
+impl From<RunError> for BindingError {
+    fn from(value: RunError) -> Self {
+        match value {
+            RunError::Timeout {
+                command_line,
+                timeout,
+            } => Self::Timeout {
+                command_line,
+                timeout,
+            },
+            RunError::Io { error, .. } => Self::UnknownIO { error },
+        }
+    }
+}
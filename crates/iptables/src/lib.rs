@@ -1,12 +1,23 @@
 mod binding;
+mod dual_stack;
 mod enums;
 mod error;
+mod lock;
+mod render;
+mod rules;
+#[cfg(feature = "testing")]
+pub mod testing;
 mod traits;
 
 use anyhow::{Context, Result};
-use binding::*;
+use std::{collections::HashMap, fs, io, path::PathBuf};
+
+pub use binding::{IptablesCmdFactory, IptablesRecorderFactory};
+pub use dual_stack::*;
 pub use enums::*;
 pub use error::*;
+pub use lock::*;
+pub use rules::*;
 pub use traits::*;
 
 const QUEUE_NUM: u16 = 200;
@@ -15,6 +26,14 @@ const CONNBYTES_VALUE: &str = "1:6";
 const CONNBYTES_DIR_VALUE: &str = "original";
 const CONNBYTES_MODE_VALUE: &str = "packets";
 const CHAIN_NAME: &str = "ZAPRET_UX";
+const FORWARD_CHAIN_NAME: &str = "ZAPRET_UX_FWD";
+const TTL_CHAIN_NAME: &str = "ZAPRET_UX_TTL";
+const QUIC_CHAIN_NAME: &str = "ZAPRET_UX_QUIC";
+const QUIC_FORWARD_CHAIN_NAME: &str = "ZAPRET_UX_QUIC_FWD";
+const QUIC_PORT: u16 = 443;
+const DNS_CHAIN_NAME: &str = "ZAPRET_UX_DNS";
+const DNS_PREROUTING_CHAIN_NAME: &str = "ZAPRET_UX_DNS_PRE";
+const DNS_PORT: u16 = 53;
 
 #[derive(Debug)]
 pub struct Iptables<F = IptablesCmdFactory>
@@ -25,6 +44,15 @@ where
     iptables_file: String,
     mark_supported: bool,
     connbytes_supported: bool,
+    interfaces: Interfaces,
+    apps: AppScope,
+    family: Family,
+    excluded_networks: Vec<Network>,
+    tethering: Tethering,
+    block_quic: BlockQuic,
+    dns_redirect: DnsRedirect,
+    fail_mode: FailMode,
+    state_file: Option<PathBuf>,
 }
 
 impl Iptables<IptablesCmdFactory> {
@@ -32,119 +60,556 @@ impl Iptables<IptablesCmdFactory> {
         iptables_file: S,
         mark_supported: bool,
         connbytes_supported: bool,
+    ) -> Self {
+        Self::with_factory(
+            IptablesCmdFactory::default(),
+            iptables_file,
+            mark_supported,
+            connbytes_supported,
+        )
+    }
+}
+
+impl<F> Iptables<F>
+where
+    F: IptablesBindingFactory,
+{
+    pub fn with_factory<S: AsRef<str>>(
+        factory: F,
+        iptables_file: S,
+        mark_supported: bool,
+        connbytes_supported: bool,
     ) -> Self {
         let iptables_file = iptables_file.as_ref();
         Self {
-            factory: IptablesCmdFactory,
+            factory,
             iptables_file: iptables_file.to_string(),
             mark_supported,
             connbytes_supported,
+            interfaces: Interfaces::default(),
+            apps: AppScope::default(),
+            family: Family::default(),
+            excluded_networks: Vec::new(),
+            tethering: Tethering::default(),
+            block_quic: BlockQuic::default(),
+            dns_redirect: DnsRedirect::default(),
+            fail_mode: FailMode::default(),
+            state_file: None,
         }
     }
+
+    /// Limit rules to traffic leaving the given interfaces
+    ///
+    /// Included interfaces get their own jump rule (`-o IFACE`),
+    /// excluded ones return from the target chain before any port rule
+    pub fn with_interfaces(mut self, interfaces: Interfaces) -> Self {
+        self.interfaces = interfaces;
+        self
+    }
+
+    /// Limit rules to traffic of the given app UIDs
+    ///
+    /// Excluded UIDs return from the target chain, included UIDs
+    /// get their own copy of every port rule
+    pub fn with_apps(mut self, apps: AppScope) -> Self {
+        self.apps = apps;
+        self
+    }
+
+    /// Set IP version of the iptables binary. Only networks of this
+    /// family are used in the rules
+    pub fn with_family(mut self, family: Family) -> Self {
+        self.family = family;
+        self
+    }
+
+    /// Never process traffic to these destination networks
+    pub fn with_excluded_networks(mut self, networks: Vec<Network>) -> Self {
+        self.excluded_networks = networks;
+        self
+    }
+
+    /// Also process traffic forwarded from downstream (hotspot) interfaces
+    pub fn with_tethering(mut self, tethering: Tethering) -> Self {
+        self.tethering = tethering;
+        self
+    }
+
+    /// Drop or reject QUIC (udp/443) so clients fall back to TCP
+    pub fn with_block_quic(mut self, block_quic: BlockQuic) -> Self {
+        self.block_quic = block_quic;
+        self
+    }
+
+    /// Redirect DNS of local apps and tethered clients to a local resolver
+    pub fn with_dns_redirect(mut self, dns_redirect: DnsRedirect) -> Self {
+        self.dns_redirect = dns_redirect;
+        self
+    }
+
+    /// Choose whether queued traffic passes or drops without nfqws
+    pub fn with_fail_mode(mut self, fail_mode: FailMode) -> Self {
+        self.fail_mode = fail_mode;
+        self
+    }
+
+    /// Remember installed chains and jumps in `path`, so cleanup removes
+    /// them even after the options they were built from change
+    pub fn with_state_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.state_file = Some(path.into());
+        self
+    }
+
+    pub fn iptables_file(&self) -> &str {
+        &self.iptables_file
+    }
+
+    pub fn family(&self) -> Family {
+        self.family
+    }
 }
 
 impl<F> Iptables<F>
 where
     F: IptablesBindingFactory,
 {
-    fn add_port_rule(&self, port_spec: &PortSpec) -> Result<()> {
-        tracing::info!(port_spec = port_spec.to_string(), "Add iptables rule");
-        let mut binding = self.factory.create(&self.iptables_file);
-        binding
-            .table("mangle")
-            .insert(CHAIN_NAME)
-            .protocol(port_spec.protocol.to_str())
-            .module(port_spec.protocol.to_str())
-            .dport(&port_spec.port.to_string());
+    /// Chains and rules installed by [`FirewallProvider::setup_rules`]
+    pub fn rule_set<I>(&self, ports_spec: I) -> RuleSet
+    where
+        I: IntoIterator<Item = PortSpec>,
+    {
+        let ports_spec: Vec<PortSpec> = ports_spec.into_iter().collect();
+        let port_rules = |chain: &str| -> Vec<Rule> {
+            ports_spec
+                .iter()
+                .map(|port_spec| self.port_rule(chain, port_spec))
+                .collect()
+        };
+
+        let mut chains = vec![Chain::new("mangle", CHAIN_NAME)];
+        let mut rules = self.chain_rules(&chains[0], true, port_rules(CHAIN_NAME));
+        if self.tethering.enabled {
+            let chain = Chain::new("mangle", FORWARD_CHAIN_NAME);
+            rules.extend(self.chain_rules(&chain, false, port_rules(FORWARD_CHAIN_NAME)));
+            chains.push(chain);
+        }
+        if let Some(ttl) = self.tethering.forwarded_ttl() {
+            chains.push(Chain::new("mangle", TTL_CHAIN_NAME));
+            let target = match self.family {
+                Family::V4 => Target::SetTtl(ttl),
+                Family::V6 => Target::SetHopLimit(ttl),
+            };
+            rules.push(Rule::new("mangle", TTL_CHAIN_NAME, Vec::new(), target));
+        }
+        if self.block_quic.enabled {
+            let chain = Chain::new("filter", QUIC_CHAIN_NAME);
+            rules.extend(self.chain_rules(&chain, true, vec![self.quic_rule(QUIC_CHAIN_NAME)]));
+            chains.push(chain);
+            if self.tethering.enabled {
+                let chain = Chain::new("filter", QUIC_FORWARD_CHAIN_NAME);
+                rules.extend(self.chain_rules(
+                    &chain,
+                    false,
+                    vec![self.quic_rule(QUIC_FORWARD_CHAIN_NAME)],
+                ));
+                chains.push(chain);
+            }
+        }
+
+        if self.dns_redirect.enabled {
+            chains.push(Chain::new("nat", DNS_CHAIN_NAME));
+            if let Some(uid) = self.dns_redirect.resolver_uid {
+                rules.push(Rule::new(
+                    "nat",
+                    DNS_CHAIN_NAME,
+                    vec![Match::UidOwner { uid, invert: false }],
+                    Target::Return,
+                ));
+            }
+            rules.extend(self.dns_rules(DNS_CHAIN_NAME));
+            if self.tethering.enabled {
+                chains.push(Chain::new("nat", DNS_PREROUTING_CHAIN_NAME));
+                rules.extend(self.dns_rules(DNS_PREROUTING_CHAIN_NAME));
+            }
+        }
+
+        let out_matches: Vec<Option<Match>> = if self.interfaces.include.is_empty() {
+            vec![None]
+        } else {
+            self.interfaces
+                .include
+                .iter()
+                .map(|name| {
+                    Some(Match::OutInterface {
+                        name: name.clone(),
+                        invert: false,
+                    })
+                })
+                .collect()
+        };
+        for out_match in &out_matches {
+            let mut matches: Vec<Match> = out_match.iter().cloned().collect();
+            if self.tethering.enabled {
+                // Forwarded packets are handled by the FORWARD chain
+                matches.push(Match::SocketExists);
+            }
+            rules.push(Rule::new(
+                "mangle",
+                "POSTROUTING",
+                matches,
+                Target::Jump(CHAIN_NAME.to_string()),
+            ));
+        }
+        // TTL is decremented before FORWARD, so the value set there leaves as is.
+        // Jumps come before the queue ones to set it before nfqws sees the packet
+        if self.tethering.forwarded_ttl().is_some() {
+            rules.extend(self.forward_jumps("mangle", TTL_CHAIN_NAME, &[None]));
+        }
+        if self.tethering.enabled {
+            rules.extend(self.forward_jumps("mangle", FORWARD_CHAIN_NAME, &out_matches));
+        }
+        if self.block_quic.enabled {
+            rules.extend(out_matches.iter().map(|out_match| {
+                Rule::new(
+                    "filter",
+                    "OUTPUT",
+                    out_match.iter().cloned().collect(),
+                    Target::Jump(QUIC_CHAIN_NAME.to_string()),
+                )
+            }));
+            if self.tethering.enabled {
+                rules.extend(self.forward_jumps("filter", QUIC_FORWARD_CHAIN_NAME, &out_matches));
+            }
+        }
+
+        if self.dns_redirect.enabled {
+            rules.push(Rule::new(
+                "nat",
+                "OUTPUT",
+                Vec::new(),
+                Target::Jump(DNS_CHAIN_NAME.to_string()),
+            ));
+            if self.tethering.enabled {
+                rules.extend(self.tethering.downstream.iter().map(|name| {
+                    Rule::new(
+                        "nat",
+                        "PREROUTING",
+                        vec![Match::InInterface {
+                            name: name.clone(),
+                            invert: false,
+                        }],
+                        Target::Jump(DNS_PREROUTING_CHAIN_NAME.to_string()),
+                    )
+                }));
+            }
+        }
+
+        RuleSet { chains, rules }
+    }
+
+    fn dns_rules(&self, chain: &str) -> Vec<Rule> {
+        [Protocol::Udp, Protocol::Tcp]
+            .into_iter()
+            .map(|protocol| {
+                Rule::new(
+                    "nat",
+                    chain,
+                    vec![
+                        Match::Protocol(protocol),
+                        Match::DestinationPort(Port::Single(DNS_PORT)),
+                    ],
+                    Target::Redirect {
+                        port: self.dns_redirect.port,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    /// FORWARD jumps to `chain` for traffic from every downstream interface
+    fn forward_jumps(&self, table: &str, chain: &str, out_matches: &[Option<Match>]) -> Vec<Rule> {
+        let mut rules = Vec::new();
+        for name in &self.tethering.downstream {
+            for out_match in out_matches {
+                let mut matches = vec![Match::InInterface {
+                    name: name.clone(),
+                    invert: false,
+                }];
+                matches.extend(out_match.iter().cloned());
+                rules.push(Rule::new(
+                    table,
+                    "FORWARD",
+                    matches,
+                    Target::Jump(chain.to_string()),
+                ));
+            }
+        }
+        rules
+    }
+
+    /// Exclusions followed by the `terminal` rules of one own chain.
+    /// App scoping only applies to `local` traffic, forwarded packets have no owner
+    fn chain_rules(&self, chain: &Chain, local: bool, terminal: Vec<Rule>) -> Vec<Rule> {
+        let return_rule = |rule_match: Match| {
+            Rule::new(&chain.table, &chain.name, vec![rule_match], Target::Return)
+        };
+
+        let mut rules: Vec<Rule> = self
+            .excluded_networks
+            .iter()
+            .filter(|network| network.family() == self.family)
+            .map(|network| {
+                return_rule(Match::Destination {
+                    network: *network,
+                    invert: false,
+                })
+            })
+            .collect();
+
+        rules.extend(self.interfaces.exclude.iter().map(|name| {
+            return_rule(Match::OutInterface {
+                name: name.clone(),
+                invert: false,
+            })
+        }));
+
+        let AppScope { mode, uids } = &self.apps;
+        let included_uids = match mode {
+            _ if !local => None,
+            AppMode::Exclude => {
+                rules.extend(uids.iter().map(|uid| {
+                    return_rule(Match::UidOwner {
+                        uid: *uid,
+                        invert: false,
+                    })
+                }));
+                None
+            }
+            AppMode::Include => Some(uids.as_slice()),
+        };
+
+        for rule in terminal {
+            match included_uids {
+                Some(uids) => rules.extend(uids.iter().map(|uid| {
+                    let mut rule = rule.clone();
+                    rule.matches.push(Match::UidOwner {
+                        uid: *uid,
+                        invert: false,
+                    });
+                    rule
+                })),
+                None => rules.push(rule),
+            }
+        }
+        rules
+    }
+
+    fn quic_rule(&self, chain: &str) -> Rule {
+        let mut matches = vec![
+            Match::Protocol(Protocol::Udp),
+            Match::DestinationPort(Port::Single(QUIC_PORT)),
+        ];
+        if let Some(ipset) = &self.block_quic.ipset {
+            matches.push(Match::Set {
+                name: ipset.clone(),
+                invert: false,
+            });
+        }
+        let target = match self.block_quic.action {
+            QuicAction::Drop => Target::Drop,
+            QuicAction::Reject => Target::Reject,
+        };
+        Rule::new("filter", chain, matches, target)
+    }
+
+    fn port_rule(&self, chain: &str, port_spec: &PortSpec) -> Rule {
+        let mut matches = vec![
+            Match::Protocol(port_spec.protocol),
+            Match::DestinationPort(port_spec.port),
+        ];
 
         if self.mark_supported {
-            tracing::info!(port_spec = port_spec.to_string(), "Add mark options");
-            binding.module("mark").mark(MARK_VALUE, Some(true));
+            tracing::debug!(port_spec = port_spec.to_string(), "Add mark options");
+            matches.push(Match::Mark {
+                value: MARK_VALUE.to_string(),
+                invert: true,
+            });
         }
 
         if self.connbytes_supported {
-            tracing::info!(proc_spec = port_spec.to_string(), "Add connbytes options");
-            binding
-                .module("connbytes")
-                .connbytes(CONNBYTES_VALUE, None)
-                .connbytes_dir(CONNBYTES_DIR_VALUE)
-                .connbytes_mode(CONNBYTES_MODE_VALUE);
+            tracing::debug!(port_spec = port_spec.to_string(), "Add connbytes options");
+            matches.push(Match::Connbytes {
+                value: CONNBYTES_VALUE.to_string(),
+                dir: CONNBYTES_DIR_VALUE.to_string(),
+                mode: CONNBYTES_MODE_VALUE.to_string(),
+                invert: false,
+            });
         }
 
-        binding.jump("NFQUEUE").queue_num(QUEUE_NUM).queue_bypass();
+        Rule::new(
+            "mangle",
+            chain,
+            matches,
+            Target::Nfqueue {
+                num: QUEUE_NUM,
+                bypass: self.fail_mode == FailMode::Open,
+            },
+        )
+    }
+
+    /// Chains and jumps to remove on cleanup: the remembered ones, or the
+    /// ones of the current options when nothing is remembered
+    fn installed_rule_set(&self) -> RuleSet {
+        let Some(path) = &self.state_file else {
+            return self.rule_set([]);
+        };
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return self.rule_set([]),
+            Err(error) => {
+                tracing::warn!(
+                    path = path.display().to_string(),
+                    error = error.to_string(),
+                    "Failed to read installed rules, cleaning up the configured ones"
+                );
+                return self.rule_set([]);
+            }
+        };
+        serde_json::from_slice(&data).unwrap_or_else(|error| {
+            tracing::warn!(
+                path = path.display().to_string(),
+                error = error.to_string(),
+                "Installed rules are corrupt, cleaning up the configured ones"
+            );
+            self.rule_set([])
+        })
+    }
+
+    /// Add chains and jumps of `rule_set` to the remembered ones before
+    /// installing them. Rules of own chains go away with their chain
+    fn remember(&self, rule_set: &RuleSet) -> Result<()> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+        let mut installed = match path.exists() {
+            true => self.installed_rule_set(),
+            false => RuleSet::default(),
+        };
+        for chain in &rule_set.chains {
+            if !installed.chains.contains(chain) {
+                installed.chains.push(chain.clone());
+            }
+        }
+        for rule in rule_set.builtin_rules() {
+            if !installed.rules.contains(rule) {
+                installed.rules.push(rule.clone());
+            }
+        }
+        let data = serde_json::to_vec(&installed)?;
+        fs::write(path, data)
+            .with_context(|| format!("Failed to save installed rules to {}", path.display()))
+    }
+
+    fn forget(&self) -> Result<()> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+        match fs::remove_file(path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => {
+                Err(error).with_context(|| format!("Failed to remove {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn append_rule(&self, rule: &Rule) -> Result<()> {
+        tracing::info!(rule = rule.to_string(), "Append iptables rule");
+        let mut binding = self.factory.create(&self.iptables_file);
+        binding.table(&rule.table).append(&rule.chain);
+        rule.apply(&mut binding);
         binding
             .run()
-            .with_context(|| format!("Failed to add port rule for {}", port_spec))?;
+            .with_context(|| format!("Failed to append rule '{}'", rule))?;
         Ok(())
     }
-}
 
-impl<F> FirewallProvider for Iptables<F>
-where
-    F: IptablesBindingFactory,
-{
-    fn setup_rules<I>(&self, ports_spec: I) -> Result<()>
-    where
-        I: IntoIterator<Item = PortSpec>,
-    {
-        tracing::info!("Setup iptables rules");
+    fn insert_rule(&self, rule: &Rule) -> Result<()> {
+        tracing::info!(rule = rule.to_string(), "Insert iptables rule");
         let mut binding = self.factory.create(&self.iptables_file);
-        tracing::info!(target_chain = CHAIN_NAME, "Create target chain");
-        binding.table("mangle").new_chain(CHAIN_NAME);
+        binding.table(&rule.table).insert(&rule.chain);
+        rule.apply(&mut binding);
         binding
             .run()
-            .with_context(|| format!("Failed to create chain {}", CHAIN_NAME))?;
+            .with_context(|| format!("Failed to insert rule '{}'", rule))?;
+        Ok(())
+    }
 
+    fn insert_rule_at(&self, rule: &Rule, rulenum: usize) -> Result<()> {
+        tracing::info!(rule = rule.to_string(), rulenum, "Insert iptables rule");
         let mut binding = self.factory.create(&self.iptables_file);
-        tracing::info!(
-            from_chain = "POSTROUTING",
-            target_chain = CHAIN_NAME,
-            "Create jump rule to target chain"
-        );
+        binding.table(&rule.table).insert_at(&rule.chain, rulenum);
+        rule.apply(&mut binding);
         binding
-            .table("mangle")
-            .insert("POSTROUTING")
-            .jump(CHAIN_NAME);
-        binding.run().with_context(|| {
-            format!("Failed to add jump rule from POSTROUTING to {}", CHAIN_NAME)
-        })?;
-
-        for port_spec in ports_spec {
-            self.add_port_rule(&port_spec)?;
-        }
-
+            .run()
+            .with_context(|| format!("Failed to insert rule '{}'", rule))?;
         Ok(())
     }
 
-    fn clean_rules(&self) -> Result<()> {
-        tracing::info!(
-            from_chain = "POSTROUTING",
-            target_chain = CHAIN_NAME,
-            "Remove jump rule to target chain"
-        );
-
+    fn delete_rule(&self, rule: &Rule) -> Result<()> {
+        tracing::info!(rule = rule.to_string(), "Remove iptables rule");
         let mut binding = self.factory.create(&self.iptables_file);
-        binding
-            .table("mangle")
-            .delete("POSTROUTING")
-            .jump(CHAIN_NAME);
+        binding.table(&rule.table).delete(&rule.chain);
+        rule.apply(&mut binding);
 
         let result = binding.run();
         match result {
-            Err(BindingError::NotFoundByThatName { stderr, stdout }) => {
+            Err(
+                BindingError::NotFoundByThatName { stderr, stdout }
+                | BindingError::BadRule { stderr, stdout },
+            ) => {
                 tracing::warn!(
                     stderr = stderr,
                     stdout = stdout,
-                    "Jump rule not found. Continuing cleanup.."
+                    "Rule not found. Continuing cleanup.."
                 );
             }
-            Err(e) => return Err(e).context("Failed to remove jump rule"),
+            Err(e) => return Err(e).with_context(|| format!("Failed to remove rule '{}'", rule)),
             Ok(_) => {}
         }
+        Ok(())
+    }
 
-        tracing::info!(target_chain = CHAIN_NAME, "Flush target chain");
+    /// Whether the rule is installed. A missing chain means a missing rule
+    fn check_rule(&self, rule: &Rule) -> Result<bool> {
         let mut binding = self.factory.create(&self.iptables_file);
-        binding.table("mangle").flush(CHAIN_NAME);
+        binding.table(&rule.table).check(&rule.chain);
+        rule.apply(&mut binding);
+
+        match binding.run() {
+            Ok(_) => Ok(true),
+            Err(BindingError::BadRule { .. } | BindingError::NotFoundByThatName { .. }) => {
+                tracing::warn!(rule = rule.to_string(), "Rule is missing");
+                Ok(false)
+            }
+            Err(e) => Err(e).with_context(|| format!("Failed to check rule '{}'", rule)),
+        }
+    }
+
+    /// Create the chain unless it already exists
+    fn ensure_chain(&self, chain: &Chain) -> Result<()> {
+        tracing::info!(target_chain = chain.name, "Create target chain");
+        let mut binding = self.factory.create(&self.iptables_file);
+        binding.table(&chain.table).new_chain(&chain.name);
+        match binding.run() {
+            Ok(_) | Err(BindingError::ChainAlreadyExists { .. }) => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("Failed to create chain {}", chain.name)),
+        }
+    }
+
+    fn remove_chain(&self, chain: &Chain) -> Result<()> {
+        tracing::info!(target_chain = chain.name, "Flush target chain");
+        let mut binding = self.factory.create(&self.iptables_file);
+        binding.table(&chain.table).flush(&chain.name);
 
         let result = binding.run();
         match result {
@@ -155,11 +620,14 @@ where
                     "Target chain not found during flush. Continuing cleanup.."
                 );
             }
-            Err(e) => return Err(e).context("Failed to flush target chain"),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to flush target chain {}", chain.name));
+            }
             Ok(_) => {
-                tracing::info!(target_chain = CHAIN_NAME, "Remove target chain");
+                tracing::info!(target_chain = chain.name, "Remove target chain");
                 let mut binding = self.factory.create(&self.iptables_file);
-                binding.table("mangle").delete_chain(CHAIN_NAME);
+                binding.table(&chain.table).delete_chain(&chain.name);
 
                 let result = binding.run();
                 match result {
@@ -170,12 +638,153 @@ where
                             "Target chain not found during deletion. Cleanup completed."
                         );
                     }
-                    Err(e) => return Err(e).with_context(|| "Failed to delete target chain"),
+                    Err(e) => {
+                        return Err(e).with_context(|| {
+                            format!("Failed to delete target chain {}", chain.name)
+                        });
+                    }
                     Ok(_) => tracing::info!("Target chain successfully removed"),
                 }
             }
         }
+        Ok(())
+    }
+}
+
+impl<F> FirewallProvider for Iptables<F>
+where
+    F: IptablesBindingFactory,
+{
+    fn setup_rules<I>(&self, ports_spec: I) -> Result<()>
+    where
+        I: IntoIterator<Item = PortSpec>,
+    {
+        tracing::info!("Setup iptables rules");
+        self.interfaces.validate()?;
+        self.tethering.validate()?;
+        let rule_set = self.rule_set(ports_spec);
+        self.remember(&rule_set)?;
+
+        for chain in &rule_set.chains {
+            tracing::info!(target_chain = chain.name, "Create target chain");
+            let mut binding = self.factory.create(&self.iptables_file);
+            binding.table(&chain.table).new_chain(&chain.name);
+            binding
+                .run()
+                .with_context(|| format!("Failed to create chain {}", chain.name))?;
+        }
+
+        for rule in rule_set.own_rules() {
+            self.append_rule(rule)?;
+        }
+
+        // Inserting puts every rule at the top, so go backwards to keep the order
+        let builtin_rules: Vec<&Rule> = rule_set.builtin_rules().collect();
+        for rule in builtin_rules.into_iter().rev() {
+            self.insert_rule(rule)?;
+        }
+
+        Ok(())
+    }
+
+    fn clean_rules(&self) -> Result<()> {
+        tracing::info!("Clean iptables rules");
+        let rule_set = self.installed_rule_set();
+
+        for rule in rule_set.builtin_rules() {
+            self.delete_rule(rule)?;
+        }
+
+        for chain in &rule_set.chains {
+            self.remove_chain(chain)?;
+        }
+
+        self.forget()
+    }
+
+    fn update_rules<I, J>(&self, from: I, to: J) -> Result<()>
+    where
+        I: IntoIterator<Item = PortSpec>,
+        J: IntoIterator<Item = PortSpec>,
+    {
+        tracing::info!("Update iptables rules");
+        self.interfaces.validate()?;
+        self.tethering.validate()?;
+        let old = self.rule_set(from);
+        let new = self.rule_set(to);
+        self.remember(&new)?;
+        let status = new
+            .rules
+            .iter()
+            .map(|rule| Ok((rule, self.check_rule(rule)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        // Every rule of a chain removed by someone else is missing
+        for chain in &new.chains {
+            let removed = status
+                .iter()
+                .filter(|(rule, _)| rule.table == chain.table && rule.chain == chain.name)
+                .all(|(_, installed)| !installed);
+            if removed || !old.chains.contains(chain) {
+                self.ensure_chain(chain)?;
+            }
+        }
+
+        // Leaves own chains with a subsequence of the new rules, so the
+        // positions below put the missing ones in place
+        for rule in old
+            .own_rules()
+            .filter(|rule| new.is_own_chain(&rule.table, &rule.chain) && !new.rules.contains(rule))
+        {
+            self.delete_rule(rule)?;
+        }
+
+        // Own chains are complete before jumps into them are added
+        let (own, builtin): (Vec<_>, Vec<_>) = status
+            .iter()
+            .partition(|(rule, _)| new.is_own_chain(&rule.table, &rule.chain));
+        let mut positions: HashMap<(&str, &str), usize> = HashMap::new();
+        for (rule, installed) in own.into_iter().chain(builtin) {
+            let position = positions
+                .entry((rule.table.as_str(), rule.chain.as_str()))
+                .or_default();
+            *position += 1;
+            if !installed {
+                self.insert_rule_at(rule, *position)?;
+            }
+        }
+
+        for rule in old.builtin_rules().filter(|rule| !new.rules.contains(rule)) {
+            self.delete_rule(rule)?;
+        }
+        for chain in old
+            .chains
+            .iter()
+            .filter(|chain| !new.chains.contains(chain))
+        {
+            self.remove_chain(chain)?;
+        }
 
         Ok(())
     }
+
+    fn rule_status<I>(&self, ports_spec: I) -> Result<Vec<RuleStatus>>
+    where
+        I: IntoIterator<Item = PortSpec>,
+    {
+        tracing::debug!("Verify iptables rules");
+        let rule_set = self.rule_set(ports_spec);
+        // Check every rule to log all of the missing ones
+        rule_set
+            .rules
+            .into_iter()
+            .map(|rule| {
+                Ok(RuleStatus {
+                    iptables: self.iptables_file.clone(),
+                    installed: self.check_rule(&rule)?,
+                    rule,
+                })
+            })
+            .collect()
+    }
 }
@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How to deal with the xtables lock held by another process (netd, firewall apps)
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(default)]
+pub struct XtablesLock {
+    /// Seconds iptables itself waits for the lock (`--wait`)
+    pub wait: u32,
+    /// Microseconds between lock attempts inside iptables (`--wait-interval`)
+    pub wait_interval: u32,
+    /// How many times a command is retried after lock contention
+    pub retries: u32,
+    /// Delay before the first retry in milliseconds. Doubled on every next retry
+    pub backoff_ms: u64,
+}
+
+impl XtablesLock {
+    /// Delay before retry number `attempt` (starting from 0)
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        runner::backoff(self.backoff_ms, attempt)
+    }
+
+    /// Arguments passed before every command, as far as the binary supports them
+    pub(crate) fn args(&self, support: WaitSupport) -> Vec<String> {
+        let mut args = Vec::new();
+        if support.wait {
+            args.push("--wait".to_string());
+            // Older binaries wait without a limit
+            if support.wait_secs {
+                args.push(self.wait.to_string());
+            }
+        }
+        if support.wait_interval {
+            args.push("--wait-interval".to_string());
+            args.push(self.wait_interval.to_string());
+        }
+        args
+    }
+}
+
+impl Default for XtablesLock {
+    fn default() -> Self {
+        Self {
+            wait: 5,
+            wait_interval: 100_000,
+            retries: 5,
+            backoff_ms: 500,
+        }
+    }
+}
+
+/// Lock related flags supported by the iptables binary
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+pub(crate) struct WaitSupport {
+    /// `--wait`, since iptables 1.4.20
+    pub wait: bool,
+    /// Seconds argument of `--wait`, since iptables 1.6.0
+    pub wait_secs: bool,
+    /// `--wait-interval`, since iptables 1.6.0
+    pub wait_interval: bool,
+}
+
+impl WaitSupport {
+    /// Detect supported flags from `iptables --version` output
+    /// (e.g. "iptables v1.8.7 (legacy)")
+    pub fn from_version(version: &str) -> Self {
+        let Some(version) = version
+            .split_whitespace()
+            .find_map(|word| word.strip_prefix('v'))
+        else {
+            return Self::default();
+        };
+
+        let mut parts = version.split('.').map(|part| part.parse::<u32>().ok());
+        let (Some(Some(major)), Some(Some(minor))) = (parts.next(), parts.next()) else {
+            return Self::default();
+        };
+        let patch = parts.next().flatten().unwrap_or(0);
+        let version = (major, minor, patch);
+
+        Self {
+            wait: version >= (1, 4, 20),
+            wait_secs: version >= (1, 6, 0),
+            wait_interval: version >= (1, 6, 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_flags_follow_version() {
+        for (version, wait, wait_secs) in [
+            ("iptables v1.4.19", false, false),
+            ("iptables v1.4.20", true, false),
+            ("iptables v1.4.21", true, false),
+            ("iptables v1.6.0", true, true),
+            ("iptables v1.8.7 (legacy)", true, true),
+            ("iptables v1.6", true, true),
+            ("iptables 1.8.7", false, false),
+        ] {
+            let support = WaitSupport::from_version(version);
+            assert_eq!(
+                (support.wait, support.wait_secs, support.wait_interval),
+                (wait, wait_secs, wait_secs),
+                "{}",
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn args_match_support() {
+        let lock = XtablesLock {
+            wait: 3,
+            wait_interval: 1000,
+            ..XtablesLock::default()
+        };
+        for (version, args) in [
+            ("iptables v1.4.19", vec![]),
+            ("iptables v1.4.20", vec!["--wait"]),
+            (
+                "iptables v1.6.0",
+                vec!["--wait", "3", "--wait-interval", "1000"],
+            ),
+        ] {
+            assert_eq!(
+                lock.args(WaitSupport::from_version(version)),
+                args,
+                "{}",
+                version
+            );
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use super::{Family, Match, Rule, RuleSet, Target};
+use std::fmt::Write;
+
+impl RuleSet {
+    /// Render `iptables-restore --noflush` input that installs the rule set
+    pub fn to_restore(&self) -> String {
+        let mut out = String::new();
+        for table in self.tables() {
+            let _ = writeln!(out, "*{}", table);
+            for chain in self.chains.iter().filter(|chain| chain.table == table) {
+                let _ = writeln!(out, ":{} - [0:0]", chain.name);
+            }
+            for rule in self.own_rules().filter(|rule| rule.table == table) {
+                let _ = writeln!(out, "-A {} {}", rule.chain, rule.spec().join(" "));
+            }
+            let mut positions: Vec<(&str, usize)> = Vec::new();
+            for rule in self.builtin_rules().filter(|rule| rule.table == table) {
+                let position = match positions.iter_mut().find(|(chain, _)| *chain == rule.chain) {
+                    Some((_, position)) => {
+                        *position += 1;
+                        *position
+                    }
+                    None => {
+                        positions.push((&rule.chain, 1));
+                        1
+                    }
+                };
+                let _ = writeln!(
+                    out,
+                    "-I {} {} {}",
+                    rule.chain,
+                    position,
+                    rule.spec().join(" ")
+                );
+            }
+            let _ = writeln!(out, "COMMIT");
+        }
+        out
+    }
+
+    /// Render `iptables-restore --noflush` input that removes the rule set
+    pub fn to_restore_cleanup(&self) -> String {
+        let mut out = String::new();
+        for table in self.tables() {
+            let _ = writeln!(out, "*{}", table);
+            for rule in self.builtin_rules().filter(|rule| rule.table == table) {
+                let _ = writeln!(out, "-D {} {}", rule.chain, rule.spec().join(" "));
+            }
+            for chain in self.chains.iter().filter(|chain| chain.table == table) {
+                let _ = writeln!(out, "-F {}", chain.name);
+            }
+            for chain in self.chains.iter().filter(|chain| chain.table == table) {
+                let _ = writeln!(out, "-X {}", chain.name);
+            }
+            let _ = writeln!(out, "COMMIT");
+        }
+        out
+    }
+
+    /// Render nftables ruleset as a single table
+    ///
+    /// # Args
+    /// * `family` - family of the iptables binary the rules are for,
+    ///   the table is created in the matching nftables family
+    /// * `name` - table name
+    pub fn to_nft(&self, family: Family, name: &str) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "table {} {} {{", family.nft_name(), name);
+
+        for chain in &self.chains {
+            let _ = writeln!(out, "\tchain {} {{", nft_chain_name(&chain.name));
+            for rule in self
+                .own_rules()
+                .filter(|rule| rule.table == chain.table && rule.chain == chain.name)
+            {
+                let _ = writeln!(out, "\t\t{}", nft_rule(rule, family));
+            }
+            let _ = writeln!(out, "\t}}");
+        }
+
+        let mut base_chains: Vec<(&str, &str)> = Vec::new();
+        for rule in self.builtin_rules() {
+            if !base_chains.contains(&(&rule.table, &rule.chain)) {
+                base_chains.push((&rule.table, &rule.chain));
+            }
+        }
+        for (table, chain) in base_chains {
+            let hook = chain.to_lowercase();
+            let _ = writeln!(out, "\tchain {}_{} {{", table, hook);
+            let _ = writeln!(
+                out,
+                "\t\ttype {} hook {} priority {}; policy accept;",
+                nft_chain_type(table, &hook),
+                hook,
+                nft_priority(table, &hook)
+            );
+            for rule in self
+                .builtin_rules()
+                .filter(|rule| rule.table == table && rule.chain == chain)
+            {
+                let _ = writeln!(out, "\t\t{}", nft_rule(rule, family));
+            }
+            let _ = writeln!(out, "\t}}");
+        }
+
+        let _ = writeln!(out, "}}");
+        out
+    }
+}
+
+fn nft_chain_name(chain: &str) -> String {
+    chain.to_lowercase()
+}
+
+fn nft_chain_type(table: &str, hook: &str) -> &'static str {
+    match (table, hook) {
+        ("nat", _) => "nat",
+        ("mangle", "output") => "route",
+        _ => "filter",
+    }
+}
+
+fn nft_priority<'a>(table: &'a str, hook: &str) -> &'a str {
+    match (table, hook) {
+        ("nat", "postrouting" | "input") => "srcnat",
+        ("nat", _) => "dstnat",
+        _ => table,
+    }
+}
+
+fn nft_rule(rule: &Rule, family: Family) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    for rule_match in &rule.matches {
+        match rule_match {
+            Match::Protocol(protocol) => parts.push(format!("meta l4proto {}", protocol)),
+            Match::DestinationPort(port) => {
+                parts.push(format!("th dport {}", port.to_string().replace(':', "-")))
+            }
+            Match::Mark { value, invert } => {
+                let op = if *invert { "!=" } else { "==" };
+                match value.split_once('/') {
+                    Some((value, mask)) => {
+                        parts.push(format!("meta mark & {} {} {}", mask, op, value))
+                    }
+                    None => parts.push(format!("meta mark {} {}", op, value)),
+                }
+            }
+            Match::Connbytes {
+                value,
+                dir,
+                mode,
+                invert,
+            } => {
+                let dir = match dir.as_str() {
+                    "both" => String::new(),
+                    dir => format!("{} ", dir),
+                };
+                let mode = match mode.as_str() {
+                    "avgpkt" => "avgpkt",
+                    "bytes" => "bytes",
+                    _ => "packets",
+                };
+                let range = match value.split_once(':') {
+                    Some((from, "")) => format!(">= {}", from),
+                    Some((from, to)) => format!("{}-{}", from, to),
+                    None => value.clone(),
+                };
+                let op = if *invert { "!= " } else { "" };
+                parts.push(format!("ct {}{} {}{}", dir, mode, op, range));
+            }
+            Match::OutInterface { name, invert } => {
+                let op = if *invert { "!= " } else { "" };
+                let name = match name.strip_suffix('+') {
+                    Some(prefix) => format!("{}*", prefix),
+                    None => name.clone(),
+                };
+                parts.push(format!("oifname {}\"{}\"", op, name));
+            }
+            Match::InInterface { name, invert } => {
+                let op = if *invert { "!= " } else { "" };
+                let name = match name.strip_suffix('+') {
+                    Some(prefix) => format!("{}*", prefix),
+                    None => name.clone(),
+                };
+                parts.push(format!("iifname {}\"{}\"", op, name));
+            }
+            // Evaluating skuid fails (and the rule doesn't match) without a socket
+            Match::SocketExists => parts.push("meta skuid >= 0".to_string()),
+            Match::Destination { network, invert } => {
+                let op = if *invert { "!= " } else { "" };
+                parts.push(format!(
+                    "{} daddr {}{}",
+                    network.family().nft_name(),
+                    op,
+                    network
+                ));
+            }
+            Match::Set { name, invert } => {
+                let op = if *invert { "!= " } else { "" };
+                parts.push(format!("{} daddr {}@{}", family.nft_name(), op, name));
+            }
+            Match::UidOwner { uid, invert } => {
+                let op = if *invert { "!= " } else { "" };
+                parts.push(format!("meta skuid {}{}", op, uid));
+            }
+        }
+    }
+
+    match &rule.target {
+        Target::Jump(chain) => parts.push(format!("jump {}", nft_chain_name(chain))),
+        Target::Nfqueue { num, bypass } => {
+            let bypass = if *bypass { " bypass" } else { "" };
+            parts.push(format!("queue num {}{}", num, bypass));
+        }
+        Target::Return => parts.push("return".to_string()),
+        Target::Drop => parts.push("drop".to_string()),
+        Target::Reject => parts.push("reject".to_string()),
+        Target::Redirect { port } => parts.push(format!("redirect to :{}", port)),
+        Target::SetTtl(value) => parts.push(format!("ip ttl set {}", value)),
+        Target::SetHopLimit(value) => parts.push(format!("ip6 hoplimit set {}", value)),
+    }
+    parts.join(" ")
+}
@@ -0,0 +1,355 @@
+use super::{IptablesBinding, Network, Port, Protocol};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+/// Match part of a rule
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Match {
+    /// Protocol with its match extension loaded
+    Protocol(Protocol),
+    /// Destination port. Requires [`Match::Protocol`] before it
+    DestinationPort(Port),
+    /// nfmark value with optional mask
+    Mark { value: String, invert: bool },
+    /// Connection bytes/packets counter
+    Connbytes {
+        value: String,
+        dir: String,
+        mode: String,
+        invert: bool,
+    },
+    /// Outgoing interface, `+` at the end is a wildcard
+    OutInterface { name: String, invert: bool },
+    /// Incoming interface, `+` at the end is a wildcard
+    InInterface { name: String, invert: bool },
+    /// UID of the socket owner. Only locally generated packets have one
+    UidOwner { uid: u32, invert: bool },
+    /// Packet belongs to a local socket, i.e. it is not forwarded
+    SocketExists,
+    /// Destination network
+    Destination { network: Network, invert: bool },
+    /// Destination address is in the ipset
+    Set { name: String, invert: bool },
+}
+
+/// What to do with a matched packet
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Target {
+    /// Jump to a user-defined chain
+    Jump(String),
+    /// Send packet to nfqueue
+    Nfqueue { num: u16, bypass: bool },
+    /// Stop traversing the current chain
+    Return,
+    /// Discard packet
+    Drop,
+    /// Discard packet and answer with an ICMP error
+    Reject,
+    /// Rewrite destination to a local port (nat table only)
+    Redirect { port: u16 },
+    /// Set IPv4 TTL and continue
+    SetTtl(u8),
+    /// Set IPv6 hop limit and continue
+    SetHopLimit(u8),
+}
+
+/// Single firewall rule
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Rule {
+    pub table: String,
+    pub chain: String,
+    pub matches: Vec<Match>,
+    pub target: Target,
+}
+
+/// Rule installed by a firewall provider and whether it is in place
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct RuleStatus {
+    /// iptables binary the rule belongs to
+    pub iptables: String,
+    pub rule: Rule,
+    pub installed: bool,
+}
+
+/// User-defined chain
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Chain {
+    pub table: String,
+    pub name: String,
+}
+
+/// Everything the firewall provider installs
+///
+/// Rules are listed in the order they end up in their chains.
+/// Rules of user-defined chains are appended, rules of built-in chains
+/// are inserted at the top so that they run before foreign rules
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+pub struct RuleSet {
+    pub chains: Vec<Chain>,
+    pub rules: Vec<Rule>,
+}
+
+impl Rule {
+    pub fn new<T, C>(table: T, chain: C, matches: Vec<Match>, target: Target) -> Self
+    where
+        T: Into<String>,
+        C: Into<String>,
+    {
+        Self {
+            table: table.into(),
+            chain: chain.into(),
+            matches,
+            target,
+        }
+    }
+
+    /// Add matches and target of the rule to the binding
+    pub fn apply<B: IptablesBinding>(&self, binding: &mut B) {
+        for rule_match in &self.matches {
+            match rule_match {
+                Match::Protocol(protocol) => {
+                    binding
+                        .protocol(protocol.to_str())
+                        .module(protocol.to_str());
+                }
+                Match::DestinationPort(port) => {
+                    binding.dport(&port.to_string());
+                }
+                Match::Mark { value, invert } => {
+                    binding.module("mark").mark(value, Some(*invert));
+                }
+                Match::Connbytes {
+                    value,
+                    dir,
+                    mode,
+                    invert,
+                } => {
+                    binding
+                        .module("connbytes")
+                        .connbytes(value, Some(*invert))
+                        .connbytes_dir(dir)
+                        .connbytes_mode(mode);
+                }
+                Match::OutInterface { name, invert } => {
+                    binding.out_interface(name, Some(*invert));
+                }
+                Match::InInterface { name, invert } => {
+                    binding.in_interface(name, Some(*invert));
+                }
+                Match::Destination { network, invert } => {
+                    binding.destination(&network.to_string(), Some(*invert));
+                }
+                Match::SocketExists => {
+                    binding.module("owner").socket_exists();
+                }
+                Match::Set { name, invert } => {
+                    binding.module("set").match_set(name, "dst", Some(*invert));
+                }
+                Match::UidOwner { uid, invert } => {
+                    binding
+                        .module("owner")
+                        .uid_owner(&uid.to_string(), Some(*invert));
+                }
+            }
+        }
+
+        match &self.target {
+            Target::Jump(chain) => {
+                binding.jump(chain);
+            }
+            Target::Nfqueue { num, bypass } => {
+                binding.jump("NFQUEUE").queue_num(*num);
+                if *bypass {
+                    binding.queue_bypass();
+                }
+            }
+            Target::Return => {
+                binding.jump("RETURN");
+            }
+            Target::Drop => {
+                binding.jump("DROP");
+            }
+            Target::Reject => {
+                binding.jump("REJECT");
+            }
+            Target::Redirect { port } => {
+                binding.jump("REDIRECT").to_ports(&port.to_string());
+            }
+            Target::SetTtl(value) => {
+                binding.jump("TTL").ttl_set(*value);
+            }
+            Target::SetHopLimit(value) => {
+                binding.jump("HL").hl_set(*value);
+            }
+        }
+    }
+
+    /// Rule specification in iptables syntax (without table and chain)
+    pub fn spec(&self) -> Vec<String> {
+        let mut spec = Vec::new();
+        for rule_match in &self.matches {
+            match rule_match {
+                Match::Protocol(protocol) => {
+                    spec.extend(
+                        ["-p", protocol.to_str(), "-m", protocol.to_str()].map(String::from),
+                    );
+                }
+                Match::DestinationPort(port) => {
+                    spec.extend(["--dport".to_string(), port.to_string()]);
+                }
+                Match::Mark { value, invert } => {
+                    spec.extend(["-m", "mark"].map(String::from));
+                    if *invert {
+                        spec.push("!".to_string());
+                    }
+                    spec.extend(["--mark".to_string(), value.clone()]);
+                }
+                Match::Connbytes {
+                    value,
+                    dir,
+                    mode,
+                    invert,
+                } => {
+                    spec.extend(["-m", "connbytes"].map(String::from));
+                    if *invert {
+                        spec.push("!".to_string());
+                    }
+                    spec.extend([
+                        "--connbytes".to_string(),
+                        value.clone(),
+                        "--connbytes-dir".to_string(),
+                        dir.clone(),
+                        "--connbytes-mode".to_string(),
+                        mode.clone(),
+                    ]);
+                }
+                Match::OutInterface { name, invert } => {
+                    if *invert {
+                        spec.push("!".to_string());
+                    }
+                    spec.extend(["-o".to_string(), name.clone()]);
+                }
+                Match::InInterface { name, invert } => {
+                    if *invert {
+                        spec.push("!".to_string());
+                    }
+                    spec.extend(["-i".to_string(), name.clone()]);
+                }
+                Match::Destination { network, invert } => {
+                    if *invert {
+                        spec.push("!".to_string());
+                    }
+                    spec.extend(["-d".to_string(), network.to_string()]);
+                }
+                Match::SocketExists => {
+                    spec.extend(["-m", "owner", "--socket-exists"].map(String::from));
+                }
+                Match::Set { name, invert } => {
+                    spec.extend(["-m", "set"].map(String::from));
+                    if *invert {
+                        spec.push("!".to_string());
+                    }
+                    spec.extend(["--match-set".to_string(), name.clone(), "dst".to_string()]);
+                }
+                Match::UidOwner { uid, invert } => {
+                    spec.extend(["-m", "owner"].map(String::from));
+                    if *invert {
+                        spec.push("!".to_string());
+                    }
+                    spec.extend(["--uid-owner".to_string(), uid.to_string()]);
+                }
+            }
+        }
+
+        match &self.target {
+            Target::Jump(chain) => spec.extend(["-j".to_string(), chain.clone()]),
+            Target::Nfqueue { num, bypass } => {
+                spec.extend(["-j".to_string(), "NFQUEUE".to_string()]);
+                spec.extend(["--queue-num".to_string(), num.to_string()]);
+                if *bypass {
+                    spec.push("--queue-bypass".to_string());
+                }
+            }
+            Target::Return => spec.extend(["-j".to_string(), "RETURN".to_string()]),
+            Target::Drop => spec.extend(["-j".to_string(), "DROP".to_string()]),
+            Target::Reject => spec.extend(["-j".to_string(), "REJECT".to_string()]),
+            Target::Redirect { port } => {
+                spec.extend(["-j", "REDIRECT", "--to-ports"].map(String::from));
+                spec.push(port.to_string());
+            }
+            Target::SetTtl(value) => {
+                spec.extend(["-j", "TTL", "--ttl-set"].map(String::from));
+                spec.push(value.to_string());
+            }
+            Target::SetHopLimit(value) => {
+                spec.extend(["-j", "HL", "--hl-set"].map(String::from));
+                spec.push(value.to_string());
+            }
+        }
+        spec
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "-t {} -A {} {}",
+            self.table,
+            self.chain,
+            self.spec().join(" ")
+        )
+    }
+}
+
+impl Chain {
+    pub fn new<T, N>(table: T, name: N) -> Self
+    where
+        T: Into<String>,
+        N: Into<String>,
+    {
+        Self {
+            table: table.into(),
+            name: name.into(),
+        }
+    }
+}
+
+impl RuleSet {
+    /// Whether the chain is created by this rule set
+    pub fn is_own_chain(&self, table: &str, chain: &str) -> bool {
+        self.chains
+            .iter()
+            .any(|own| own.table == table && own.name == chain)
+    }
+
+    /// Rules that live in built-in chains (jumps into own chains)
+    pub fn builtin_rules(&self) -> impl Iterator<Item = &Rule> {
+        self.rules
+            .iter()
+            .filter(|rule| !self.is_own_chain(&rule.table, &rule.chain))
+    }
+
+    /// Rules that live in own chains
+    pub fn own_rules(&self) -> impl Iterator<Item = &Rule> {
+        self.rules
+            .iter()
+            .filter(|rule| self.is_own_chain(&rule.table, &rule.chain))
+    }
+
+    /// Tables touched by the rule set, in order of first use
+    pub fn tables(&self) -> Vec<&str> {
+        let mut tables: Vec<&str> = Vec::new();
+        let names = self
+            .chains
+            .iter()
+            .map(|chain| chain.table.as_str())
+            .chain(self.rules.iter().map(|rule| rule.table.as_str()));
+        for table in names {
+            if !tables.contains(&table) {
+                tables.push(table);
+            }
+        }
+        tables
+    }
+}
@@ -0,0 +1,121 @@
+//! Recording test doubles for [`IptablesBindingFactory`]
+
+use super::{BindingError, IptablesBindingFactory, IptablesRecorderFactory};
+use crate::binding::{IptablesRecorder, Outcome};
+use runner::{CommandLog, shell_line};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Error injected into a matching command
+#[derive(Clone, Debug)]
+struct ScriptedFailure {
+    pattern: Vec<String>,
+    error: fn() -> BindingError,
+    remaining: Option<usize>,
+}
+
+type Failures = Arc<Mutex<Vec<ScriptedFailure>>>;
+
+/// Factory of fake iptables bindings
+///
+/// [`IptablesRecorderFactory`] recording every command as a full argument vector
+/// (program first). Commands containing a scripted pattern fail with the scripted error
+#[derive(Clone, Debug)]
+pub struct FakeIptablesFactory {
+    log: CommandLog,
+    failures: Failures,
+    recorder: IptablesRecorderFactory,
+}
+
+impl Default for FakeIptablesFactory {
+    fn default() -> Self {
+        let log = CommandLog::new();
+        let failures = Failures::default();
+        let scripted = failures.clone();
+        let recorder = IptablesRecorderFactory::new(log.clone()).with_outcome(Outcome(Arc::new(
+            move |argv: &[String]| match take_failure(&scripted, argv) {
+                Some(error) => Err(error),
+                None => Ok(()),
+            },
+        )));
+        Self {
+            log,
+            failures,
+            recorder,
+        }
+    }
+}
+
+impl FakeIptablesFactory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail every command that contains `pattern` as consecutive arguments
+    pub fn fail_when(&self, pattern: &[&str], error: fn() -> BindingError) -> &Self {
+        self.push_failure(pattern, error, None)
+    }
+
+    /// Fail the next `times` commands that contain `pattern` as consecutive arguments
+    pub fn fail_times(&self, pattern: &[&str], times: usize, error: fn() -> BindingError) -> &Self {
+        self.push_failure(pattern, error, Some(times))
+    }
+
+    /// Recorded argument vectors
+    pub fn commands(&self) -> Vec<Vec<String>> {
+        self.log.entries()
+    }
+
+    /// Recorded commands rendered as sh command lines
+    pub fn lines(&self) -> Vec<String> {
+        self.commands()
+            .iter()
+            .map(|argv| shell_line(argv))
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.log.clear();
+    }
+
+    fn push_failure(
+        &self,
+        pattern: &[&str],
+        error: fn() -> BindingError,
+        remaining: Option<usize>,
+    ) -> &Self {
+        lock(&self.failures).push(ScriptedFailure {
+            pattern: pattern.iter().map(|arg| arg.to_string()).collect(),
+            error,
+            remaining,
+        });
+        self
+    }
+}
+
+impl IptablesBindingFactory for FakeIptablesFactory {
+    type Binding = IptablesRecorder;
+    fn create(&self, iptables_file: &str) -> Self::Binding {
+        self.recorder.create(iptables_file)
+    }
+}
+
+fn take_failure(failures: &Failures, argv: &[String]) -> Option<BindingError> {
+    let mut failures = lock(failures);
+    let failure = failures
+        .iter_mut()
+        .find(|failure| failure.remaining != Some(0) && contains(argv, &failure.pattern))?;
+    if let Some(remaining) = failure.remaining.as_mut() {
+        *remaining -= 1;
+    }
+    Some((failure.error)())
+}
+
+fn lock(failures: &Failures) -> MutexGuard<'_, Vec<ScriptedFailure>> {
+    failures
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn contains(argv: &[String], pattern: &[String]) -> bool {
+    pattern.is_empty() || argv.windows(pattern.len()).any(|window| window == pattern)
+}
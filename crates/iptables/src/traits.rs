@@ -1,7 +1,7 @@
 use anyhow::Result;
 use std::fmt::Debug;
 
-use super::{BindingError, PortSpec};
+use super::{BindingError, PortSpec, RuleStatus};
 
 /// Binding for main iptables
 pub trait IptablesBinding: Debug {
@@ -20,6 +20,19 @@ pub trait IptablesBinding: Debug {
     /// * `chain` - Chain name
     fn insert(&mut self, chain: &str) -> &mut Self;
 
+    /// Insert in chain at a position
+    ///
+    /// # Args
+    /// * `chain` - Chain name
+    /// * `rulenum` - Position of the new rule, 1 is the top
+    fn insert_at(&mut self, chain: &str, rulenum: usize) -> &mut Self;
+
+    /// Append to chain
+    ///
+    /// # Args
+    /// * `chain` - Chain name
+    fn append(&mut self, chain: &str) -> &mut Self;
+
     /// Create a new user-defined chain
     ///
     /// # Args
@@ -44,6 +57,12 @@ pub trait IptablesBinding: Debug {
     /// * `chain` - Chain name
     fn delete(&mut self, chain: &str) -> &mut Self;
 
+    /// Check whether matching rule exists in chain
+    ///
+    /// # Args
+    /// * `chain` - Chain name
+    fn check(&mut self, chain: &str) -> &mut Self;
+
     /// Table to manipulate
     ///
     /// # Args
@@ -75,6 +94,45 @@ pub trait IptablesBinding: Debug {
     /// * `invert` - when true, matches everything EXCEPT specified mark
     fn mark(&mut self, value: &str, invert: Option<bool>) -> &mut Self;
 
+    /// Match outgoing interface
+    ///
+    /// # Args
+    /// * `name` - interface name, "+" at the end is a wildcard (e.g., "wlan+")
+    /// * `invert` - when true, matches every interface EXCEPT specified one
+    fn out_interface(&mut self, name: &str, invert: Option<bool>) -> &mut Self;
+
+    /// Match incoming interface
+    ///
+    /// # Args
+    /// * `name` - interface name, "+" at the end is a wildcard (e.g., "rndis+")
+    /// * `invert` - when true, matches every interface EXCEPT specified one
+    fn in_interface(&mut self, name: &str, invert: Option<bool>) -> &mut Self;
+
+    /// Match destination address
+    ///
+    /// # Args
+    /// * `network` - address with optional prefix (e.g., "10.0.0.0/8", "fe80::/10")
+    /// * `invert` - when true, matches every address EXCEPT specified network
+    fn destination(&mut self, network: &str, invert: Option<bool>) -> &mut Self;
+
+    /// Match UID of the socket owner (requires "owner" module)
+    ///
+    /// # Args
+    /// * `uid` - user id or range (e.g., "10123", "10000-19999")
+    /// * `invert` - when true, matches every UID EXCEPT specified one
+    fn uid_owner(&mut self, uid: &str, invert: Option<bool>) -> &mut Self;
+
+    /// Match packets that belong to a local socket (requires "owner" module)
+    fn socket_exists(&mut self) -> &mut Self;
+
+    /// Match address in ipset (requires "set" module)
+    ///
+    /// # Args
+    /// * `name` - ipset name
+    /// * `flags` - address to look up (e.g., "dst", "src")
+    /// * `invert` - when true, matches addresses NOT in the set
+    fn match_set(&mut self, name: &str, flags: &str, invert: Option<bool>) -> &mut Self;
+
     /// Match by connection bytes
     ///
     /// # Args
@@ -101,6 +159,24 @@ pub trait IptablesBinding: Debug {
 
     /// Bypass Queueing if no queue instance exists
     fn queue_bypass(&mut self) -> &mut Self;
+
+    /// Redirect to local port (requires "REDIRECT" target)
+    ///
+    /// # Args
+    /// * `value` - port or range (e.g., "5353", "5353-5354")
+    fn to_ports(&mut self, value: &str) -> &mut Self;
+
+    /// Set IPv4 TTL (requires "TTL" target)
+    ///
+    /// # Args
+    /// * `value` - new TTL
+    fn ttl_set(&mut self, value: u8) -> &mut Self;
+
+    /// Set IPv6 hop limit (requires "HL" target)
+    ///
+    /// # Args
+    /// * `value` - new hop limit
+    fn hl_set(&mut self, value: u8) -> &mut Self;
 }
 
 pub trait FirewallProvider {
@@ -108,6 +184,25 @@ pub trait FirewallProvider {
     where
         I: IntoIterator<Item = PortSpec>;
     fn clean_rules(&self) -> Result<()>;
+    /// Turn the rules installed for `from` into the ones for `to`, only adding
+    /// missing rules and removing stale ones. Traffic keeps being processed
+    /// by the unchanged rules meanwhile
+    fn update_rules<I, J>(&self, from: I, to: J) -> Result<()>
+    where
+        I: IntoIterator<Item = PortSpec>,
+        J: IntoIterator<Item = PortSpec>;
+    /// Every rule installed by `setup_rules` with whether it is in place
+    fn rule_status<I>(&self, ports_spec: I) -> Result<Vec<RuleStatus>>
+    where
+        I: IntoIterator<Item = PortSpec>;
+    /// Whether every rule installed by `setup_rules` is still in place
+    fn verify_rules<I>(&self, ports_spec: I) -> Result<bool>
+    where
+        I: IntoIterator<Item = PortSpec>,
+    {
+        let status = self.rule_status(ports_spec)?;
+        Ok(status.iter().all(|rule| rule.installed))
+    }
 }
 
 pub trait IptablesBindingFactory {
@@ -0,0 +1,87 @@
+use iptables::{
+    BindingError, IptablesBinding, IptablesBindingFactory, IptablesCmdFactory, XtablesLock,
+};
+use runner::CommandRunner;
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// iptables v1.8.7 failing its first `fails` commands with `stderr`.
+/// Every command is appended to `log` in the script's directory
+fn fake_iptables(name: &str, fails: u32, stderr: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("iptables-cmd-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("iptables");
+    let script = format!(
+        r#"#!/bin/sh
+dir=$(dirname "$0")
+if [ "$1" = --version ]; then echo "iptables v1.8.7 (legacy)"; exit 0; fi
+echo "$*" >> "$dir/log"
+count=$(wc -l < "$dir/log")
+if [ "$count" -le {fails} ]; then echo "{stderr}" >&2; exit 4; fi
+"#
+    );
+    fs::write(&path, script).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+fn log(iptables: &Path) -> Vec<String> {
+    fs::read_to_string(iptables.with_file_name("log"))
+        .unwrap_or_default()
+        .lines()
+        .map(String::from)
+        .collect()
+}
+
+fn factory() -> IptablesCmdFactory {
+    let lock = XtablesLock {
+        wait: 1,
+        wait_interval: 1000,
+        retries: 2,
+        backoff_ms: 1,
+    };
+    IptablesCmdFactory::new(lock, CommandRunner::new(Duration::from_secs(5)))
+}
+
+fn new_chain(factory: &IptablesCmdFactory, iptables: &Path) -> Result<(), BindingError> {
+    let mut binding = factory.create(iptables.to_str().unwrap());
+    binding.table("mangle").new_chain("ZAPRET_UX");
+    binding.run()
+}
+
+const LOCK_BUSY: &str =
+    "Another app is currently holding the xtables lock. Perhaps you want to use the -w option?";
+
+#[test]
+fn busy_xtables_lock_is_retried() {
+    let iptables = fake_iptables("retried", 2, LOCK_BUSY);
+    new_chain(&factory(), &iptables).unwrap();
+    assert_eq!(
+        log(&iptables),
+        ["--wait 1 --wait-interval 1000 --table mangle --new ZAPRET_UX"; 3]
+    );
+}
+
+#[test]
+fn retries_are_limited() {
+    let iptables = fake_iptables("limited", 3, LOCK_BUSY);
+    let error = new_chain(&factory(), &iptables).unwrap_err();
+    assert!(matches!(error, BindingError::XtablesLock { .. }), "{error}");
+    assert_eq!(log(&iptables).len(), 3);
+}
+
+#[test]
+fn other_errors_are_not_retried() {
+    let iptables = fake_iptables("not-retried", 1, "iptables: Chain already exists.");
+    let error = new_chain(&factory(), &iptables).unwrap_err();
+    assert!(
+        matches!(error, BindingError::ChainAlreadyExists { .. }),
+        "{error}"
+    );
+    assert_eq!(log(&iptables).len(), 1);
+}
@@ -0,0 +1,766 @@
+use iptables::testing::FakeIptablesFactory;
+use iptables::{
+    AppMode, AppScope, BindingError, BlockQuic, DnsRedirect, FailMode, Family, FirewallProvider,
+    Interfaces, Iptables, Network, Port, PortSpec, Protocol, QuicAction, Tethering,
+};
+use std::path::PathBuf;
+
+fn ports() -> Vec<PortSpec> {
+    vec![
+        PortSpec::new(Port::Single(80), Protocol::Tcp),
+        PortSpec::new(Port::Range(50000, 50099), Protocol::Udp),
+    ]
+}
+
+fn iptables(mark: bool, connbytes: bool) -> (Iptables<FakeIptablesFactory>, FakeIptablesFactory) {
+    let factory = FakeIptablesFactory::new();
+    let iptables = Iptables::with_factory(factory.clone(), "iptables", mark, connbytes);
+    (iptables, factory)
+}
+
+/// Fresh state file, unique per test and process
+fn state_file(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "zapret-ux-golden-{}-{}.json",
+        name,
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn not_found() -> BindingError {
+    BindingError::NotFoundByThatName {
+        stderr: "iptables: No chain/target/match by that name.".to_string(),
+        stdout: String::new(),
+    }
+}
+
+fn chain_exists() -> BindingError {
+    BindingError::ChainAlreadyExists {
+        stderr: "iptables: Chain already exists.".to_string(),
+        stdout: String::new(),
+    }
+}
+
+fn bad_rule() -> BindingError {
+    BindingError::BadRule {
+        stderr: "iptables: Bad rule (does a matching rule exist in that chain?).".to_string(),
+        stdout: String::new(),
+    }
+}
+
+fn unknown() -> BindingError {
+    BindingError::Unknown {
+        stderr: "iptables: Permission denied.".to_string(),
+        stdout: String::new(),
+    }
+}
+
+#[test]
+fn setup_creates_chain_then_rules_then_jump() {
+    let (iptables, factory) = iptables(false, false);
+    iptables.setup_rules(ports()).unwrap();
+    assert_eq!(
+        factory.lines(),
+        [
+            "iptables --table mangle --new ZAPRET_UX",
+            "iptables --table mangle --append ZAPRET_UX --protocol tcp --match tcp --dport 80 --jump NFQUEUE --queue-num 200 --queue-bypass",
+            "iptables --table mangle --append ZAPRET_UX --protocol udp --match udp --dport 50000:50099 --jump NFQUEUE --queue-num 200 --queue-bypass",
+            "iptables --table mangle --insert POSTROUTING --jump ZAPRET_UX",
+        ]
+    );
+}
+
+#[test]
+fn setup_mark_and_connbytes_combinations() {
+    let mark = " --match mark ! --mark 0x40000000/0x40000000";
+    let connbytes =
+        " --match connbytes --connbytes 1:6 --connbytes-dir original --connbytes-mode packets";
+    for (mark_supported, connbytes_supported, expected_matches) in [
+        (false, false, String::new()),
+        (true, false, mark.to_string()),
+        (false, true, connbytes.to_string()),
+        (true, true, format!("{}{}", mark, connbytes)),
+    ] {
+        let (iptables, factory) = iptables(mark_supported, connbytes_supported);
+        iptables
+            .setup_rules([PortSpec::new(Port::Single(443), Protocol::Udp)])
+            .unwrap();
+        assert_eq!(
+            factory.lines()[1],
+            format!(
+                "iptables --table mangle --append ZAPRET_UX --protocol udp --match udp --dport 443{} --jump NFQUEUE --queue-num 200 --queue-bypass",
+                expected_matches
+            ),
+            "mark: {}, connbytes: {}",
+            mark_supported,
+            connbytes_supported
+        );
+    }
+}
+
+#[test]
+fn setup_stops_when_chain_exists() {
+    let (iptables, factory) = iptables(false, false);
+    factory.fail_when(&["--new", "ZAPRET_UX"], chain_exists);
+    let error = iptables.setup_rules(ports()).unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<BindingError>(),
+        Some(BindingError::ChainAlreadyExists { .. })
+    ));
+    assert_eq!(factory.lines(), ["iptables --table mangle --new ZAPRET_UX"]);
+}
+
+#[test]
+fn cleanup_removes_jump_then_chain() {
+    let (iptables, factory) = iptables(true, true);
+    iptables.clean_rules().unwrap();
+    assert_eq!(
+        factory.lines(),
+        [
+            "iptables --table mangle --delete POSTROUTING --jump ZAPRET_UX",
+            "iptables --table mangle --flush ZAPRET_UX",
+            "iptables --table mangle --delete-chain ZAPRET_UX",
+        ]
+    );
+}
+
+#[test]
+fn cleanup_continues_without_jump_rule() {
+    let (iptables, factory) = iptables(false, false);
+    factory.fail_when(&["--delete", "POSTROUTING"], not_found);
+    iptables.clean_rules().unwrap();
+    assert_eq!(factory.commands().len(), 3);
+}
+
+#[test]
+fn cleanup_skips_chain_deletion_when_chain_is_missing() {
+    let (iptables, factory) = iptables(false, false);
+    factory
+        .fail_when(&["--delete", "POSTROUTING"], not_found)
+        .fail_when(&["--flush", "ZAPRET_UX"], not_found);
+    iptables.clean_rules().unwrap();
+    assert_eq!(
+        factory.lines(),
+        [
+            "iptables --table mangle --delete POSTROUTING --jump ZAPRET_UX",
+            "iptables --table mangle --flush ZAPRET_UX",
+        ]
+    );
+}
+
+#[test]
+fn cleanup_fails_on_unknown_error() {
+    let (iptables, factory) = iptables(false, false);
+    factory.fail_when(&["--flush"], unknown);
+    assert!(iptables.clean_rules().is_err());
+    assert_eq!(factory.commands().len(), 2);
+}
+
+#[test]
+fn scripted_failure_is_consumed() {
+    let (iptables, factory) = iptables(false, false);
+    factory.fail_times(&["--new"], 1, chain_exists);
+    assert!(iptables.setup_rules(ports()).is_err());
+    factory.clear();
+    iptables.setup_rules(ports()).unwrap();
+    assert_eq!(factory.commands().len(), 4);
+}
+
+#[test]
+fn rule_set_matches_setup() {
+    let (iptables, _) = iptables(true, false);
+    assert_eq!(
+        iptables.rule_set(ports()).to_restore(),
+        "*mangle\n\
+         :ZAPRET_UX - [0:0]\n\
+         -A ZAPRET_UX -p tcp -m tcp --dport 80 -m mark ! --mark 0x40000000/0x40000000 -j NFQUEUE --queue-num 200 --queue-bypass\n\
+         -A ZAPRET_UX -p udp -m udp --dport 50000:50099 -m mark ! --mark 0x40000000/0x40000000 -j NFQUEUE --queue-num 200 --queue-bypass\n\
+         -I POSTROUTING 1 -j ZAPRET_UX\n\
+         COMMIT\n"
+    );
+}
+
+#[test]
+fn interface_scoping() {
+    let (iptables, factory) = iptables(false, false);
+    let iptables = iptables.with_interfaces(Interfaces {
+        include: vec!["rmnet+".to_string(), "wlan0".to_string()],
+        exclude: vec!["tun+".to_string()],
+    });
+    iptables
+        .setup_rules([PortSpec::new(Port::Single(443), Protocol::Tcp)])
+        .unwrap();
+    assert_eq!(
+        factory.lines(),
+        [
+            "iptables --table mangle --new ZAPRET_UX",
+            "iptables --table mangle --append ZAPRET_UX --out-interface tun+ --jump RETURN",
+            "iptables --table mangle --append ZAPRET_UX --protocol tcp --match tcp --dport 443 --jump NFQUEUE --queue-num 200 --queue-bypass",
+            "iptables --table mangle --insert POSTROUTING --out-interface wlan0 --jump ZAPRET_UX",
+            "iptables --table mangle --insert POSTROUTING --out-interface rmnet+ --jump ZAPRET_UX",
+        ]
+    );
+
+    factory.clear();
+    iptables.clean_rules().unwrap();
+    assert_eq!(
+        factory.lines(),
+        [
+            "iptables --table mangle --delete POSTROUTING --out-interface rmnet+ --jump ZAPRET_UX",
+            "iptables --table mangle --delete POSTROUTING --out-interface wlan0 --jump ZAPRET_UX",
+            "iptables --table mangle --flush ZAPRET_UX",
+            "iptables --table mangle --delete-chain ZAPRET_UX",
+        ]
+    );
+}
+
+#[test]
+fn cleanup_removes_jumps_after_interfaces_and_fail_mode_change() {
+    let state = state_file("interfaces");
+    let (iptables, factory) = iptables(false, false);
+    iptables
+        .with_state_file(&state)
+        .with_fail_mode(FailMode::Closed)
+        .with_interfaces(Interfaces {
+            include: vec!["rmnet+".to_string()],
+            exclude: Vec::new(),
+        })
+        .setup_rules(ports())
+        .unwrap();
+    assert!(
+        !factory
+            .lines()
+            .iter()
+            .any(|line| line.contains("--queue-bypass"))
+    );
+
+    factory.clear();
+    let changed = Iptables::with_factory(factory.clone(), "iptables", false, false)
+        .with_state_file(&state)
+        .with_fail_mode(FailMode::Open)
+        .with_interfaces(Interfaces {
+            include: vec!["wlan0".to_string()],
+            exclude: Vec::new(),
+        });
+    changed.clean_rules().unwrap();
+    assert_eq!(
+        factory.lines(),
+        [
+            "iptables --table mangle --delete POSTROUTING --out-interface rmnet+ --jump ZAPRET_UX",
+            "iptables --table mangle --flush ZAPRET_UX",
+            "iptables --table mangle --delete-chain ZAPRET_UX",
+        ]
+    );
+}
+
+#[test]
+fn invalid_interface_is_rejected() {
+    let (iptables, factory) = iptables(false, false);
+    let iptables = iptables.with_interfaces(Interfaces {
+        include: vec!["wl+an".to_string()],
+        exclude: Vec::new(),
+    });
+    let error = iptables.setup_rules(ports()).unwrap_err();
+    assert!(
+        format!("{:#}", error).contains("'+' is only allowed at the end"),
+        "{:#}",
+        error
+    );
+    assert!(factory.commands().is_empty());
+
+    for (name, reason) in [
+        ("wlan/0", "must not contain '/'"),
+        ("!wlan0", "must not contain '!'"),
+        ("wlan 0", "must not contain ' '"),
+        ("wlan0_too_long_name", "must be 1-15 characters long"),
+    ] {
+        let error = Interfaces {
+            include: Vec::new(),
+            exclude: vec![name.to_string()],
+        }
+        .validate()
+        .unwrap_err();
+        assert!(error.to_string().contains(reason), "{}: {}", name, error);
+    }
+}
+
+#[test]
+fn excluded_apps_return_before_port_rules() {
+    let (iptables, factory) = iptables(false, false);
+    let iptables = iptables.with_apps(AppScope {
+        mode: AppMode::Exclude,
+        uids: vec![10123, 1010123],
+    });
+    iptables
+        .setup_rules([PortSpec::new(Port::Single(443), Protocol::Tcp)])
+        .unwrap();
+    assert_eq!(
+        factory.lines(),
+        [
+            "iptables --table mangle --new ZAPRET_UX",
+            "iptables --table mangle --append ZAPRET_UX --match owner --uid-owner 10123 --jump RETURN",
+            "iptables --table mangle --append ZAPRET_UX --match owner --uid-owner 1010123 --jump RETURN",
+            "iptables --table mangle --append ZAPRET_UX --protocol tcp --match tcp --dport 443 --jump NFQUEUE --queue-num 200 --queue-bypass",
+            "iptables --table mangle --insert POSTROUTING --jump ZAPRET_UX",
+        ]
+    );
+}
+
+#[test]
+fn included_apps_get_own_port_rules() {
+    let (iptables, factory) = iptables(false, false);
+    let iptables = iptables.with_apps(AppScope {
+        mode: AppMode::Include,
+        uids: vec![10123, 10456],
+    });
+    iptables.setup_rules(ports()).unwrap();
+    assert_eq!(
+        factory.lines(),
+        [
+            "iptables --table mangle --new ZAPRET_UX",
+            "iptables --table mangle --append ZAPRET_UX --protocol tcp --match tcp --dport 80 --match owner --uid-owner 10123 --jump NFQUEUE --queue-num 200 --queue-bypass",
+            "iptables --table mangle --append ZAPRET_UX --protocol tcp --match tcp --dport 80 --match owner --uid-owner 10456 --jump NFQUEUE --queue-num 200 --queue-bypass",
+            "iptables --table mangle --append ZAPRET_UX --protocol udp --match udp --dport 50000:50099 --match owner --uid-owner 10123 --jump NFQUEUE --queue-num 200 --queue-bypass",
+            "iptables --table mangle --append ZAPRET_UX --protocol udp --match udp --dport 50000:50099 --match owner --uid-owner 10456 --jump NFQUEUE --queue-num 200 --queue-bypass",
+            "iptables --table mangle --insert POSTROUTING --jump ZAPRET_UX",
+        ]
+    );
+}
+
+#[test]
+fn excluded_networks_of_own_family_return_first() {
+    let networks: Vec<Network> = ["10.0.0.0/8", "fc00::/7", "192.0.2.1"]
+        .iter()
+        .map(|network| network.parse().unwrap())
+        .collect();
+    for (family, expected) in [
+        (
+            Family::V4,
+            [
+                "iptables --table mangle --append ZAPRET_UX --destination 10.0.0.0/8 --jump RETURN",
+                "iptables --table mangle --append ZAPRET_UX --destination 192.0.2.1/32 --jump RETURN",
+            ],
+        ),
+        (
+            Family::V6,
+            [
+                "iptables --table mangle --append ZAPRET_UX --destination fc00::/7 --jump RETURN",
+                "iptables --table mangle --append ZAPRET_UX --out-interface tun+ --jump RETURN",
+            ],
+        ),
+    ] {
+        let (iptables, factory) = iptables(false, false);
+        let iptables = iptables
+            .with_family(family)
+            .with_excluded_networks(networks.clone())
+            .with_interfaces(Interfaces {
+                include: Vec::new(),
+                exclude: vec!["tun+".to_string()],
+            });
+        iptables.setup_rules(ports()).unwrap();
+        assert_eq!(factory.lines()[1..3], expected, "family: {:?}", family);
+    }
+}
+
+#[test]
+fn invalid_networks_are_rejected() {
+    for network in ["10.0.0.0/33", "fe80::/129", "10.0.0/8", "example.com"] {
+        assert!(network.parse::<Network>().is_err(), "{}", network);
+    }
+}
+
+#[test]
+fn tethering_adds_forward_chain_without_app_scoping() {
+    let (iptables, factory) = iptables(true, false);
+    let iptables = iptables
+        .with_apps(AppScope {
+            mode: AppMode::Exclude,
+            uids: vec![10123],
+        })
+        .with_interfaces(Interfaces {
+            include: vec!["rmnet+".to_string()],
+            exclude: Vec::new(),
+        })
+        .with_tethering(Tethering {
+            enabled: true,
+            downstream: vec!["ap+".to_string(), "rndis0".to_string()],
+            ttl: None,
+        });
+    iptables
+        .setup_rules([PortSpec::new(Port::Single(443), Protocol::Tcp)])
+        .unwrap();
+    let port_rule = "--protocol tcp --match tcp --dport 443 --match mark ! --mark 0x40000000/0x40000000 --jump NFQUEUE --queue-num 200 --queue-bypass";
+    assert_eq!(
+        factory.lines(),
+        [
+            "iptables --table mangle --new ZAPRET_UX".to_string(),
+            "iptables --table mangle --new ZAPRET_UX_FWD".to_string(),
+            "iptables --table mangle --append ZAPRET_UX --match owner --uid-owner 10123 --jump RETURN".to_string(),
+            format!("iptables --table mangle --append ZAPRET_UX {}", port_rule),
+            format!("iptables --table mangle --append ZAPRET_UX_FWD {}", port_rule),
+            "iptables --table mangle --insert FORWARD --in-interface rndis0 --out-interface rmnet+ --jump ZAPRET_UX_FWD".to_string(),
+            "iptables --table mangle --insert FORWARD --in-interface ap+ --out-interface rmnet+ --jump ZAPRET_UX_FWD".to_string(),
+            "iptables --table mangle --insert POSTROUTING --out-interface rmnet+ --match owner --socket-exists --jump ZAPRET_UX".to_string(),
+        ]
+    );
+
+    factory.clear();
+    iptables.clean_rules().unwrap();
+    assert_eq!(
+        factory.lines(),
+        [
+            "iptables --table mangle --delete POSTROUTING --out-interface rmnet+ --match owner --socket-exists --jump ZAPRET_UX",
+            "iptables --table mangle --delete FORWARD --in-interface ap+ --out-interface rmnet+ --jump ZAPRET_UX_FWD",
+            "iptables --table mangle --delete FORWARD --in-interface rndis0 --out-interface rmnet+ --jump ZAPRET_UX_FWD",
+            "iptables --table mangle --flush ZAPRET_UX",
+            "iptables --table mangle --delete-chain ZAPRET_UX",
+            "iptables --table mangle --flush ZAPRET_UX_FWD",
+            "iptables --table mangle --delete-chain ZAPRET_UX_FWD",
+        ]
+    );
+}
+
+#[test]
+fn cleanup_removes_tethering_rules_after_it_is_disabled() {
+    let state = state_file("tethering");
+    let (iptables, factory) = iptables(false, false);
+    iptables
+        .with_state_file(&state)
+        .with_tethering(Tethering {
+            enabled: true,
+            downstream: vec!["ap+".to_string()],
+            ttl: Some(65),
+        })
+        .setup_rules(ports())
+        .unwrap();
+
+    factory.clear();
+    let disabled =
+        Iptables::with_factory(factory.clone(), "iptables", false, false).with_state_file(&state);
+    disabled.clean_rules().unwrap();
+    assert_eq!(
+        factory.lines(),
+        [
+            "iptables --table mangle --delete POSTROUTING --match owner --socket-exists --jump ZAPRET_UX",
+            "iptables --table mangle --delete FORWARD --in-interface ap+ --jump ZAPRET_UX_TTL",
+            "iptables --table mangle --delete FORWARD --in-interface ap+ --jump ZAPRET_UX_FWD",
+            "iptables --table mangle --flush ZAPRET_UX",
+            "iptables --table mangle --delete-chain ZAPRET_UX",
+            "iptables --table mangle --flush ZAPRET_UX_FWD",
+            "iptables --table mangle --delete-chain ZAPRET_UX_FWD",
+            "iptables --table mangle --flush ZAPRET_UX_TTL",
+            "iptables --table mangle --delete-chain ZAPRET_UX_TTL",
+        ]
+    );
+    assert!(!state.exists());
+
+    // Nothing is remembered anymore, so the configured rules are cleaned up
+    factory.clear();
+    disabled.clean_rules().unwrap();
+    assert_eq!(factory.commands().len(), 3);
+}
+
+#[test]
+fn tethering_ttl_is_set_before_queueing() {
+    for (family, target) in [
+        (Family::V4, "--jump TTL --ttl-set 65"),
+        (Family::V6, "--jump HL --hl-set 65"),
+    ] {
+        let (iptables, factory) = iptables(false, false);
+        let iptables = iptables.with_family(family).with_tethering(Tethering {
+            enabled: true,
+            downstream: vec!["ap+".to_string()],
+            ttl: Some(65),
+        });
+        iptables.setup_rules([]).unwrap();
+        assert_eq!(
+            factory.lines(),
+            [
+                "iptables --table mangle --new ZAPRET_UX".to_string(),
+                "iptables --table mangle --new ZAPRET_UX_FWD".to_string(),
+                "iptables --table mangle --new ZAPRET_UX_TTL".to_string(),
+                format!("iptables --table mangle --append ZAPRET_UX_TTL {}", target),
+                "iptables --table mangle --insert FORWARD --in-interface ap+ --jump ZAPRET_UX_FWD"
+                    .to_string(),
+                "iptables --table mangle --insert FORWARD --in-interface ap+ --jump ZAPRET_UX_TTL"
+                    .to_string(),
+                "iptables --table mangle --insert POSTROUTING --match owner --socket-exists --jump ZAPRET_UX"
+                    .to_string(),
+            ],
+            "family: {:?}",
+            family
+        );
+    }
+
+    let (disabled, factory) = iptables(false, false);
+    let disabled = disabled.with_tethering(Tethering {
+        enabled: false,
+        downstream: vec!["ap+".to_string()],
+        ttl: Some(65),
+    });
+    disabled.setup_rules([]).unwrap();
+    assert!(
+        !factory.lines().iter().any(|line| line.contains("TTL")),
+        "{:#?}",
+        factory.lines()
+    );
+
+    let (iptables, _) = iptables(false, false);
+    let iptables = iptables.with_tethering(Tethering {
+        ttl: Some(0),
+        ..Tethering::default()
+    });
+    assert!(iptables.setup_rules(ports()).is_err());
+}
+
+#[test]
+fn block_quic_in_filter_table() {
+    let (iptables, factory) = iptables(false, false);
+    let iptables = iptables
+        .with_apps(AppScope {
+            mode: AppMode::Include,
+            uids: vec![10123],
+        })
+        .with_block_quic(BlockQuic {
+            enabled: true,
+            action: QuicAction::Drop,
+            ipset: Some("zapret_hosts".to_string()),
+        });
+    iptables.setup_rules([]).unwrap();
+    assert_eq!(
+        factory.lines(),
+        [
+            "iptables --table mangle --new ZAPRET_UX",
+            "iptables --table filter --new ZAPRET_UX_QUIC",
+            "iptables --table filter --append ZAPRET_UX_QUIC --protocol udp --match udp --dport 443 --match set --match-set zapret_hosts dst --match owner --uid-owner 10123 --jump DROP",
+            "iptables --table filter --insert OUTPUT --jump ZAPRET_UX_QUIC",
+            "iptables --table mangle --insert POSTROUTING --jump ZAPRET_UX",
+        ]
+    );
+
+    factory.clear();
+    iptables.clean_rules().unwrap();
+    assert_eq!(
+        factory.lines(),
+        [
+            "iptables --table mangle --delete POSTROUTING --jump ZAPRET_UX",
+            "iptables --table filter --delete OUTPUT --jump ZAPRET_UX_QUIC",
+            "iptables --table mangle --flush ZAPRET_UX",
+            "iptables --table mangle --delete-chain ZAPRET_UX",
+            "iptables --table filter --flush ZAPRET_UX_QUIC",
+            "iptables --table filter --delete-chain ZAPRET_UX_QUIC",
+        ]
+    );
+}
+
+#[test]
+fn cleanup_removes_quic_rules_after_they_are_disabled() {
+    let state = state_file("quic");
+    let (iptables, factory) = iptables(false, false);
+    iptables
+        .with_state_file(&state)
+        .with_tethering(Tethering {
+            enabled: true,
+            downstream: vec!["ap+".to_string()],
+            ttl: None,
+        })
+        .with_block_quic(BlockQuic {
+            enabled: true,
+            action: QuicAction::Reject,
+            ipset: None,
+        })
+        .setup_rules([])
+        .unwrap();
+
+    factory.clear();
+    let disabled = Iptables::with_factory(factory.clone(), "iptables", false, false)
+        .with_state_file(&state)
+        .with_tethering(Tethering {
+            enabled: true,
+            downstream: vec!["ap+".to_string()],
+            ttl: None,
+        });
+    disabled.clean_rules().unwrap();
+    let lines = factory.lines();
+    for line in [
+        "iptables --table filter --delete OUTPUT --jump ZAPRET_UX_QUIC",
+        "iptables --table filter --delete FORWARD --in-interface ap+ --jump ZAPRET_UX_QUIC_FWD",
+        "iptables --table filter --delete-chain ZAPRET_UX_QUIC",
+        "iptables --table filter --delete-chain ZAPRET_UX_QUIC_FWD",
+    ] {
+        assert!(
+            lines.iter().any(|l| l == line),
+            "{} not in {:#?}",
+            line,
+            lines
+        );
+    }
+}
+
+#[test]
+fn dns_is_redirected_except_for_resolver() {
+    let (iptables, factory) = iptables(false, false);
+    let iptables = iptables
+        .with_dns_redirect(DnsRedirect {
+            enabled: true,
+            port: 5354,
+            resolver_uid: Some(1053),
+        })
+        .with_tethering(Tethering {
+            enabled: true,
+            downstream: vec!["ap+".to_string()],
+            ttl: None,
+        });
+    iptables.setup_rules([]).unwrap();
+    assert_eq!(
+        factory.lines(),
+        [
+            "iptables --table mangle --new ZAPRET_UX",
+            "iptables --table mangle --new ZAPRET_UX_FWD",
+            "iptables --table nat --new ZAPRET_UX_DNS",
+            "iptables --table nat --new ZAPRET_UX_DNS_PRE",
+            "iptables --table nat --append ZAPRET_UX_DNS --match owner --uid-owner 1053 --jump RETURN",
+            "iptables --table nat --append ZAPRET_UX_DNS --protocol udp --match udp --dport 53 --jump REDIRECT --to-ports 5354",
+            "iptables --table nat --append ZAPRET_UX_DNS --protocol tcp --match tcp --dport 53 --jump REDIRECT --to-ports 5354",
+            "iptables --table nat --append ZAPRET_UX_DNS_PRE --protocol udp --match udp --dport 53 --jump REDIRECT --to-ports 5354",
+            "iptables --table nat --append ZAPRET_UX_DNS_PRE --protocol tcp --match tcp --dport 53 --jump REDIRECT --to-ports 5354",
+            "iptables --table nat --insert PREROUTING --in-interface ap+ --jump ZAPRET_UX_DNS_PRE",
+            "iptables --table nat --insert OUTPUT --jump ZAPRET_UX_DNS",
+            "iptables --table mangle --insert FORWARD --in-interface ap+ --jump ZAPRET_UX_FWD",
+            "iptables --table mangle --insert POSTROUTING --match owner --socket-exists --jump ZAPRET_UX",
+        ]
+    );
+    assert!(
+        iptables
+            .rule_set([])
+            .to_nft(Family::V4, "zapret_ux")
+            .contains("\t\tmeta l4proto udp th dport 53 redirect to :5354\n")
+    );
+}
+
+#[test]
+fn cleanup_removes_dns_redirect_after_it_is_disabled() {
+    let state = state_file("dns");
+    let (iptables, factory) = iptables(false, false);
+    iptables
+        .with_state_file(&state)
+        .with_tethering(Tethering {
+            enabled: true,
+            downstream: vec!["ap+".to_string()],
+            ttl: None,
+        })
+        .with_dns_redirect(DnsRedirect {
+            enabled: true,
+            port: 5354,
+            resolver_uid: None,
+        })
+        .setup_rules([])
+        .unwrap();
+
+    factory.clear();
+    let disabled =
+        Iptables::with_factory(factory.clone(), "iptables", false, false).with_state_file(&state);
+    disabled.clean_rules().unwrap();
+    assert_eq!(
+        factory.lines(),
+        [
+            "iptables --table mangle --delete POSTROUTING --match owner --socket-exists --jump ZAPRET_UX",
+            "iptables --table mangle --delete FORWARD --in-interface ap+ --jump ZAPRET_UX_FWD",
+            "iptables --table nat --delete OUTPUT --jump ZAPRET_UX_DNS",
+            "iptables --table nat --delete PREROUTING --in-interface ap+ --jump ZAPRET_UX_DNS_PRE",
+            "iptables --table mangle --flush ZAPRET_UX",
+            "iptables --table mangle --delete-chain ZAPRET_UX",
+            "iptables --table mangle --flush ZAPRET_UX_FWD",
+            "iptables --table mangle --delete-chain ZAPRET_UX_FWD",
+            "iptables --table nat --flush ZAPRET_UX_DNS",
+            "iptables --table nat --delete-chain ZAPRET_UX_DNS",
+            "iptables --table nat --flush ZAPRET_UX_DNS_PRE",
+            "iptables --table nat --delete-chain ZAPRET_UX_DNS_PRE",
+        ]
+    );
+}
+
+#[test]
+fn verify_reports_missing_rules() {
+    let (iptables, factory) = iptables(false, false);
+    assert!(iptables.verify_rules(ports()).unwrap());
+    assert_eq!(
+        factory.lines()[0],
+        "iptables --table mangle --check ZAPRET_UX --protocol tcp --match tcp --dport 80 --jump NFQUEUE --queue-num 200 --queue-bypass"
+    );
+
+    factory.clear();
+    factory.fail_when(&["--check", "POSTROUTING"], bad_rule);
+    assert!(!iptables.verify_rules(ports()).unwrap());
+    assert_eq!(factory.commands().len(), 3);
+    let missing: Vec<String> = iptables
+        .rule_status(ports())
+        .unwrap()
+        .iter()
+        .filter(|status| !status.installed)
+        .map(|status| status.rule.to_string())
+        .collect();
+    assert_eq!(missing, ["-t mangle -A POSTROUTING -j ZAPRET_UX"]);
+
+    factory.fail_when(&["--check"], unknown);
+    assert!(iptables.verify_rules(ports()).is_err());
+}
+
+#[test]
+fn update_touches_only_changed_rules() {
+    let (iptables, factory) = iptables(false, false);
+    let to = [
+        PortSpec::new(Port::Single(80), Protocol::Tcp),
+        PortSpec::new(Port::Single(443), Protocol::Tcp),
+    ];
+    factory.fail_times(&["--dport", "443"], 1, bad_rule);
+    iptables.update_rules(ports(), to).unwrap();
+    assert_eq!(
+        factory.lines(),
+        [
+            "iptables --table mangle --check ZAPRET_UX --protocol tcp --match tcp --dport 80 --jump NFQUEUE --queue-num 200 --queue-bypass",
+            "iptables --table mangle --check ZAPRET_UX --protocol tcp --match tcp --dport 443 --jump NFQUEUE --queue-num 200 --queue-bypass",
+            "iptables --table mangle --check POSTROUTING --jump ZAPRET_UX",
+            "iptables --table mangle --delete ZAPRET_UX --protocol udp --match udp --dport 50000:50099 --jump NFQUEUE --queue-num 200 --queue-bypass",
+            "iptables --table mangle --insert ZAPRET_UX 2 --protocol tcp --match tcp --dport 443 --jump NFQUEUE --queue-num 200 --queue-bypass",
+        ]
+    );
+}
+
+#[test]
+fn update_restores_missing_rules_in_place() {
+    let (iptables, factory) = iptables(false, false);
+    factory.fail_times(&["--check", "POSTROUTING"], 1, bad_rule);
+    iptables.update_rules(ports(), ports()).unwrap();
+    assert_eq!(
+        factory.lines()[3..],
+        ["iptables --table mangle --insert POSTROUTING 1 --jump ZAPRET_UX"]
+    );
+
+    // A removed chain is created again before its rules
+    factory.clear();
+    factory.fail_when(&["--check"], not_found);
+    iptables.update_rules(ports(), ports()).unwrap();
+    assert_eq!(
+        factory.lines()[3..],
+        [
+            "iptables --table mangle --new ZAPRET_UX",
+            "iptables --table mangle --insert ZAPRET_UX 1 --protocol tcp --match tcp --dport 80 --jump NFQUEUE --queue-num 200 --queue-bypass",
+            "iptables --table mangle --insert ZAPRET_UX 2 --protocol udp --match udp --dport 50000:50099 --jump NFQUEUE --queue-num 200 --queue-bypass",
+            "iptables --table mangle --insert POSTROUTING 1 --jump ZAPRET_UX",
+        ]
+    );
+}
+
+#[test]
+fn nft_table_matches_family() {
+    let (iptables, _) = iptables(false, false);
+    let iptables = iptables
+        .with_family(Family::V6)
+        .with_excluded_networks(vec!["fe80::/10".parse().unwrap()]);
+    let nft = iptables.rule_set(ports()).to_nft(Family::V6, "zapret_ux");
+    assert!(nft.starts_with("table ip6 zapret_ux {\n"), "{}", nft);
+    assert!(nft.contains("\t\tip6 daddr fe80::/10 return\n"), "{}", nft);
+}
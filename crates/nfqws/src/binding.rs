@@ -1,7 +1,8 @@
-use std::process::Command;
+use std::{fmt, process::Command, sync::Arc};
 
-use super::{NfqwsBinding, NfqwsBindingFactory};
-use anyhow::{Result, bail};
+use super::{NfqwsBinding, NfqwsBindingFactory, PgrepFn, PkillFn};
+use anyhow::{Context, Result, bail};
+use runner::{CommandLog, CommandRunner};
 use tracing::debug;
 
 macro_rules! add_value_flag {
@@ -26,18 +27,61 @@ macro_rules! add_value_flag {
     };
 }
 
+/// Flag methods of [`NfqwsBinding`] shared by all bindings with an `arg` method
+macro_rules! add_flags {
+    () => {
+        fn daemon(&mut self) -> &mut Self {
+            self.arg("--daemon");
+            self
+        }
+
+        add_value_flag!(debug, "--debug");
+        add_value_flag!(uid, "--uid");
+        add_value_flag!(dpi_desync_fwmark, "--dpi-desync-fwmark");
+        add_value_flag!(hostlist, "--hostlist");
+        add_value_flag!(hostlist_exclude, "--hostlist-exclude");
+        add_value_flag!(hostlist_auto, "--hostlist-auto");
+        add_value_flag!(qnum, "--qnum", u16);
+        add_value_flag!(
+            hostlist_auto_fail_threshold,
+            "--hostlist-auto-fail-threshold",
+            u32
+        );
+        add_value_flag!(hostlist_auto_fail_time, "--hostlist-auto-fail-time", u32);
+        add_value_flag!(
+            hostlist_auto_retrans_threshold,
+            "--hostlist-auto-retrans-threshold",
+            u32
+        );
+
+        fn custom_args<I, S>(&mut self, args: I) -> &mut Self
+        where
+            I: IntoIterator<Item = S>,
+            S: Into<String>,
+        {
+            for arg in args.into_iter() {
+                let arg = arg.into();
+                self.arg(arg);
+            }
+            self
+        }
+    };
+}
+
 #[derive(Debug)]
 pub struct NfqwsCmd {
     path: String,
     args: Vec<String>,
+    runner: CommandRunner,
 }
 
 impl NfqwsCmd {
-    pub(crate) fn new<S: AsRef<str>>(nfqws_path: S) -> Self {
+    pub(crate) fn new<S: AsRef<str>>(nfqws_path: S, runner: CommandRunner) -> Self {
         let nfqws_path = nfqws_path.as_ref();
         Self {
             path: nfqws_path.to_string(),
             args: Vec::new(),
+            runner,
         }
     }
 
@@ -57,7 +101,7 @@ impl NfqwsBinding for NfqwsCmd {
         );
 
         cmd.args(&self.args);
-        let output = cmd.output()?;
+        let output = self.runner.output(&mut cmd)?;
         let stderr = String::from_utf8_lossy(&output.stderr);
         let stdout = String::from_utf8_lossy(&output.stdout);
         let stderr_trim = stderr.trim();
@@ -80,44 +124,20 @@ impl NfqwsBinding for NfqwsCmd {
         Ok(())
     }
 
-    fn daemon(&mut self) -> &mut Self {
-        self.arg("--daemon");
-        self
-    }
+    add_flags!();
+}
 
-    add_value_flag!(debug, "--debug");
-    add_value_flag!(uid, "--uid");
-    add_value_flag!(dpi_desync_fwmark, "--dpi-desync-fwmark");
-    add_value_flag!(hostlist, "--hostlist");
-    add_value_flag!(hostlist_exclude, "--hostlist-exclude");
-    add_value_flag!(hostlist_auto, "--hostlist-auto");
-    add_value_flag!(qnum, "--qnum", u16);
-    add_value_flag!(
-        hostlist_auto_fail_threshold,
-        "--hostlist-auto-fail-threshold",
-        u32
-    );
-    add_value_flag!(hostlist_auto_fail_time, "--hostlist-auto-fail-time", u32);
-    add_value_flag!(
-        hostlist_auto_retrans_threshold,
-        "--hostlist-auto-retrans-threshold",
-        u32
-    );
+#[derive(Default, Debug)]
+pub struct NfqwsCmdFactory {
+    runner: CommandRunner,
+}
 
-    fn custom_args<I, S>(&mut self, args: I) -> &mut Self
-    where
-        I: IntoIterator<Item = S>,
-        S: Into<String>,
-    {
-        for arg in args.into_iter() {
-            let arg = arg.into();
-            self.arg(arg);
-        }
-        self
+impl NfqwsCmdFactory {
+    pub fn new(runner: CommandRunner) -> Self {
+        Self { runner }
     }
 }
 
-pub struct NfqwsCmdFactory;
 impl NfqwsBindingFactory for NfqwsCmdFactory {
     type Binding = NfqwsCmd;
     fn create(&self, nfqws_path: &str) -> Self::Binding {
@@ -125,11 +145,110 @@ impl NfqwsBindingFactory for NfqwsCmdFactory {
             nfqws_path = nfqws_path,
             "Creating new NfqwsCmd instance with factory"
         );
-        NfqwsCmd::new(nfqws_path)
+        NfqwsCmd::new(nfqws_path, self.runner)
+    }
+}
+
+type OutcomeFn = dyn Fn(&[String]) -> Result<()> + Send + Sync;
+
+/// Result of a recorded command decided from its argument vector (program first)
+#[derive(Clone)]
+pub(crate) struct Outcome(pub Arc<OutcomeFn>);
+
+impl fmt::Debug for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Outcome")
+    }
+}
+
+/// Binding that records the command line to a [`CommandLog`] instead of running it
+#[derive(Debug)]
+pub struct NfqwsRecorder {
+    path: String,
+    args: Vec<String>,
+    log: CommandLog,
+    outcome: Option<Outcome>,
+}
+
+impl NfqwsRecorder {
+    fn arg<S: Into<String>>(&mut self, arg: S) {
+        let arg = arg.into();
+        self.args.push(arg);
+    }
+}
+
+impl NfqwsBinding for NfqwsRecorder {
+    fn run(self) -> Result<()> {
+        tracing::info!(
+            path = self.path,
+            args = ?self.args,
+            "Recording nfqws"
+        );
+        let argv: Vec<String> = std::iter::once(self.path).chain(self.args).collect();
+        self.log.record(&argv[0], &argv[1..]);
+        match self.outcome {
+            Some(Outcome(outcome)) => outcome(&argv),
+            None => Ok(()),
+        }
+    }
+
+    add_flags!();
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct NfqwsRecorderFactory {
+    log: CommandLog,
+    outcome: Option<Outcome>,
+}
+
+impl NfqwsRecorderFactory {
+    pub fn new(log: CommandLog) -> Self {
+        Self { log, outcome: None }
+    }
+
+    /// Let `outcome` decide the result of every recorded nfqws run instead of succeeding
+    #[cfg(feature = "testing")]
+    pub(crate) fn with_outcome(mut self, outcome: Outcome) -> Self {
+        self.outcome = Some(outcome);
+        self
+    }
+
+    /// pgrep replacement that records the call and reports the process as not running
+    pub fn pgrep(&self) -> PgrepFn {
+        let log = self.log.clone();
+        Box::new(move |pgrep_path, process_name| {
+            log.record(pgrep_path, [process_name]);
+            Ok(Vec::new())
+        })
+    }
+
+    /// pkill replacement that records the call without killing anything
+    pub fn pkill(&self) -> PkillFn {
+        let log = self.log.clone();
+        Box::new(move |pkill_path, process_name| {
+            log.record(pkill_path, [process_name]);
+            Ok(())
+        })
+    }
+}
+
+impl NfqwsBindingFactory for NfqwsRecorderFactory {
+    type Binding = NfqwsRecorder;
+    fn create(&self, nfqws_path: &str) -> Self::Binding {
+        tracing::debug!(
+            nfqws_path = nfqws_path,
+            "Creating new NfqwsRecorder instance with factory"
+        );
+        NfqwsRecorder {
+            path: nfqws_path.to_string(),
+            args: Vec::new(),
+            log: self.log.clone(),
+            outcome: self.outcome.clone(),
+        }
     }
 }
 
-pub(crate) fn pkill(pkill_path: &str, process_name: &str) -> Result<()> {
+pub(crate) fn pkill(runner: &CommandRunner, pkill_path: &str, process_name: &str) -> Result<()> {
     tracing::info!(
         pkill_path = pkill_path,
         process_name = process_name,
@@ -137,7 +256,7 @@ pub(crate) fn pkill(pkill_path: &str, process_name: &str) -> Result<()> {
     );
     let mut cmd = Command::new(pkill_path);
     cmd.arg(process_name);
-    let output = cmd.output()?;
+    let output = runner.output(&mut cmd)?;
     let stdout = String::from_utf8_lossy(&output.stdout);
     let stderr = String::from_utf8_lossy(&output.stderr);
     let stdout_trim = stdout.trim();
@@ -160,7 +279,11 @@ pub(crate) fn pkill(pkill_path: &str, process_name: &str) -> Result<()> {
     Ok(())
 }
 
-pub(crate) fn pgrep(pgrep_path: &str, process_name: &str) -> Result<bool> {
+pub(crate) fn pgrep(
+    runner: &CommandRunner,
+    pgrep_path: &str,
+    process_name: &str,
+) -> Result<Vec<u32>> {
     tracing::info!(
         pgrep_path = pgrep_path,
         process_name = process_name,
@@ -168,7 +291,7 @@ pub(crate) fn pgrep(pgrep_path: &str, process_name: &str) -> Result<bool> {
     );
     let mut cmd = Command::new(pgrep_path);
     cmd.arg(process_name);
-    let output = cmd.output()?;
+    let output = runner.output(&mut cmd)?;
     let stdout = String::from_utf8_lossy(&output.stdout);
     let stderr = String::from_utf8_lossy(&output.stderr);
     let stdout_trim = stdout.trim();
@@ -181,7 +304,7 @@ pub(crate) fn pgrep(pgrep_path: &str, process_name: &str) -> Result<bool> {
                 process_name = process_name,
                 "Process is not running",
             );
-            return Ok(false);
+            return Ok(Vec::new());
         }
         tracing::error!(
             stdout = stdout_trim,
@@ -198,5 +321,14 @@ pub(crate) fn pgrep(pgrep_path: &str, process_name: &str) -> Result<bool> {
         process_name = process_name,
         "Process is running",
     );
-    Ok(true)
+    // One pid per line
+    let pids = stdout_trim
+        .lines()
+        .map(|line| {
+            line.trim()
+                .parse()
+                .with_context(|| format!("Invalid pgrep output '{}'", line))
+        })
+        .collect::<Result<Vec<u32>>>()?;
+    Ok(pids)
 }
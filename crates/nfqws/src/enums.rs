@@ -7,3 +7,30 @@ pub enum FilterMode {
     HostFile,
     None,
 }
+
+#[cfg(target_os = "android")]
+const HOSTLISTS_DIR: &str = "/data/adb/zapret-ux";
+#[cfg(not(target_os = "android"))]
+const HOSTLISTS_DIR: &str = "/opt/zapret-ux";
+
+/// Host files used by the host file filter modes
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[serde(default)]
+pub struct Hostlists {
+    /// Hosts to bypass blocking for
+    pub hosts: String,
+    /// Hosts never processed
+    pub exclude: String,
+    /// Hosts added automatically in `auto_host_file` mode
+    pub auto: String,
+}
+
+impl Default for Hostlists {
+    fn default() -> Self {
+        Self {
+            hosts: format!("{}/hosts.txt", HOSTLISTS_DIR),
+            exclude: format!("{}/hosts-exclude.txt", HOSTLISTS_DIR),
+            auto: format!("{}/hosts-auto.txt", HOSTLISTS_DIR),
+        }
+    }
+}
@@ -1,10 +1,14 @@
 mod binding;
 mod enums;
+#[cfg(feature = "testing")]
+pub mod testing;
 mod traits;
 
 use anyhow::{Context, Result};
-use binding::*;
+pub use binding::{NfqwsCmdFactory, NfqwsRecorderFactory};
 pub use enums::*;
+use runner::CommandRunner;
+use std::fmt::Debug;
 pub use traits::*;
 
 const QUEUE_NUM: u16 = 200;
@@ -13,11 +17,15 @@ const FWMARK_VALUE: &str = "0x40000000";
 const UID_VALUE: &str = "0:0";
 const NFQWS_PROCESS_NAME: &str = "nfqws";
 
-#[derive(Debug)]
+/// Process search function: `(pgrep_path, process_name) -> pids`
+pub type PgrepFn = Box<dyn Fn(&str, &str) -> Result<Vec<u32>>>;
+/// Process kill function: `(pkill_path, process_name)`
+pub type PkillFn = Box<dyn Fn(&str, &str) -> Result<()>>;
+
 pub struct Nfqws<F, PG, PK>
 where
     F: NfqwsBindingFactory,
-    PG: Fn(&str, &str) -> Result<bool>,
+    PG: Fn(&str, &str) -> Result<Vec<u32>>,
     PK: Fn(&str, &str) -> Result<()>,
 {
     nfqws_path: String,
@@ -25,18 +33,20 @@ where
     pkill_path: String,
     mark_supported: bool,
     filter_mode: FilterMode,
+    hostlists: Hostlists,
     pgrep: PG,
     pkill: PK,
     factory: F,
 }
 
-impl Nfqws<NfqwsCmdFactory, fn(&str, &str) -> Result<bool>, fn(&str, &str) -> Result<()>> {
+impl Nfqws<NfqwsCmdFactory, PgrepFn, PkillFn> {
     pub fn new<S, PGS, PKS>(
         nfqws_path: S,
         pgrep_path: PGS,
         pkill_path: PKS,
         mark_supported: bool,
         filter_mode: FilterMode,
+        runner: CommandRunner,
     ) -> Self
     where
         S: AsRef<str>,
@@ -46,10 +56,10 @@ impl Nfqws<NfqwsCmdFactory, fn(&str, &str) -> Result<bool>, fn(&str, &str) -> Re
         let nfqws_path = nfqws_path.as_ref();
         let pgrep_path = pgrep_path.as_ref();
         let pkill_path = pkill_path.as_ref();
-        let factory = NfqwsCmdFactory;
+        let factory = NfqwsCmdFactory::new(runner);
 
-        let pgrep = binding::pgrep;
-        let pkill = binding::pkill;
+        let pgrep: PgrepFn = Box::new(move |path, name| binding::pgrep(&runner, path, name));
+        let pkill: PkillFn = Box::new(move |path, name| binding::pkill(&runner, path, name));
 
         Self {
             nfqws_path: nfqws_path.to_string(),
@@ -57,6 +67,7 @@ impl Nfqws<NfqwsCmdFactory, fn(&str, &str) -> Result<bool>, fn(&str, &str) -> Re
             pkill_path: pkill_path.to_string(),
             mark_supported,
             filter_mode,
+            hostlists: Hostlists::default(),
             pgrep,
             pkill,
             factory,
@@ -64,10 +75,80 @@ impl Nfqws<NfqwsCmdFactory, fn(&str, &str) -> Result<bool>, fn(&str, &str) -> Re
     }
 }
 
+impl<F, PG, PK> Nfqws<F, PG, PK>
+where
+    F: NfqwsBindingFactory,
+    PG: Fn(&str, &str) -> Result<Vec<u32>>,
+    PK: Fn(&str, &str) -> Result<()>,
+{
+    /// Host files passed in the host file filter modes
+    pub fn with_hostlists(mut self, hostlists: Hostlists) -> Self {
+        self.hostlists = hostlists;
+        self
+    }
+
+    pub fn hostlists(&self) -> &Hostlists {
+        &self.hostlists
+    }
+
+    /// Replace the factory used to create nfqws bindings
+    pub fn with_factory<NF: NfqwsBindingFactory>(self, factory: NF) -> Nfqws<NF, PG, PK> {
+        Nfqws {
+            nfqws_path: self.nfqws_path,
+            pgrep_path: self.pgrep_path,
+            pkill_path: self.pkill_path,
+            mark_supported: self.mark_supported,
+            filter_mode: self.filter_mode,
+            hostlists: self.hostlists,
+            pgrep: self.pgrep,
+            pkill: self.pkill,
+            factory,
+        }
+    }
+
+    /// Replace the functions used to search and kill the nfqws process
+    pub fn with_process_control<NPG, NPK>(self, pgrep: NPG, pkill: NPK) -> Nfqws<F, NPG, NPK>
+    where
+        NPG: Fn(&str, &str) -> Result<Vec<u32>>,
+        NPK: Fn(&str, &str) -> Result<()>,
+    {
+        Nfqws {
+            nfqws_path: self.nfqws_path,
+            pgrep_path: self.pgrep_path,
+            pkill_path: self.pkill_path,
+            mark_supported: self.mark_supported,
+            filter_mode: self.filter_mode,
+            hostlists: self.hostlists,
+            pgrep,
+            pkill,
+            factory: self.factory,
+        }
+    }
+}
+
+impl<F, PG, PK> Debug for Nfqws<F, PG, PK>
+where
+    F: NfqwsBindingFactory + Debug,
+    PG: Fn(&str, &str) -> Result<Vec<u32>>,
+    PK: Fn(&str, &str) -> Result<()>,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Nfqws")
+            .field("nfqws_path", &self.nfqws_path)
+            .field("pgrep_path", &self.pgrep_path)
+            .field("pkill_path", &self.pkill_path)
+            .field("mark_supported", &self.mark_supported)
+            .field("filter_mode", &self.filter_mode)
+            .field("hostlists", &self.hostlists)
+            .field("factory", &self.factory)
+            .finish_non_exhaustive()
+    }
+}
+
 impl<F, PG, PK> BypassSoftware for Nfqws<F, PG, PK>
 where
     F: NfqwsBindingFactory,
-    PG: Fn(&str, &str) -> Result<bool>,
+    PG: Fn(&str, &str) -> Result<Vec<u32>>,
     PK: Fn(&str, &str) -> Result<()>,
 {
     fn run<I, S>(&self, opt: I) -> Result<()>
@@ -95,17 +176,17 @@ where
         Ok(())
     }
 
-    fn is_running(&self) -> Result<bool> {
-        let is_running = (self.pgrep)(&self.pgrep_path, NFQWS_PROCESS_NAME)
+    fn pids(&self) -> Result<Vec<u32>> {
+        let pids = (self.pgrep)(&self.pgrep_path, NFQWS_PROCESS_NAME)
             .context("Failed to search nfqws process")?;
-        Ok(is_running)
+        Ok(pids)
     }
 }
 
 impl<F, PG, PK> Nfqws<F, PG, PK>
 where
     F: NfqwsBindingFactory,
-    PG: Fn(&str, &str) -> Result<bool>,
+    PG: Fn(&str, &str) -> Result<Vec<u32>>,
     PK: Fn(&str, &str) -> Result<()>,
 {
     fn parse_opt<B: NfqwsBinding, S, I>(&self, binging: &mut B, opt: I)
@@ -113,37 +194,23 @@ where
         S: AsRef<str>,
         I: IntoIterator<Item = S>,
     {
-        #[cfg(target_os = "android")]
-        const HOSTLIST_PATH: &str = "/data/adb/zapret-ux/hosts.txt";
-        #[cfg(target_os = "android")]
-        const HOSTLIST_EXCLUDE_PATH: &str = "/data/adb/zapret-ux/hosts-exclude.txt";
-        #[cfg(target_os = "android")]
-        const HOSTLIST_AUTO_PATH: &str = "/data/adb/zapret-ux/hosts-auto.txt";
-
-        #[cfg(not(target_os = "android"))]
-        const HOSTLIST_PATH: &str = "/opt/zapret-ux/hosts.txt";
-        #[cfg(not(target_os = "android"))]
-        const HOSTLIST_EXCLUDE_PATH: &str = "/opt/zapret-ux/hosts-exclude.txt";
-        #[cfg(not(target_os = "android"))]
-        const HOSTLIST_AUTO_PATH: &str = "/opt/zapret-ux/hosts-auto.txt";
-
         for arg in opt {
             let arg = arg.as_ref();
             if arg == "<FILTER_MODE>" {
                 match self.filter_mode {
                     FilterMode::AutoHostFile => {
                         binging
-                            .hostlist(HOSTLIST_PATH)
-                            .hostlist_exclude(HOSTLIST_EXCLUDE_PATH)
-                            .hostlist_auto(HOSTLIST_AUTO_PATH)
+                            .hostlist(&self.hostlists.hosts)
+                            .hostlist_exclude(&self.hostlists.exclude)
+                            .hostlist_auto(&self.hostlists.auto)
                             .hostlist_auto_fail_threshold(3)
                             .hostlist_auto_fail_time(60)
                             .hostlist_auto_retrans_threshold(3);
                     }
                     FilterMode::HostFile => {
                         binging
-                            .hostlist(HOSTLIST_PATH)
-                            .hostlist_exclude(HOSTLIST_EXCLUDE_PATH);
+                            .hostlist(&self.hostlists.hosts)
+                            .hostlist_exclude(&self.hostlists.exclude);
                     }
                     FilterMode::None => {}
                 }
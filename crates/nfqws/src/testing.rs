@@ -0,0 +1,172 @@
+//! Recording test doubles for [`NfqwsBindingFactory`] and the pgrep/pkill functions
+
+use super::{NfqwsBindingFactory, NfqwsRecorderFactory, PgrepFn, PkillFn};
+use crate::binding::{NfqwsRecorder, Outcome};
+use anyhow::{Result, bail};
+use runner::{CommandLog, shell_line};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// PID reported by the fake pgrep while the process is running
+pub const FAKE_PID: u32 = 4242;
+
+/// Error injected into a matching command
+#[derive(Clone, Debug)]
+struct ScriptedFailure {
+    pattern: Vec<String>,
+    message: String,
+    remaining: Option<usize>,
+}
+
+#[derive(Default, Debug)]
+struct State {
+    running: bool,
+    failures: Vec<ScriptedFailure>,
+}
+
+type SharedState = Arc<Mutex<State>>;
+
+/// Fake nfqws process shared by the binding factory and pgrep/pkill functions
+///
+/// nfqws runs go through [`NfqwsRecorderFactory`], pgrep and pkill calls are recorded
+/// in the same log as full argument vectors (program first). A successful nfqws run
+/// marks the process as running, a successful pkill marks it as stopped
+#[derive(Clone, Debug)]
+pub struct FakeNfqws {
+    log: CommandLog,
+    state: SharedState,
+    recorder: NfqwsRecorderFactory,
+}
+
+impl Default for FakeNfqws {
+    fn default() -> Self {
+        let log = CommandLog::new();
+        let state = SharedState::default();
+        let shared = state.clone();
+        let recorder = NfqwsRecorderFactory::new(log.clone()).with_outcome(Outcome(Arc::new(
+            move |argv: &[String]| {
+                take_failure(&shared, argv)?;
+                lock(&shared).running = true;
+                Ok(())
+            },
+        )));
+        Self {
+            log,
+            state,
+            recorder,
+        }
+    }
+}
+
+impl FakeNfqws {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail every call that contains `pattern` as consecutive arguments
+    pub fn fail_when<S: Into<String>>(&self, pattern: &[&str], message: S) -> &Self {
+        self.push_failure(pattern, message.into(), None)
+    }
+
+    /// Fail the next `times` calls that contain `pattern` as consecutive arguments
+    pub fn fail_times<S: Into<String>>(&self, pattern: &[&str], times: usize, message: S) -> &Self {
+        self.push_failure(pattern, message.into(), Some(times))
+    }
+
+    pub fn set_running(&self, running: bool) {
+        lock(&self.state).running = running;
+    }
+
+    pub fn is_running(&self) -> bool {
+        lock(&self.state).running
+    }
+
+    /// pgrep replacement reporting the fake process state
+    pub fn pgrep(&self) -> PgrepFn {
+        let fake = self.clone();
+        Box::new(move |pgrep_path, process_name| {
+            fake.call(pgrep_path, process_name)?;
+            Ok(if fake.is_running() {
+                vec![FAKE_PID]
+            } else {
+                Vec::new()
+            })
+        })
+    }
+
+    /// pkill replacement stopping the fake process
+    pub fn pkill(&self) -> PkillFn {
+        let fake = self.clone();
+        Box::new(move |pkill_path, process_name| {
+            fake.call(pkill_path, process_name)?;
+            fake.set_running(false);
+            Ok(())
+        })
+    }
+
+    /// Recorded argument vectors
+    pub fn commands(&self) -> Vec<Vec<String>> {
+        self.log.entries()
+    }
+
+    /// Recorded commands rendered as sh command lines
+    pub fn lines(&self) -> Vec<String> {
+        self.commands()
+            .iter()
+            .map(|argv| shell_line(argv))
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.log.clear();
+    }
+
+    /// Record a pgrep/pkill call and fail it when a scripted failure matches
+    fn call(&self, program: &str, process_name: &str) -> Result<()> {
+        self.log.record(program, [process_name]);
+        take_failure(
+            &self.state,
+            &[program.to_string(), process_name.to_string()],
+        )
+    }
+
+    fn push_failure(&self, pattern: &[&str], message: String, remaining: Option<usize>) -> &Self {
+        lock(&self.state).failures.push(ScriptedFailure {
+            pattern: pattern.iter().map(|arg| arg.to_string()).collect(),
+            message,
+            remaining,
+        });
+        self
+    }
+}
+
+impl NfqwsBindingFactory for FakeNfqws {
+    type Binding = NfqwsRecorder;
+    fn create(&self, nfqws_path: &str) -> Self::Binding {
+        self.recorder.create(nfqws_path)
+    }
+}
+
+fn take_failure(state: &SharedState, argv: &[String]) -> Result<()> {
+    let mut state = lock(state);
+    let Some(failure) = state
+        .failures
+        .iter_mut()
+        .find(|failure| failure.remaining != Some(0) && contains(argv, &failure.pattern))
+    else {
+        return Ok(());
+    };
+    if let Some(remaining) = failure.remaining.as_mut() {
+        *remaining -= 1;
+    }
+    bail!("{}", failure.message)
+}
+
+fn lock(state: &SharedState) -> MutexGuard<'_, State> {
+    state
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn contains(argv: &[String], pattern: &[String]) -> bool {
+    pattern.is_empty() || argv.windows(pattern.len()).any(|window| window == pattern)
+}
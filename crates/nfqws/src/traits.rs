@@ -68,7 +68,11 @@ pub trait BypassSoftware {
         S: AsRef<str>,
         I: IntoIterator<Item = S>;
     fn kill(&self) -> Result<()>;
-    fn is_running(&self) -> Result<bool>;
+    /// PIDs of the running processes
+    fn pids(&self) -> Result<Vec<u32>>;
+    fn is_running(&self) -> Result<bool> {
+        Ok(!self.pids()?.is_empty())
+    }
 }
 
 pub trait NfqwsBindingFactory {
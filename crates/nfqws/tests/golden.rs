@@ -0,0 +1,137 @@
+use nfqws::testing::{FAKE_PID, FakeNfqws};
+use nfqws::{BypassSoftware, FilterMode, Hostlists, Nfqws, PgrepFn, PkillFn};
+use runner::CommandRunner;
+
+fn nfqws(mark: bool, filter_mode: FilterMode) -> (Nfqws<FakeNfqws, PgrepFn, PkillFn>, FakeNfqws) {
+    let fake = FakeNfqws::new();
+    let nfqws = Nfqws::new(
+        "nfqws",
+        "pgrep",
+        "pkill",
+        mark,
+        filter_mode,
+        CommandRunner::default(),
+    )
+    .with_factory(fake.clone())
+    .with_process_control(fake.pgrep(), fake.pkill());
+    (nfqws, fake)
+}
+
+const OPT: [&str; 3] = ["--filter-tcp=80", "<FILTER_MODE>", "--dpi-desync=fake"];
+
+#[test]
+fn run_without_mark() {
+    let (nfqws, fake) = nfqws(false, FilterMode::None);
+    nfqws.run(OPT).unwrap();
+    assert_eq!(
+        fake.lines(),
+        ["nfqws --debug 1 --daemon --qnum 200 --uid 0:0 --filter-tcp=80 --dpi-desync=fake"]
+    );
+    assert!(fake.is_running());
+}
+
+#[test]
+fn run_with_mark() {
+    let (nfqws, fake) = nfqws(true, FilterMode::None);
+    nfqws.run(OPT).unwrap();
+    assert_eq!(
+        fake.lines(),
+        [
+            "nfqws --debug 1 --daemon --qnum 200 --uid 0:0 --dpi-desync-fwmark 0x40000000 --filter-tcp=80 --dpi-desync=fake"
+        ]
+    );
+}
+
+#[test]
+fn run_every_filter_mode() {
+    let prefix = "nfqws --debug 1 --daemon --qnum 200 --uid 0:0 --filter-tcp=80";
+    for (filter_mode, expected) in [
+        (FilterMode::None, String::new()),
+        (
+            FilterMode::HostFile,
+            " --hostlist /opt/zapret-ux/hosts.txt --hostlist-exclude /opt/zapret-ux/hosts-exclude.txt".to_string(),
+        ),
+        (
+            FilterMode::AutoHostFile,
+            " --hostlist /opt/zapret-ux/hosts.txt --hostlist-exclude /opt/zapret-ux/hosts-exclude.txt \
+             --hostlist-auto /opt/zapret-ux/hosts-auto.txt --hostlist-auto-fail-threshold 3 \
+             --hostlist-auto-fail-time 60 --hostlist-auto-retrans-threshold 3"
+                .to_string(),
+        ),
+    ] {
+        let (nfqws, fake) = nfqws(false, filter_mode);
+        nfqws.run(OPT).unwrap();
+        assert_eq!(
+            fake.lines(),
+            [format!("{}{} --dpi-desync=fake", prefix, expected)],
+            "filter mode: {:?}",
+            filter_mode
+        );
+    }
+}
+
+#[test]
+fn custom_hostlists() {
+    let (nfqws, fake) = nfqws(false, FilterMode::HostFile);
+    let nfqws = nfqws.with_hostlists(Hostlists {
+        hosts: "/etc/zapret/hosts".to_string(),
+        exclude: "/etc/zapret/exclude".to_string(),
+        auto: "/etc/zapret/auto".to_string(),
+    });
+    nfqws.run(["<FILTER_MODE>"]).unwrap();
+    assert_eq!(
+        fake.lines(),
+        [
+            "nfqws --debug 1 --daemon --qnum 200 --uid 0:0 --hostlist /etc/zapret/hosts --hostlist-exclude /etc/zapret/exclude"
+        ]
+    );
+}
+
+#[test]
+fn filter_mode_placeholder_is_expanded_in_place() {
+    let (nfqws, fake) = nfqws(false, FilterMode::HostFile);
+    nfqws
+        .run(["<FILTER_MODE>", "--new", "<FILTER_MODE>"])
+        .unwrap();
+    let argv = &fake.commands()[0];
+    let hostlists = argv.iter().filter(|arg| *arg == "--hostlist").count();
+    assert_eq!(hostlists, 2);
+    assert_eq!(argv.iter().position(|arg| arg == "--new"), Some(12));
+}
+
+#[test]
+fn kill_and_status() {
+    let (nfqws, fake) = nfqws(false, FilterMode::None);
+    assert!(!nfqws.is_running().unwrap());
+    nfqws.run(Vec::<String>::new()).unwrap();
+    assert_eq!(nfqws.pids().unwrap(), [FAKE_PID]);
+    nfqws.kill().unwrap();
+    assert!(!nfqws.is_running().unwrap());
+    assert_eq!(
+        fake.lines(),
+        [
+            "pgrep nfqws",
+            "nfqws --debug 1 --daemon --qnum 200 --uid 0:0",
+            "pgrep nfqws",
+            "pkill nfqws",
+            "pgrep nfqws",
+        ]
+    );
+}
+
+#[test]
+fn scripted_failures() {
+    let (nfqws, fake) = nfqws(false, FilterMode::None);
+    fake.fail_times(&["nfqws", "--debug"], 1, "can't bind to queue")
+        .fail_when(&["pkill"], "no process found");
+
+    let error = nfqws.run(Vec::<String>::new()).unwrap_err();
+    assert_eq!(error.to_string(), "can't bind to queue");
+    assert!(!fake.is_running());
+    nfqws.run(Vec::<String>::new()).unwrap();
+    assert!(fake.is_running());
+
+    let error = nfqws.kill().unwrap_err();
+    assert_eq!(error.to_string(), "Failed to kill nfqws process");
+    assert!(fake.is_running());
+}
@@ -0,0 +1,17 @@
+use std::{io, time::Duration};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum RunError {
+    #[error("Command timed out after {timeout:?}. command: '{command_line}'")]
+    Timeout {
+        command_line: String,
+        timeout: Duration,
+    },
+    #[error("Failed to run command. command: '{command_line}' error: {error}")]
+    Io {
+        command_line: String,
+        #[source]
+        error: io::Error,
+    },
+}
@@ -0,0 +1,149 @@
+mod error;
+mod log;
+mod shell;
+
+pub use error::*;
+pub use log::*;
+pub use shell::*;
+use std::{
+    io::Read,
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Runs external commands with a deadline
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct CommandRunner {
+    timeout: Duration,
+}
+
+impl CommandRunner {
+    pub fn new(timeout: Duration) -> Self {
+        tracing::debug!(
+            timeout_ms = timeout.as_millis() as u64,
+            "Creating new CommandRunner instance"
+        );
+        Self { timeout }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Run command to completion and collect its output.
+    /// The child is killed when it does not exit before the timeout
+    pub fn output(&self, cmd: &mut Command) -> Result<Output, RunError> {
+        let command_line = command_line(cmd);
+        let deadline = Instant::now() + self.timeout;
+        let mut child = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|error| RunError::Io {
+                command_line: command_line.clone(),
+                error,
+            })?;
+
+        let stdout = read_pipe(child.stdout.take());
+        let stderr = read_pipe(child.stderr.take());
+
+        let status = match self.wait(&mut child, deadline) {
+            Ok(Some(status)) => status,
+            Ok(None) => {
+                tracing::error!(
+                    command_line = command_line,
+                    timeout_ms = self.timeout.as_millis() as u64,
+                    "Command timed out. Killing it"
+                );
+                if let Err(e) = child.kill() {
+                    tracing::warn!(error = e.to_string(), "Failed to kill timed out command");
+                }
+                let _ = child.wait();
+                return Err(RunError::Timeout {
+                    command_line,
+                    timeout: self.timeout,
+                });
+            }
+            Err(error) => {
+                return Err(RunError::Io {
+                    command_line,
+                    error,
+                });
+            }
+        };
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let (Ok(stdout), Ok(stderr)) = (
+            stdout.recv_timeout(remaining),
+            stderr.recv_timeout(remaining),
+        ) else {
+            tracing::error!(
+                command_line = command_line,
+                "Command exited, but its output is still held open"
+            );
+            return Err(RunError::Timeout {
+                command_line,
+                timeout: self.timeout,
+            });
+        };
+
+        Ok(Output {
+            status,
+            stdout,
+            stderr,
+        })
+    }
+
+    fn wait(&self, child: &mut Child, deadline: Instant) -> std::io::Result<Option<ExitStatus>> {
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(Some(status));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(POLL_INTERVAL.min(deadline - now));
+        }
+    }
+}
+
+impl Default for CommandRunner {
+    fn default() -> Self {
+        Self::new(DEFAULT_TIMEOUT)
+    }
+}
+
+/// Delay before retry number `attempt` (starting from 0): `base_ms` doubled
+/// on every retry, saturating instead of overflowing
+pub fn backoff(base_ms: u64, attempt: u32) -> Duration {
+    let factor = 1u64.checked_shl(attempt).unwrap_or(u64::MAX);
+    Duration::from_millis(base_ms.saturating_mul(factor))
+}
+
+/// Render command as a single line for logs and errors
+pub fn command_line(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        let _ = tx.send(buf);
+    });
+    rx
+}
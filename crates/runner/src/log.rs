@@ -0,0 +1,41 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Ordered record of command invocations shared between recording bindings.
+/// Every entry is the full argument vector, program first
+#[derive(Clone, Default, Debug)]
+pub struct CommandLog {
+    entries: Arc<Mutex<Vec<Vec<String>>>>,
+}
+
+impl CommandLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record<P, I, S>(&self, program: P, args: I)
+    where
+        P: Into<String>,
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let entry: Vec<String> = std::iter::once(program.into())
+            .chain(args.into_iter().map(Into::into))
+            .collect();
+        tracing::debug!(command = ?entry, "Record command");
+        self.lock().push(entry);
+    }
+
+    pub fn entries(&self) -> Vec<Vec<String>> {
+        self.lock().clone()
+    }
+
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Vec<String>>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
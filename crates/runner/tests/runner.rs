@@ -0,0 +1,62 @@
+use runner::{CommandRunner, RunError, backoff};
+use std::{
+    path::Path,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+#[test]
+fn output_is_collected() {
+    let runner = CommandRunner::new(Duration::from_secs(5));
+    let output = runner
+        .output(Command::new("sh").args(["-c", "echo out; echo err >&2; exit 3"]))
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(output.stdout, b"out\n");
+    assert_eq!(output.stderr, b"err\n");
+}
+
+#[test]
+fn hanging_command_is_killed_on_timeout() {
+    let pid_file = std::env::temp_dir().join(format!("runner-timeout-{}.pid", std::process::id()));
+    let runner = CommandRunner::new(Duration::from_millis(200));
+    let started = Instant::now();
+    let error = runner
+        .output(Command::new("sh").args([
+            "-c",
+            r#"echo $$ > "$0"; exec sleep 10"#,
+            pid_file.to_str().unwrap(),
+        ]))
+        .unwrap_err();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert!(
+        matches!(error, RunError::Timeout { timeout, .. } if timeout == Duration::from_millis(200)),
+        "{error}"
+    );
+
+    let pid = std::fs::read_to_string(&pid_file).unwrap();
+    std::fs::remove_file(&pid_file).unwrap();
+    assert!(!Path::new(&format!("/proc/{}", pid.trim())).exists());
+}
+
+#[test]
+fn missing_binary_is_io_error() {
+    let runner = CommandRunner::default();
+    let error = runner
+        .output(&mut Command::new("/nonexistent/zapret-ux-test"))
+        .unwrap_err();
+    assert!(matches!(error, RunError::Io { .. }), "{error}");
+}
+
+#[test]
+fn backoff_doubles_and_saturates() {
+    let delays: Vec<u64> = (0..5)
+        .map(|attempt| backoff(100, attempt).as_millis() as u64)
+        .collect();
+    assert_eq!(delays, [100, 200, 400, 800, 1600]);
+    assert_eq!(backoff(0, 10), Duration::ZERO);
+    assert_eq!(backoff(1, 63), Duration::from_millis(1 << 63));
+    assert_eq!(backoff(2, 63), Duration::from_millis(u64::MAX));
+    assert_eq!(backoff(1, 64), Duration::from_millis(u64::MAX));
+    assert_eq!(backoff(1, u32::MAX), Duration::from_millis(u64::MAX));
+}
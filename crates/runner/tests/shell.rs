@@ -0,0 +1,48 @@
+use runner::{shell_line, shell_quote};
+use std::process::Command;
+
+#[test]
+fn arguments_are_quoted_when_needed() {
+    for (arg, quoted) in [
+        ("", "''"),
+        ("plain", "plain"),
+        ("--dpi-desync=fake", "--dpi-desync=fake"),
+        ("-", "-"),
+        ("0x40000000/0x40000000", "0x40000000/0x40000000"),
+        ("it's", r"'it'\''s'"),
+        ("'", r"''\'''"),
+        ("two words", "'two words'"),
+        ("$HOME", "'$HOME'"),
+        ("line\nbreak", "'line\nbreak'"),
+        ("a;b", "'a;b'"),
+        ("`id`", "'`id`'"),
+        ("*", "'*'"),
+    ] {
+        assert_eq!(shell_quote(arg), quoted, "{:?}", arg);
+    }
+}
+
+#[test]
+fn line_is_parsed_back_by_sh() {
+    let argv = [
+        "printf",
+        "%s|",
+        "",
+        "it's",
+        "two words",
+        "$HOME",
+        "line\nbreak",
+        "-n",
+        "--dpi-desync-fake-tls=\"quoted\" value",
+    ];
+    let line = shell_line(&argv);
+    let output = Command::new("sh").args(["-c", &line]).output().unwrap();
+    assert!(output.status.success(), "{}", line);
+    let expected: String = argv[2..].iter().map(|arg| format!("{}|", arg)).collect();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+}
+
+#[test]
+fn empty_line() {
+    assert_eq!(shell_line::<&str>(&[]), "");
+}
@@ -0,0 +1,111 @@
+use crate::config::ConfigApps;
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use iptables::{AppMode, AppScope};
+use std::{collections::HashMap, fs};
+use tracing::{info, warn};
+
+/// UIDs of Android user `n` start at `n * PER_USER_RANGE`
+const PER_USER_RANGE: u32 = 100_000;
+
+/// Package name to app id mapping from `packages.list`
+#[derive(Debug, Default)]
+pub struct Packages {
+    app_ids: HashMap<String, u32>,
+}
+
+impl Packages {
+    /// Read `packages.list`. Every line starts with the package name and its app id
+    pub fn load(path: &Utf8Path) -> Result<Self> {
+        let content =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+        Ok(Self::parse(&content))
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let app_ids = content
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let name = fields.next()?;
+                let app_id = fields.next()?.parse().ok()?;
+                Some((name.to_string(), app_id))
+            })
+            .collect();
+        Self { app_ids }
+    }
+
+    pub fn app_id(&self, package: &str) -> Option<u32> {
+        self.app_ids.get(package).copied()
+    }
+}
+
+/// Android user ids from the users directory (`0/`, `10/`, `0.xml`, ...)
+///
+/// Falls back to the owner user when the directory can't be read
+pub fn load_users(path: &Utf8Path) -> Vec<u32> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(error) => {
+            warn!(path = %path, %error, "Failed to read users, using owner only");
+            return vec![0];
+        }
+    };
+    let mut users: Vec<u32> = entries
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            name.strip_suffix(".xml").unwrap_or(&name).parse().ok()
+        })
+        .collect();
+    users.sort_unstable();
+    users.dedup();
+    if users.is_empty() {
+        users.push(0);
+    }
+    users
+}
+
+/// UIDs of the package for every user. Empty when the package is not installed
+pub fn resolve(packages: &Packages, users: &[u32], package: &str) -> Vec<u32> {
+    let Some(app_id) = packages.app_id(package) else {
+        return Vec::new();
+    };
+    users
+        .iter()
+        .map(|user| user * PER_USER_RANGE + app_id % PER_USER_RANGE)
+        .collect()
+}
+
+/// UIDs of all packages for every user, warning about unknown packages
+pub fn resolve_all(packages: &Packages, users: &[u32], names: &[String]) -> Vec<u32> {
+    let mut uids = Vec::new();
+    for name in names {
+        let resolved = resolve(packages, users, name);
+        if resolved.is_empty() {
+            warn!(package = name, "Package is not installed, skipping");
+        } else {
+            info!(package = name, uids = ?resolved, "Package resolved");
+        }
+        uids.extend(resolved);
+    }
+    uids
+}
+
+/// Resolve configured packages into the app scope of the rules
+pub fn scope(config: &ConfigApps) -> Result<AppScope> {
+    if config.packages.is_empty() {
+        if config.mode == AppMode::Include {
+            warn!("apps.mode is include, but apps.packages is empty. No traffic is processed");
+        }
+        return Ok(AppScope {
+            mode: config.mode,
+            uids: Vec::new(),
+        });
+    }
+    let packages = Packages::load(&config.packages_list_path)?;
+    let users = load_users(&config.users_path);
+    Ok(AppScope {
+        mode: config.mode,
+        uids: resolve_all(&packages, &users, &config.packages),
+    })
+}
@@ -0,0 +1,175 @@
+use crate::{config::ConfigAutostart, netlink};
+use anyhow::{Context, Result, bail};
+use camino::Utf8Path;
+use runner::CommandRunner;
+use rustix::{
+    fs::{Access, FlockOperation, access, flock},
+    io::Errno,
+};
+use std::{
+    env, fmt, fs,
+    io::ErrorKind,
+    path::Path,
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, info, warn};
+
+/// Something the system needs before the rules can be set up at boot
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Condition {
+    BootCompleted,
+    DefaultRoute,
+    XtablesLock,
+    NfqwsBinary,
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::BootCompleted => "boot completed",
+            Self::DefaultRoute => "default route",
+            Self::XtablesLock => "free xtables lock",
+            Self::NfqwsBinary => "nfqws binary",
+        })
+    }
+}
+
+/// Wait until every enabled condition holds, failing with the pending ones on timeout.
+/// Commands checking the conditions are run with `runner`
+pub fn wait_ready(
+    config: &ConfigAutostart,
+    nfqws_path: &Utf8Path,
+    runner: CommandRunner,
+) -> Result<()> {
+    let conditions: Vec<Condition> = [
+        (config.boot_completed, Condition::BootCompleted),
+        (config.default_route, Condition::DefaultRoute),
+        (config.xtables_lock, Condition::XtablesLock),
+        (config.nfqws_binary, Condition::NfqwsBinary),
+    ]
+    .into_iter()
+    .filter_map(|(enabled, condition)| enabled.then_some(condition))
+    .collect();
+
+    let started = Instant::now();
+    let timeout = Duration::from_secs(config.timeout_secs);
+    loop {
+        let pending: Vec<String> = conditions
+            .iter()
+            .filter(|condition| !is_met(**condition, config, nfqws_path, runner))
+            .map(Condition::to_string)
+            .collect();
+        if pending.is_empty() {
+            info!(
+                elapsed_ms = started.elapsed().as_millis(),
+                "System is ready"
+            );
+            return Ok(());
+        }
+        if started.elapsed() >= timeout {
+            bail!(
+                "Timed out after {}s waiting for: {}",
+                config.timeout_secs,
+                pending.join(", ")
+            );
+        }
+        debug!(pending = pending.join(", "), "Waiting for system readiness");
+        thread::sleep(Duration::from_millis(config.poll_interval_ms));
+    }
+}
+
+fn is_met(
+    condition: Condition,
+    config: &ConfigAutostart,
+    nfqws_path: &Utf8Path,
+    runner: CommandRunner,
+) -> bool {
+    match condition {
+        // A hanging getprop counts as not booted yet
+        Condition::BootCompleted => {
+            match runner.output(Command::new("getprop").arg("sys.boot_completed")) {
+                Ok(output) => String::from_utf8_lossy(&output.stdout).trim() == "1",
+                Err(error) => {
+                    debug!(error = %error, "Failed to read boot state");
+                    false
+                }
+            }
+        }
+        Condition::DefaultRoute => match netlink::uplink() {
+            Ok(uplink) => uplink.interface.is_some(),
+            Err(error) => {
+                debug!(error = format!("{:#}", error), "Failed to look up routes");
+                false
+            }
+        },
+        Condition::XtablesLock => lock_is_free(config.xtables_lock_path.as_std_path()),
+        Condition::NfqwsBinary => executable(nfqws_path.as_std_path()),
+    }
+}
+
+/// Probe the lock without waiting. A missing lock file is free
+fn lock_is_free(path: &Path) -> bool {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(error) if error.kind() == ErrorKind::NotFound => return true,
+        Err(error) => {
+            debug!(error = %error, path = %path.display(), "Failed to open lock file");
+            return false;
+        }
+    };
+    match flock(&file, FlockOperation::NonBlockingLockExclusive) {
+        Ok(()) => flock(&file, FlockOperation::Unlock).is_ok(),
+        Err(Errno::WOULDBLOCK) => false,
+        Err(error) => {
+            debug!(error = %error, path = %path.display(), "Failed to probe lock file");
+            false
+        }
+    }
+}
+
+/// Path or a name looked up in `PATH`, like `Command` does
+fn executable(path: &Path) -> bool {
+    let is_executable = |path: &Path| path.is_file() && access(path, Access::EXEC_OK).is_ok();
+    if path.components().count() > 1 {
+        return is_executable(path);
+    }
+    env::var_os("PATH")
+        .is_some_and(|paths| env::split_paths(&paths).any(|dir| is_executable(&dir.join(path))))
+}
+
+/// Run `setup` until it succeeds, undoing what it left with `cleanup` before every retry
+pub fn retry<S, C>(config: &ConfigAutostart, mut setup: S, mut cleanup: C) -> Result<()>
+where
+    S: FnMut() -> Result<()>,
+    C: FnMut(),
+{
+    let attempts = config.retries.saturating_add(1);
+    let mut attempt = 1;
+    loop {
+        let error = match setup() {
+            Ok(()) => return Ok(()),
+            Err(error) => error,
+        };
+        if attempt >= attempts {
+            error!(
+                attempts,
+                error = format!("{:#}", error),
+                "Giving up on autostart"
+            );
+            return Err(error)
+                .with_context(|| format!("Autostart failed after {} attempts", attempts));
+        }
+        let delay = runner::backoff(config.backoff_ms, attempt - 1);
+        warn!(
+            attempt,
+            delay_ms = delay.as_millis(),
+            error = format!("{:#}", error),
+            "Autostart failed, retrying"
+        );
+        cleanup();
+        thread::sleep(delay);
+        attempt += 1;
+    }
+}
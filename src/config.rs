@@ -1,14 +1,56 @@
+use crate::uci;
+use anyhow::{Context, Result};
 use camino::Utf8PathBuf;
-use iptables::{Port, PortSpec, Protocol};
-use nfqws::FilterMode;
+use iptables::{
+    AppMode, BlockQuic, DnsRedirect, FailMode, Family, Interfaces, Network, Port, PortSpec,
+    Protocol, Tethering, XtablesLock,
+};
+use nfqws::{FilterMode, Hostlists};
 use serde::{Deserialize, Serialize};
+use std::{io::ErrorKind, net::IpAddr, path::Path};
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+/// Load the config, TOML or OpenWrt UCI. A missing file is created with the
+/// TOML defaults
+pub fn load(path: &Path) -> Result<Config> {
+    match std::fs::read_to_string(path) {
+        Ok(text) if uci::is_uci(&text) => {
+            uci::parse_config(&text).with_context(|| format!("Failed to load {}", path.display()))
+        }
+        Err(error) if error.kind() != ErrorKind::NotFound => {
+            Err(error).with_context(|| format!("Failed to read {}", path.display()))
+        }
+        _ => confy::load_path(path).with_context(|| format!("Failed to load {}", path.display())),
+    }
+}
+
+/// Whether the config at `path` is in OpenWrt UCI format, edited with `uci`
+pub fn is_uci(path: &Path) -> bool {
+    std::fs::read_to_string(path).is_ok_and(|text| uci::is_uci(&text))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub iptables: ConfigIptables,
     pub nfqws: ConfigNfqws,
     pub mark_supported: bool,
     pub autostart_enabled: bool,
+    /// Deadline for every external command (iptables, nfqws, pgrep, pkill)
+    #[serde(default = "default_command_timeout_secs")]
+    pub command_timeout_secs: u64,
+    #[serde(default)]
+    pub apps: ConfigApps,
+    #[serde(default)]
+    pub supervisor: ConfigSupervisor,
+    /// Strategies for specific networks. The first matching one overrides
+    /// `iptables.ports` and `nfqws.opt`
+    #[serde(default)]
+    pub profiles: Vec<ConfigProfile>,
+    #[serde(default)]
+    pub daemon: ConfigDaemon,
+    #[serde(default)]
+    pub autostart: ConfigAutostart,
+    #[serde(default)]
+    pub lock: ConfigLock,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -16,6 +58,24 @@ pub struct ConfigIptables {
     pub iptables_path: Utf8PathBuf,
     pub connbytes_supported: bool,
     pub ports: Vec<PortSpec>,
+    #[serde(default)]
+    pub xtables_lock: XtablesLock,
+    #[serde(default)]
+    pub interfaces: Interfaces,
+    /// ip6tables binary. IPv6 traffic is not processed when unset
+    #[serde(default)]
+    pub ip6tables_path: Option<Utf8PathBuf>,
+    /// Destination networks that never go to nfqws
+    #[serde(default = "default_excluded_networks")]
+    pub excluded_networks: Vec<Network>,
+    #[serde(default)]
+    pub tethering: Tethering,
+    #[serde(default)]
+    pub block_quic: BlockQuic,
+    #[serde(default)]
+    pub dns_redirect: DnsRedirect,
+    #[serde(default)]
+    pub fail_mode: FailMode,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -25,6 +85,187 @@ pub struct ConfigNfqws {
     pub pkill_path: Utf8PathBuf,
     pub filter_mode: FilterMode,
     pub opt: Vec<String>,
+    #[serde(default)]
+    pub hostlists: Hostlists,
+}
+
+/// Per-app scoping by Android package name
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ConfigApps {
+    pub mode: AppMode,
+    /// Package names. When empty, exclude mode processes every app
+    /// and include mode none
+    pub packages: Vec<String>,
+    pub packages_list_path: Utf8PathBuf,
+    pub users_path: Utf8PathBuf,
+}
+
+/// Private, loopback, link-local, CGNAT and multicast ranges
+fn default_excluded_networks() -> Vec<Network> {
+    [
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.168.0.0/16",
+        "224.0.0.0/4",
+        "::1/128",
+        "fc00::/7",
+        "fe80::/10",
+        "ff00::/8",
+    ]
+    .iter()
+    .map(|network| network.parse().expect("default network is valid"))
+    .collect()
+}
+
+/// `supervise` command timings
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ConfigSupervisor {
+    pub check_interval_ms: u64,
+    /// How long nfqws may stay down before fail-closed rules are replaced
+    pub grace_period_ms: u64,
+    /// Re-apply rules when links, addresses or routes change
+    pub network_events: bool,
+    /// Quiet time after the last network event before re-applying
+    pub debounce_ms: u64,
+}
+
+/// nfqws options and ports used while the uplink matches `when`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConfigProfile {
+    pub name: String,
+    #[serde(default)]
+    pub when: ProfileCondition,
+    /// Global ports when unset
+    pub ports: Option<Vec<PortSpec>>,
+    /// Global nfqws options when unset
+    pub opt: Option<Vec<String>>,
+}
+
+/// Uplink conditions, all set ones must match. An empty condition always matches
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ProfileCondition {
+    /// Outgoing interface name, a trailing `+` matches any suffix
+    pub interface: Option<String>,
+    /// IPv4 default gateway
+    pub gateway: Option<IpAddr>,
+    /// Whether the network has IPv6 connectivity
+    pub ipv6: Option<bool>,
+}
+
+/// `daemon` command control socket
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ConfigDaemon {
+    /// Unix socket the daemon listens on. `start`, `stop`, `restart`, `status`
+    /// and `hosts` are sent to the daemon when it is running
+    pub socket_path: Utf8PathBuf,
+}
+
+/// What `autostart` waits for at boot before setting up, and how it retries
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ConfigAutostart {
+    /// Give up waiting for the conditions after this many seconds
+    pub timeout_secs: u64,
+    /// Delay between condition checks
+    pub poll_interval_ms: u64,
+    /// Wait for the `sys.boot_completed` Android property
+    pub boot_completed: bool,
+    /// Wait for an IPv4 or IPv6 default route
+    pub default_route: bool,
+    /// Wait until nobody holds `xtables_lock_path` (netd at boot)
+    pub xtables_lock: bool,
+    pub xtables_lock_path: Utf8PathBuf,
+    /// Wait until `nfqws.nfqws_path` is executable, e.g. on storage mounted late
+    pub nfqws_binary: bool,
+    /// Setup attempts after the first failed one
+    pub retries: u32,
+    /// Delay before the first setup retry in milliseconds. Doubled on every next retry
+    pub backoff_ms: u64,
+}
+
+/// Default lock file on Android
+pub const ANDROID_LOCK_PATH: &str = "/dev/zapret-ux.lock";
+
+/// Lock serialising the commands that change rules, nfqws or hostlists
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct ConfigLock {
+    /// Advisory lock file in a runtime directory
+    pub path: Utf8PathBuf,
+    /// How long to wait for another zapret-ux holding the lock
+    pub wait_secs: u64,
+}
+
+fn default_command_timeout_secs() -> u64 {
+    30
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            iptables: ConfigIptables::default(),
+            nfqws: ConfigNfqws::default(),
+            mark_supported: false,
+            autostart_enabled: false,
+            command_timeout_secs: default_command_timeout_secs(),
+            apps: ConfigApps::default(),
+            supervisor: ConfigSupervisor::default(),
+            profiles: Vec::new(),
+            daemon: ConfigDaemon::default(),
+            autostart: ConfigAutostart::default(),
+            lock: ConfigLock::default(),
+        }
+    }
+}
+
+impl ConfigLock {
+    /// Chains and jumps installed by the `family` iptables, kept beside the
+    /// lock file so they don't outlive a reboot either
+    pub fn rules_path(&self, family: Family) -> Utf8PathBuf {
+        let stem = self.path.file_stem().unwrap_or("zapret-ux");
+        self.path
+            .with_file_name(format!("{}-{}.rules.json", stem, family.nft_name()))
+    }
+}
+
+impl Default for ConfigLock {
+    fn default() -> Self {
+        Self {
+            // tmpfs, so a lock file can't outlive a reboot
+            #[cfg(target_os = "android")]
+            path: ANDROID_LOCK_PATH.into(),
+            #[cfg(not(target_os = "android"))]
+            path: "/run/zapret-ux.lock".into(),
+            wait_secs: 30,
+        }
+    }
+}
+
+impl Default for ConfigAutostart {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 120,
+            poll_interval_ms: 500,
+            boot_completed: cfg!(target_os = "android"),
+            default_route: true,
+            xtables_lock: true,
+            #[cfg(target_os = "android")]
+            xtables_lock_path: "/system/etc/xtables.lock".into(),
+            #[cfg(not(target_os = "android"))]
+            xtables_lock_path: "/run/xtables.lock".into(),
+            nfqws_binary: true,
+            retries: 5,
+            backoff_ms: 1000,
+        }
+    }
 }
 
 impl Default for ConfigIptables {
@@ -37,6 +278,14 @@ impl Default for ConfigIptables {
                 PortSpec::new(Port::Single(443), Protocol::Udp),
                 PortSpec::new(Port::Range(50000, 50099), Protocol::Udp),
             ],
+            xtables_lock: XtablesLock::default(),
+            interfaces: Interfaces::default(),
+            ip6tables_path: None,
+            excluded_networks: default_excluded_networks(),
+            tethering: Tethering::default(),
+            block_quic: BlockQuic::default(),
+            dns_redirect: DnsRedirect::default(),
+            fail_mode: FailMode::default(),
         }
     }
 }
@@ -49,6 +298,42 @@ impl Default for ConfigNfqws {
             pkill_path: "pkill".into(),
             filter_mode: FilterMode::AutoHostFile,
             opt: Vec::new(),
+            hostlists: Hostlists::default(),
+        }
+    }
+}
+
+impl Default for ConfigApps {
+    fn default() -> Self {
+        Self {
+            mode: AppMode::default(),
+            packages: Vec::new(),
+            packages_list_path: "/data/system/packages.list".into(),
+            users_path: "/data/system/users".into(),
+        }
+    }
+}
+
+impl Default for ConfigSupervisor {
+    fn default() -> Self {
+        Self {
+            check_interval_ms: 5_000,
+            grace_period_ms: 30_000,
+            network_events: true,
+            debounce_ms: 2_000,
+        }
+    }
+}
+
+impl Default for ConfigDaemon {
+    fn default() -> Self {
+        #[cfg(target_os = "android")]
+        const SOCKET_PATH: &str = "/data/adb/zapret-ux/zapret-ux.sock";
+        #[cfg(not(target_os = "android"))]
+        const SOCKET_PATH: &str = "/run/zapret-ux.sock";
+
+        Self {
+            socket_path: SOCKET_PATH.into(),
         }
     }
 }
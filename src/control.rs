@@ -0,0 +1,176 @@
+use crate::status::StatusFormat;
+use anyhow::{Context, Result, bail};
+use std::{
+    fmt,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
+
+/// Log lines returned by `logs` by default
+pub const DEFAULT_LOG_LINES: usize = 100;
+
+/// Deadline for a daemon to answer, covers iptables and nfqws runs
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Control socket request. Sent as a single line, e.g. `hosts add --exclude a.com`
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Request {
+    Start,
+    Stop,
+    Restart,
+    /// Re-read the config and restart with it
+    Reload,
+    Status {
+        format: StatusFormat,
+    },
+    Hosts {
+        action: HostsAction,
+        exclude: bool,
+    },
+    /// Force a strategy profile, `auto` returns to uplink detection.
+    /// Lists the profiles without a name
+    Profile {
+        name: Option<String>,
+    },
+    /// Recent daemon log lines
+    Logs {
+        lines: usize,
+    },
+}
+
+/// Profile name returning to uplink detection
+pub const AUTO_PROFILE: &str = "auto";
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum HostsAction {
+    Add(Vec<String>),
+    Remove(Vec<String>),
+    List,
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Start => write!(f, "start"),
+            Self::Stop => write!(f, "stop"),
+            Self::Restart => write!(f, "restart"),
+            Self::Reload => write!(f, "reload"),
+            Self::Status { format } => match format {
+                StatusFormat::Text => write!(f, "status"),
+                StatusFormat::Json => write!(f, "status --format json"),
+            },
+            Self::Profile { name: None } => write!(f, "profile"),
+            Self::Profile { name: Some(name) } => write!(f, "profile {}", name),
+            Self::Logs { lines } => write!(f, "logs --lines {}", lines),
+            Self::Hosts { action, exclude } => {
+                let (name, hosts) = match action {
+                    HostsAction::Add(hosts) => ("add", hosts.as_slice()),
+                    HostsAction::Remove(hosts) => ("remove", hosts.as_slice()),
+                    HostsAction::List => ("list", [].as_slice()),
+                };
+                write!(f, "hosts {}", name)?;
+                if *exclude {
+                    write!(f, " --exclude")?;
+                }
+                for host in hosts {
+                    write!(f, " {}", host)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FromStr for Request {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let mut words = line.split_whitespace();
+        let request = match words.next() {
+            Some("start") => Self::Start,
+            Some("stop") => Self::Stop,
+            Some("restart") => Self::Restart,
+            Some("reload") => Self::Reload,
+            Some("status") => {
+                let format = match (words.next(), words.next()) {
+                    (None, _) => StatusFormat::default(),
+                    (Some("--format"), Some(format)) => format.parse()?,
+                    _ => bail!("Invalid status request '{}'", line),
+                };
+                Self::Status { format }
+            }
+            Some("profile") => Self::Profile {
+                name: words.next().map(String::from),
+            },
+            Some("logs") => {
+                let lines = match (words.next(), words.next()) {
+                    (None, _) => DEFAULT_LOG_LINES,
+                    (Some("--lines"), Some(lines)) => lines.parse()?,
+                    _ => bail!("Invalid logs request '{}'", line),
+                };
+                Self::Logs { lines }
+            }
+            Some("hosts") => {
+                let action = words.next();
+                let mut exclude = false;
+                let mut hosts = Vec::new();
+                for word in words.by_ref() {
+                    match word {
+                        "--exclude" => exclude = true,
+                        host => hosts.push(host.to_string()),
+                    }
+                }
+                let action = match action {
+                    Some("add") => HostsAction::Add(hosts),
+                    Some("remove") => HostsAction::Remove(hosts),
+                    Some("list") if hosts.is_empty() => HostsAction::List,
+                    _ => bail!("Invalid hosts request '{}'", line),
+                };
+                return Ok(Self::Hosts { action, exclude });
+            }
+            _ => bail!("Unknown request '{}'", line),
+        };
+        if words.next().is_some() {
+            bail!("Unexpected arguments in '{}'", line);
+        }
+        Ok(request)
+    }
+}
+
+/// Send request to the daemon listening on `path`. Returns the response lines,
+/// `None` when no daemon is running
+pub fn request(path: &Path, request: &Request) -> Result<Option<Vec<String>>> {
+    let mut stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(error)
+            if matches!(
+                error.kind(),
+                ErrorKind::NotFound | ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(None);
+        }
+        Err(error) => {
+            return Err(error).with_context(|| format!("Failed to connect to {}", path.display()));
+        }
+    };
+    stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+    writeln!(stream, "{}", request).context("Failed to send request to daemon")?;
+
+    let mut lines = BufReader::new(stream).lines();
+    let status = lines
+        .next()
+        .context("Daemon closed connection without response")?
+        .context("Failed to read daemon response")?;
+    let body = lines
+        .collect::<Result<Vec<String>, _>>()
+        .context("Failed to read daemon response")?;
+    match status.strip_prefix("error: ") {
+        Some(error) => bail!("Daemon: {}", error),
+        None if status == "ok" => Ok(Some(body)),
+        None => bail!("Invalid daemon response '{}'", status),
+    }
+}
@@ -0,0 +1,427 @@
+use crate::{
+    config::{self, Config, ConfigLock},
+    control::{AUTO_PROFILE, HostsAction, Request},
+    hosts, lock, logs,
+    profiles::{Profiles, Selection},
+    status::{self, Settings, StatusFormat},
+    systemd::Notifier,
+};
+use anyhow::{Context, Result, bail};
+use iptables::FirewallProvider;
+use nfqws::{BypassSoftware, Hostlists};
+use rustix::{
+    event::{self, PollFd, PollFlags, Timespec},
+    io::Errno,
+};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    low_level::pipe,
+};
+use std::{
+    fs,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    time::{Duration, Instant},
+};
+use tracing::{error, info, warn};
+
+/// Deadline for a client to send its request and read the response. Clients
+/// are served one at a time, so a silent one holds up the others until then
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Owns nfqws and the rules, serving control requests on a Unix socket
+pub struct Daemon<'a, FP, BS>
+where
+    FP: FirewallProvider,
+    BS: BypassSoftware,
+{
+    pub iptables: &'a FP,
+    pub nfqws: &'a BS,
+    pub profiles: Profiles,
+    pub hostlists: &'a Hostlists,
+    /// Config re-read on `reload`
+    pub config_path: &'a Path,
+    pub settings: Settings,
+    /// Strategy of the installed rules, `None` while stopped
+    pub selection: Option<Selection>,
+    /// Held while requests change rules, nfqws or hostlists
+    pub lock: &'a ConfigLock,
+}
+
+/// Config to restart the daemon with after `reload`
+pub struct Reload {
+    pub config: Config,
+    /// Whether the daemon was started before the reload
+    pub start: bool,
+    /// Profile forced before the reload
+    pub profile: Option<String>,
+}
+
+/// SIGTERM and SIGINT, delivered through a socket so they wake up [`Daemon::serve`]
+pub struct Signals {
+    receiver: UnixStream,
+}
+
+impl Signals {
+    pub fn new() -> Result<Self> {
+        let (receiver, sender) = UnixStream::pair()?;
+        receiver.set_nonblocking(true)?;
+        for signal in [SIGTERM, SIGINT] {
+            pipe::register(signal, sender.try_clone()?)
+                .with_context(|| format!("Failed to handle signal {}", signal))?;
+        }
+        Ok(Self { receiver })
+    }
+
+    /// Whether a signal arrived since the last call
+    fn received(&self) -> bool {
+        let mut buf = [0u8; 16];
+        let mut received = false;
+        while let Ok(1..) = (&self.receiver).read(&mut buf) {
+            received = true;
+        }
+        received
+    }
+}
+
+/// Listen on `path`, replacing a stale socket left by a crashed daemon
+pub fn bind(path: &Path) -> Result<UnixListener> {
+    match UnixStream::connect(path) {
+        Ok(_) => bail!("Daemon is already listening on {}", path.display()),
+        Err(error) if error.kind() == ErrorKind::NotFound => {}
+        Err(_) => {
+            let metadata = fs::symlink_metadata(path)
+                .with_context(|| format!("Failed to inspect {}", path.display()))?;
+            if !metadata.file_type().is_socket() {
+                bail!("{} is not a socket, refusing to replace it", path.display());
+            }
+            fs::remove_file(path)
+                .with_context(|| format!("Failed to remove stale socket {}", path.display()))?
+        }
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Failed to listen on {}", path.display()))?;
+    // Requests change the firewall, only root may send them
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    info!(path = %path.display(), "Listening for requests");
+    Ok(listener)
+}
+
+impl<FP, BS> Daemon<'_, FP, BS>
+where
+    FP: FirewallProvider,
+    BS: BypassSoftware,
+{
+    /// Serve requests until a reload is requested, keeping the systemd watchdog
+    /// fed while nfqws is alive. `None` after SIGTERM or SIGINT, with the rules
+    /// and nfqws removed
+    pub fn serve(
+        &mut self,
+        listener: &UnixListener,
+        signals: &Signals,
+        notifier: &Notifier,
+    ) -> Result<Option<Reload>> {
+        let mut fed = Instant::now();
+        loop {
+            let timeout = match notifier.watchdog_interval() {
+                Some(interval) => {
+                    let elapsed = fed.elapsed();
+                    if elapsed >= interval {
+                        self.feed_watchdog(notifier);
+                        fed = Instant::now();
+                        continue;
+                    }
+                    Some(interval - elapsed)
+                }
+                None => None,
+            };
+            match wait(listener, signals, timeout)? {
+                Wakeup::Connection => {}
+                Wakeup::Signal => {
+                    notifier.stopping();
+                    self.terminate()?;
+                    return Ok(None);
+                }
+                Wakeup::Timeout => continue,
+            }
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(error) => {
+                    warn!(error = %error, "Failed to accept connection");
+                    continue;
+                }
+            };
+            // Requests may run iptables for up to the command timeout each,
+            // give them a full watchdog interval
+            if notifier.watchdog_interval().is_some() {
+                self.feed_watchdog(notifier);
+                fed = Instant::now();
+            }
+            match self.handle(stream) {
+                Ok(Some(reload)) => {
+                    notifier.reloading();
+                    return Ok(Some(reload));
+                }
+                Ok(None) => {}
+                Err(error) => warn!(error = format!("{:#}", error), "Failed to serve client"),
+            }
+            notifier.status(&self.summary());
+        }
+    }
+
+    /// Withheld while nfqws is dead after start, so systemd restarts the service
+    fn feed_watchdog(&self, notifier: &Notifier) {
+        if self.selection.is_some() {
+            match self.nfqws.is_running() {
+                Ok(true) => {}
+                Ok(false) => {
+                    error!("nfqws is not running, withholding watchdog");
+                    notifier.status("nfqws is not running");
+                    return;
+                }
+                Err(error) => warn!(error = format!("{:#}", error), "Failed to check nfqws"),
+            }
+        }
+        notifier.watchdog();
+    }
+
+    /// One-line state shown by `systemctl status`
+    pub fn summary(&self) -> String {
+        match &self.selection {
+            None => "Stopped".to_string(),
+            Some(Selection {
+                profile: Some(profile),
+                ..
+            }) => format!("Running with profile {}", profile),
+            Some(_) => "Running".to_string(),
+        }
+    }
+
+    /// Install rules for the current uplink and start nfqws
+    pub fn start(&mut self) -> Result<()> {
+        if self.selection.is_some() {
+            bail!("Daemon is already started");
+        }
+        let selection = self.profiles.detect();
+        self.iptables.setup_rules(selection.ports.clone())?;
+        let opt = selection.opt.clone();
+        self.selection = Some(selection);
+        self.nfqws.run(opt)?;
+        Ok(())
+    }
+
+    /// Start with the lock held, first cleaning up after a dead daemon when `recover`
+    pub fn boot(&mut self, recover: bool) -> Result<()> {
+        let _lock = lock::acquire(self.lock)?;
+        if recover && let Err(error) = self.recover() {
+            error!(error = format!("{:#}", error), "Failed to clean up");
+        }
+        self.start()
+    }
+
+    /// Remove rules and nfqws left by a daemon that died, so a respawned one can start
+    fn recover(&mut self) -> Result<()> {
+        let rules = self.iptables.rule_status(self.profiles.ports.clone())?;
+        let installed = rules.iter().any(|rule| rule.installed);
+        let running = self.nfqws.is_running()?;
+        if installed || running {
+            warn!("Cleaning up after a previous daemon");
+        }
+        if installed {
+            self.iptables.clean_rules()?;
+        }
+        if running {
+            self.nfqws.kill()?;
+        }
+        Ok(())
+    }
+
+    /// Remove the rules and nfqws before exiting
+    fn terminate(&mut self) -> Result<()> {
+        info!("Received termination signal, stopping");
+        let _lock = lock::acquire(self.lock)?;
+        if self.selection.is_some() {
+            self.stop()?;
+        }
+        Ok(())
+    }
+
+    pub fn stop(&mut self) -> Result<()> {
+        self.selection = None;
+        self.iptables.clean_rules()?;
+        self.nfqws.kill()?;
+        Ok(())
+    }
+
+    fn handle(&mut self, stream: UnixStream) -> Result<Option<Reload>> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        let mut reload = None;
+        let response = line.trim().parse().and_then(|request: Request| {
+            info!(%request, "Received request");
+            match request {
+                Request::Reload => lock::acquire(self.lock)
+                    .and_then(|_lock| self.reload())
+                    .map(|config| {
+                        reload = Some(config);
+                        vec!["Reloading config".to_string()]
+                    }),
+                request => self.execute(request),
+            }
+        });
+
+        let mut stream = &stream;
+        match response {
+            Ok(lines) => {
+                writeln!(stream, "ok")?;
+                for line in lines {
+                    writeln!(stream, "{}", line)?;
+                }
+            }
+            Err(error) => {
+                error!(error = format!("{:#}", error), "Request failed");
+                writeln!(stream, "error: {:#}", error)?;
+            }
+        }
+        Ok(reload)
+    }
+
+    /// Load the config and stop before serving with it
+    fn reload(&mut self) -> Result<Reload> {
+        let config = config::load(self.config_path)?;
+        let start = self.selection.is_some();
+        if start {
+            self.stop()?;
+        }
+        Ok(Reload {
+            config,
+            start,
+            profile: self.profiles.forced.clone(),
+        })
+    }
+
+    /// Run request, returning the lines to print on the client. `reload` is
+    /// only served by [`Daemon::serve`]
+    pub fn execute(&mut self, request: Request) -> Result<Vec<String>> {
+        let _lock = match &request {
+            Request::Start
+            | Request::Stop
+            | Request::Restart
+            | Request::Profile { name: Some(_) }
+            | Request::Hosts {
+                action: HostsAction::Add(_) | HostsAction::Remove(_),
+                ..
+            } => Some(lock::acquire(self.lock)?),
+            _ => None,
+        };
+        let lines = match request {
+            Request::Start => {
+                self.start()?;
+                vec!["Starting daemon".to_string()]
+            }
+            Request::Stop => {
+                self.stop()?;
+                vec!["Stoping daemon".to_string()]
+            }
+            Request::Restart => {
+                self.stop()?;
+                self.start()?;
+                vec!["Restarting daemon".to_string()]
+            }
+            Request::Reload => bail!("No daemon is running"),
+            Request::Status {
+                format: StatusFormat::Json,
+            } => {
+                let selection = match &self.selection {
+                    Some(selection) => selection.clone(),
+                    None => self.profiles.detect(),
+                };
+                let status =
+                    status::collect(self.iptables, self.nfqws, &self.settings, &selection, true)?;
+                serde_json::to_string_pretty(&status)?
+                    .lines()
+                    .map(String::from)
+                    .collect()
+            }
+            Request::Status {
+                format: StatusFormat::Text,
+            } => {
+                let mut lines = vec![if self.nfqws.is_running()? {
+                    "Daemon is running".to_string()
+                } else {
+                    "Daemon is not running".to_string()
+                }];
+                if let Some(profile) = self.selection.as_ref().and_then(|s| s.profile.as_ref()) {
+                    lines.push(format!("Profile: {}", profile));
+                }
+                lines
+            }
+            Request::Hosts { action, exclude } => hosts::execute(self.hostlists, &action, exclude)?,
+            Request::Profile { name: None } => self.list_profiles(),
+            Request::Profile { name: Some(name) } => {
+                self.profiles.forced = if name == AUTO_PROFILE {
+                    None
+                } else if self.profiles.find(&name).is_some() {
+                    Some(name.clone())
+                } else {
+                    bail!("Unknown profile '{}'", name);
+                };
+                if self.selection.is_some() {
+                    self.stop()?;
+                    self.start()?;
+                }
+                vec![format!("Profile: {}", name)]
+            }
+            Request::Logs { lines } => logs::tail(lines),
+        };
+        Ok(lines)
+    }
+
+    /// `auto` and the configured profiles, active ones marked with `*`
+    fn list_profiles(&self) -> Vec<String> {
+        let active = self.selection.as_ref().and_then(|s| s.profile.as_deref());
+        let marker = |active: bool| if active { "*" } else { " " };
+        let mut lines = vec![format!(
+            "{} {}",
+            marker(self.profiles.forced.is_none()),
+            AUTO_PROFILE
+        )];
+        for profile in &self.profiles.profiles {
+            lines.push(format!(
+                "{} {}",
+                marker(active == Some(profile.name.as_str())),
+                profile.name
+            ));
+        }
+        lines
+    }
+}
+
+/// What woke up [`Daemon::serve`]
+enum Wakeup {
+    Connection,
+    Signal,
+    Timeout,
+}
+
+/// Wait up to `timeout` (forever when `None`) for a connection on `listener`
+/// or a signal, signals first
+fn wait(listener: &UnixListener, signals: &Signals, timeout: Option<Duration>) -> Result<Wakeup> {
+    let timeout = timeout.map(Timespec::try_from).transpose()?;
+    let mut fds = [
+        PollFd::new(&signals.receiver, PollFlags::IN),
+        PollFd::new(listener, PollFlags::IN),
+    ];
+    match event::poll(&mut fds, timeout.as_ref()) {
+        Ok(_) if !fds[0].revents().is_empty() && signals.received() => Ok(Wakeup::Signal),
+        Ok(_) if !fds[1].revents().is_empty() => Ok(Wakeup::Connection),
+        Ok(_) | Err(Errno::INTR) => Ok(Wakeup::Timeout),
+        Err(error) => Err(error).context("Failed to wait for connections"),
+    }
+}
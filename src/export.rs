@@ -0,0 +1,141 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use iptables::{DualStack, FirewallProvider, IptablesRecorderFactory, PortSpec};
+use nfqws::BypassSoftware;
+use runner::{CommandLog, shell_line};
+use std::fmt::Write;
+
+const NFT_TABLE: &str = "zapret_ux";
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExportFormat {
+    /// Plain iptables commands
+    Sh,
+    /// Single iptables-restore transaction
+    IptablesRestore,
+    /// nftables table loaded with nft -f
+    Nft,
+}
+
+/// Renders start/stop script from recording firewall and nfqws bindings
+pub struct Exporter<'a, BS: BypassSoftware> {
+    pub iptables: &'a DualStack<IptablesRecorderFactory>,
+    pub nfqws: &'a BS,
+    pub log: &'a CommandLog,
+}
+
+impl<BS: BypassSoftware> Exporter<'_, BS> {
+    pub fn render(
+        &self,
+        format: ExportFormat,
+        ports: Vec<PortSpec>,
+        opt: Vec<String>,
+    ) -> Result<String> {
+        let (mut start, mut stop) = match format {
+            ExportFormat::Sh => {
+                let start = self.record(|| self.iptables.setup_rules(ports))?;
+                let stop = self.record(|| self.iptables.clean_rules())?;
+                (start, or_true(stop))
+            }
+            ExportFormat::IptablesRestore => {
+                let (mut start, mut stop) = (Vec::new(), Vec::new());
+                for iptables in self.iptables.families() {
+                    let rule_set = iptables.rule_set(ports.clone());
+                    let restore = shell_line(&[
+                        format!("{}-restore", iptables.iptables_file()),
+                        "--noflush".to_string(),
+                    ]);
+                    start.push(format!("{} <<'EOF'\n{}EOF", restore, rule_set.to_restore()));
+                    stop.push(format!(
+                        "{} <<'EOF' || true\n{}EOF",
+                        restore,
+                        rule_set.to_restore_cleanup()
+                    ));
+                }
+                (start, stop)
+            }
+            ExportFormat::Nft => {
+                let (mut start, mut stop) = (Vec::new(), Vec::new());
+                for iptables in self.iptables.families() {
+                    let family = iptables.family();
+                    let rule_set = iptables.rule_set(ports.clone());
+                    start.push(format!(
+                        "nft -f - <<'EOF'\n{}EOF",
+                        rule_set.to_nft(family, NFT_TABLE)
+                    ));
+                    stop.push(format!(
+                        "nft delete table {} {} || true",
+                        family.nft_name(),
+                        NFT_TABLE
+                    ));
+                }
+                (start, stop)
+            }
+        };
+
+        start.extend(self.record(|| self.nfqws.run(opt))?);
+        stop.extend(or_true(self.record(|| self.nfqws.kill())?));
+
+        Ok(script(format, &start, &stop))
+    }
+
+    /// Run action against recording bindings and return the recorded command lines
+    fn record<A: FnOnce() -> Result<()>>(&self, action: A) -> Result<Vec<String>> {
+        self.log.clear();
+        action()?;
+        let lines = self
+            .log
+            .entries()
+            .iter()
+            .map(|argv| shell_line(argv))
+            .collect();
+        self.log.clear();
+        Ok(lines)
+    }
+}
+
+fn or_true(lines: Vec<String>) -> Vec<String> {
+    lines
+        .into_iter()
+        .map(|line| format!("{} || true", line))
+        .collect()
+}
+
+fn script(format: ExportFormat, start: &[String], stop: &[String]) -> String {
+    let mut out = String::new();
+    let format = format
+        .to_possible_value()
+        .map(|value| value.get_name().to_string());
+    let _ = writeln!(out, "#!/bin/sh");
+    let _ = writeln!(
+        out,
+        "# Generated by zapret-ux {} (format: {})",
+        env!("CARGO_PKG_VERSION"),
+        format.unwrap_or_default()
+    );
+    let _ = writeln!(out, "set -e");
+    write_function(&mut out, "start", start);
+    write_function(&mut out, "stop", stop);
+    let _ = writeln!(out);
+    let _ = writeln!(out, "case \"$1\" in");
+    let _ = writeln!(out, "    start) start ;;");
+    let _ = writeln!(out, "    stop) stop ;;");
+    let _ = writeln!(out, "    restart) stop; start ;;");
+    let _ = writeln!(
+        out,
+        "    *) echo \"Usage: $0 {{start|stop|restart}}\" >&2; exit 1 ;;"
+    );
+    let _ = writeln!(out, "esac");
+    out
+}
+
+fn write_function(out: &mut String, name: &str, lines: &[String]) {
+    let _ = writeln!(out);
+    let _ = writeln!(out, "{}() {{", name);
+    // Only the first line of an entry is indented, so heredoc bodies
+    // and their terminators stay at the start of the line
+    for line in lines {
+        let _ = writeln!(out, "    {}", line);
+    }
+    let _ = writeln!(out, "}}");
+}
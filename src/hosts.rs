@@ -0,0 +1,97 @@
+use crate::control::HostsAction;
+use anyhow::{Context, Result, bail};
+use nfqws::Hostlists;
+use std::{fs, io::ErrorKind, path::Path};
+
+/// Edit or print a hostlist, returning the lines to print
+pub fn execute(hostlists: &Hostlists, action: &HostsAction, exclude: bool) -> Result<Vec<String>> {
+    let path = Path::new(if exclude {
+        &hostlists.exclude
+    } else {
+        &hostlists.hosts
+    });
+    let line = match action {
+        HostsAction::Add(hosts) => format!("Added {} hosts", add(path, hosts)?),
+        HostsAction::Remove(hosts) => format!("Removed {} hosts", remove(path, hosts)?),
+        HostsAction::List => return read(path),
+    };
+    Ok(vec![line])
+}
+
+/// Hosts in a hostlist file, one per line. Missing file is an empty list
+pub fn read(path: &Path) -> Result<Vec<String>> {
+    Ok(lines(path)?
+        .into_iter()
+        .filter_map(|line| host(&line).map(String::from))
+        .collect())
+}
+
+/// Append hosts that are not in the list yet. Returns the number of added hosts
+pub fn add(path: &Path, hosts: &[String]) -> Result<usize> {
+    validate(hosts)?;
+    let mut lines = lines(path)?;
+    let mut added = 0;
+    for new in hosts {
+        let new = new.to_ascii_lowercase();
+        if !lines.iter().any(|line| host(line) == Some(new.as_str())) {
+            lines.push(new);
+            added += 1;
+        }
+    }
+    if added > 0 {
+        write(path, &lines)?;
+    }
+    Ok(added)
+}
+
+/// Remove hosts from the list, keeping comments. Returns the number of removed hosts
+pub fn remove(path: &Path, hosts: &[String]) -> Result<usize> {
+    validate(hosts)?;
+    let mut lines = lines(path)?;
+    let before = lines.len();
+    lines.retain(|line| {
+        host(line).is_none_or(|host| {
+            !hosts
+                .iter()
+                .any(|removed| removed.eq_ignore_ascii_case(host))
+        })
+    });
+    let removed = before - lines.len();
+    if removed > 0 {
+        write(path, &lines)?;
+    }
+    Ok(removed)
+}
+
+fn lines(path: &Path) -> Result<Vec<String>> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(content.lines().map(String::from).collect()),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(error) => Err(error).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Host on a line, `None` for blank lines and comments
+fn host(line: &str) -> Option<&str> {
+    let line = line.trim();
+    (!line.is_empty() && !line.starts_with('#')).then_some(line)
+}
+
+fn validate(hosts: &[String]) -> Result<()> {
+    for host in hosts {
+        if host.is_empty() || host.starts_with('#') || host.contains(char::is_whitespace) {
+            bail!("Invalid host '{}'", host);
+        }
+    }
+    Ok(())
+}
+
+fn write(path: &Path, lines: &[String]) -> Result<()> {
+    let mut content = lines.join("\n");
+    content.push('\n');
+    // nfqws re-reads a hostlist when its file changes, replace it atomically
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use crate::config::ConfigLock;
+use anyhow::{Context, Result, bail};
+use runner::shell_line;
+use rustix::{
+    fs::{FlockOperation, flock},
+    io::Errno,
+};
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info};
+
+/// Delay between attempts to take a busy lock
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Held lock, released on drop. Taking it twice in one process deadlocks until timeout
+pub struct Lock {
+    _file: File,
+}
+
+/// Take the lock, waiting up to `config.wait_secs` for its holder to finish
+pub fn acquire(config: &ConfigLock) -> Result<Lock> {
+    let path = config.path.as_std_path();
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to open lock file {}", path.display()))?;
+
+    let started = Instant::now();
+    let mut waiting = false;
+    loop {
+        match flock(&file, FlockOperation::NonBlockingLockExclusive) {
+            Ok(()) => break,
+            Err(Errno::WOULDBLOCK) => {}
+            Err(error) => {
+                return Err(error).with_context(|| format!("Failed to lock {}", path.display()));
+            }
+        }
+        let holder = holder(path);
+        if started.elapsed() >= Duration::from_secs(config.wait_secs) {
+            bail!(
+                "Another zapret-ux is changing rules: {} is held by {}, gave up after {}s",
+                path.display(),
+                holder,
+                config.wait_secs
+            );
+        }
+        if !waiting {
+            info!(holder, "Waiting for another zapret-ux to finish");
+            waiting = true;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    // Lets the waiting processes name the holder
+    let argv: Vec<String> = env::args().collect();
+    file.set_len(0)?;
+    writeln!(file, "{} {}", std::process::id(), shell_line(&argv))?;
+    debug!(path = %path.display(), "Lock acquired");
+    Ok(Lock { _file: file })
+}
+
+/// `pid 42 (zapret-ux start)` from the lock file
+fn holder(path: &std::path::Path) -> String {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    match content.trim().split_once(' ') {
+        Some((pid, command)) => format!("pid {} ({})", pid, command),
+        None if !content.trim().is_empty() => format!("pid {}", content.trim()),
+        None => "another process".to_string(),
+    }
+}
@@ -0,0 +1,60 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    sync::Mutex,
+};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Lines kept for `logs`
+const CAPACITY: usize = 500;
+
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Log writer keeping the last lines in memory, served by the daemon
+#[derive(Clone, Copy, Debug)]
+pub struct RecentLogs;
+
+/// Buffers one formatted event and stores it when dropped
+pub struct RecentWriter(Vec<u8>);
+
+impl<'a> MakeWriter<'a> for RecentLogs {
+    type Writer = RecentWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RecentWriter(Vec::new())
+    }
+}
+
+impl Write for RecentWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for RecentWriter {
+    fn drop(&mut self) {
+        let Ok(mut recent) = RECENT.lock() else {
+            return;
+        };
+        for line in String::from_utf8_lossy(&self.0).lines() {
+            if recent.len() == CAPACITY {
+                recent.pop_front();
+            }
+            recent.push_back(line.to_string());
+        }
+    }
+}
+
+/// Last `lines` log lines, oldest first
+pub fn tail(lines: usize) -> Vec<String> {
+    let Ok(recent) = RECENT.lock() else {
+        return Vec::new();
+    };
+    let skip = recent.len().saturating_sub(lines);
+    recent.iter().skip(skip).cloned().collect()
+}
@@ -1,15 +1,51 @@
+mod apps;
+mod autostart;
 mod config;
+mod control;
+mod daemon;
+mod export;
+mod hosts;
+mod lock;
+mod logs;
+mod module;
+mod netlink;
+mod openwrt;
+mod profiles;
+mod status;
+mod supervisor;
+mod systemd;
+mod uci;
+#[cfg(feature = "webui")]
+mod webui;
 
 use anyhow::{Result, bail};
+use camino::Utf8Path;
 use clap::{Parser, Subcommand};
 use config::*;
-use iptables::{FirewallProvider, Iptables};
-use nfqws::{BypassSoftware, Nfqws};
+use control::{HostsAction, Request};
+use daemon::Daemon;
+use export::*;
+use iptables::{
+    AppScope, DualStack, FailMode, Family, FirewallProvider, Iptables, IptablesBindingFactory,
+    IptablesCmdFactory, IptablesRecorderFactory,
+};
+use netlink::NetlinkMonitor;
+use nfqws::{BypassSoftware, Nfqws, NfqwsCmdFactory, NfqwsRecorderFactory, PgrepFn, PkillFn};
+use profiles::{Profiles, Selection};
+use runner::{CommandLog, CommandRunner, shell_line};
 use rustix::process;
-use std::path::PathBuf;
-use tracing::info;
+use status::{Settings, StatusFormat};
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use supervisor::*;
+use tracing::{error, info, warn};
 use tracing_subscriber::{
-    EnvFilter,
+    EnvFilter, Layer,
+    filter::LevelFilter,
     fmt::{self, time::ChronoUtc},
     layer::SubscriberExt,
     util::SubscriberInitExt,
@@ -27,6 +63,10 @@ struct Cli {
     #[arg(short, long, value_name = "FILE", default_value = DEFAULT_CONFIG_PATH)]
     config: PathBuf,
 
+    /// Print commands instead of executing them
+    #[arg(long, global = true)]
+    dry_run: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -40,11 +80,209 @@ enum Commands {
     /// Restart daemon
     Restart,
     /// Print status daemon
-    Status,
+    Status {
+        /// Output format
+        #[arg(short, long, value_enum, default_value_t = StatusFormat::Text)]
+        format: StatusFormat,
+    },
+    /// Run in the foreground owning nfqws and the rules, controlled over a Unix socket
+    Daemon,
+    /// Make the running daemon re-read the config
+    Reload,
+    /// Force a strategy profile in the running daemon, `auto` for uplink detection.
+    /// Lists the profiles without a name
+    Profile { name: Option<String> },
+    /// Print recent daemon log lines
+    Logs {
+        #[arg(short = 'n', long, default_value_t = control::DEFAULT_LOG_LINES)]
+        lines: usize,
+    },
+    /// Edit hostlists used by the host file filter modes
+    Hosts {
+        #[command(subcommand)]
+        command: HostsCommand,
+    },
+    /// Start daemon, keep nfqws running and re-apply removed rules.
+    /// Falls back to fail-open rules in fail-closed mode when nfqws can't be restarted
+    #[command(alias = "watch")]
+    Supervise,
+    /// Export rules and nfqws command line as a standalone start/stop script
+    Export {
+        /// Script flavour
+        #[arg(short, long, value_enum, default_value_t = ExportFormat::Sh)]
+        format: ExportFormat,
+        /// Write script to file instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Manage apps the rules are scoped to (Android)
+    Apps {
+        #[command(subcommand)]
+        command: AppsCommand,
+    },
+    /// Local web UI for the Magisk/KernelSU WebUI tab
+    #[cfg(feature = "webui")]
+    Webui {
+        #[command(subcommand)]
+        command: WebuiCommand,
+    },
+    /// Magisk/KernelSU module with this binary, its scripts and default settings
+    Module {
+        #[command(subcommand)]
+        command: ModuleCommand,
+    },
+    /// Run the daemon under systemd
+    Systemd {
+        #[command(subcommand)]
+        command: SystemdCommand,
+    },
+    /// Run the daemon under OpenWrt procd with a UCI config
+    Openwrt {
+        #[command(subcommand)]
+        command: OpenwrtCommand,
+    },
     #[command(hide = true)]
     Autostart,
 }
 
+#[derive(Subcommand, Debug)]
+enum OpenwrtCommand {
+    /// Write a procd init script for this binary, reloading on `uci commit zapret-ux`.
+    /// Creates `/etc/config/zapret-ux` and the hostlists when missing
+    Install {
+        /// Root of the filesystem to install into, e.g. a package staging directory
+        #[arg(long, value_name = "DIR", default_value = "/")]
+        root: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SystemdCommand {
+    /// Write a `Type=notify` unit running the daemon with this binary and config
+    Install {
+        /// Directory to write the unit to
+        #[arg(long, value_name = "DIR", default_value = systemd::UNIT_DIR)]
+        dir: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ModuleCommand {
+    /// Build a flashable module zip, byte-identical for the same binaries
+    Build {
+        /// Zip to write
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+        /// zapret-ux binary for an installer architecture (arm, arm64, x86, x64),
+        /// e.g. `arm64=target/aarch64-linux-android/release/zapret-ux`
+        #[arg(long = "binary", value_name = "ARCH=FILE", required = true, value_parser = module::parse_binary)]
+        binaries: Vec<(module::Arch, PathBuf)>,
+    },
+    /// Install the module from the current binary, active after reboot.
+    /// Keeps existing settings and hostlists
+    Install {
+        /// Magisk/KernelSU data directory
+        #[arg(long, value_name = "DIR", default_value = module::ADB_DIR)]
+        root: PathBuf,
+    },
+}
+
+#[cfg(feature = "webui")]
+#[derive(Subcommand, Debug)]
+enum WebuiCommand {
+    /// Serve the UI over HTTP
+    Serve {
+        /// Loopback address to listen on
+        #[arg(short, long, default_value = "127.0.0.1:8787")]
+        listen: std::net::SocketAddr,
+    },
+    /// Write the UI as a static page calling the CLI through KernelSU/Magisk `ksu.exec`,
+    /// e.g. into the module `webroot`
+    Export {
+        dir: PathBuf,
+        /// zapret-ux binary the page runs. Defaults to the current one
+        #[arg(long, value_name = "FILE")]
+        bin: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AppsCommand {
+    /// Add packages to the config
+    Add {
+        #[arg(required = true)]
+        packages: Vec<String>,
+    },
+    /// Remove packages from the config
+    Remove {
+        #[arg(required = true)]
+        packages: Vec<String>,
+    },
+    /// Print configured packages with their UIDs
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+enum HostsCommand {
+    /// Add hosts to the hostlist
+    Add {
+        /// Edit the exclude hostlist
+        #[arg(long)]
+        exclude: bool,
+        #[arg(required = true)]
+        hosts: Vec<String>,
+    },
+    /// Remove hosts from the hostlist
+    Remove {
+        /// Edit the exclude hostlist
+        #[arg(long)]
+        exclude: bool,
+        #[arg(required = true)]
+        hosts: Vec<String>,
+    },
+    /// Print hosts in the hostlist
+    List {
+        /// Print the exclude hostlist
+        #[arg(long)]
+        exclude: bool,
+    },
+}
+
+impl HostsCommand {
+    fn request(&self) -> Request {
+        let (action, exclude) = match self {
+            Self::Add { exclude, hosts } => (HostsAction::Add(hosts.clone()), *exclude),
+            Self::Remove { exclude, hosts } => (HostsAction::Remove(hosts.clone()), *exclude),
+            Self::List { exclude } => (HostsAction::List, *exclude),
+        };
+        Request::Hosts { action, exclude }
+    }
+}
+
+/// Commands run on the rules and nfqws directly when no daemon is running
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Action {
+    Start,
+    Stop,
+    Restart,
+    Status(StatusFormat),
+    Autostart,
+}
+
+impl Action {
+    /// Request sent instead when a daemon is running
+    fn request(self) -> Option<Request> {
+        match self {
+            Self::Start => Some(Request::Start),
+            Self::Stop => Some(Request::Stop),
+            Self::Restart => Some(Request::Restart),
+            Self::Status(format) => Some(Request::Status { format }),
+            // A running daemon owns the rules, so boot just asks it to start
+            Self::Autostart => Some(Request::Start),
+        }
+    }
+}
+
 #[cfg(not(any(target_os = "android", target_os = "linux")))]
 fn main() {
     panic!("Your OS is not android or linux");
@@ -53,71 +291,571 @@ fn main() {
 #[cfg(any(target_os = "android", target_os = "linux"))]
 fn main() -> Result<()> {
     init_logger();
-    check_root()?;
     let cli = Cli::parse();
-    let config: Config = confy::load_path(cli.config)?;
-    let Config {
-        iptables,
-        nfqws,
-        mark_supported,
-        autostart_enabled,
-    } = config;
-
-    let ConfigIptables {
-        iptables_path,
-        connbytes_supported,
-        ports,
-    } = iptables;
-
-    let ConfigNfqws {
-        nfqws_path,
-        pgrep_path,
-        pkill_path,
-        filter_mode,
-        opt,
-    } = nfqws;
-
-    let iptables = Iptables::new(iptables_path, mark_supported, connbytes_supported);
-    let nfqws = Nfqws::new(
-        nfqws_path,
-        pgrep_path,
-        pkill_path,
+    match &cli.command {
+        Commands::Start => run_action(&cli, Action::Start),
+        Commands::Stop => run_action(&cli, Action::Stop),
+        Commands::Restart => run_action(&cli, Action::Restart),
+        Commands::Status { format } => run_action(&cli, Action::Status(*format)),
+        Commands::Autostart => run_action(&cli, Action::Autostart),
+        Commands::Daemon => {
+            reject_dry_run(&cli, "daemon control commands")?;
+            check_root()?;
+            run_daemon(&cli.config, config::load(&cli.config)?)
+        }
+        Commands::Reload => run_request(&cli, Request::Reload),
+        Commands::Profile { name } => run_request(&cli, Request::Profile { name: name.clone() }),
+        Commands::Logs { lines } => run_request(&cli, Request::Logs { lines: *lines }),
+        Commands::Hosts { command } => run_request(&cli, command.request()),
+        Commands::Supervise => {
+            if cli.dry_run {
+                bail!("supervise never returns and can't be used with --dry-run");
+            }
+            check_root()?;
+            run_supervisor(config::load(&cli.config)?)
+        }
+        Commands::Export { format, output } => {
+            run_export(*format, output.as_deref(), &config::load(&cli.config)?)
+        }
+        Commands::Apps { command } => {
+            if !matches!(command, AppsCommand::List) {
+                reject_dry_run(&cli, "apps add and remove")?;
+            }
+            if !cli.dry_run {
+                check_root()?;
+            }
+            run_apps_command(command, config::load(&cli.config)?, &cli.config)
+        }
+        #[cfg(feature = "webui")]
+        Commands::Webui { command } => {
+            if let WebuiCommand::Serve { .. } = command {
+                reject_dry_run(&cli, "webui serve")?;
+            }
+            if !cli.dry_run {
+                check_root()?;
+            }
+            run_webui(command, &cli.config, &config::load(&cli.config)?)
+        }
+        Commands::Module { command } => {
+            reject_dry_run(&cli, "install commands")?;
+            run_module_command(command)
+        }
+        Commands::Systemd {
+            command: SystemdCommand::Install { dir },
+        } => {
+            reject_dry_run(&cli, "install commands")?;
+            check_root()?;
+            config::load(&cli.config)?;
+            let config_path = std::path::absolute(&cli.config)?;
+            let unit = systemd::install(dir, &std::env::current_exe()?, &config_path)?;
+            info!(
+                "Unit written to {}, start it with `systemctl daemon-reload && systemctl enable --now {}`",
+                unit.display(),
+                systemd::UNIT_NAME
+            );
+            Ok(())
+        }
+        Commands::Openwrt {
+            command: OpenwrtCommand::Install { root },
+        } => {
+            reject_dry_run(&cli, "install commands")?;
+            check_root()?;
+            openwrt::install(root, &std::env::current_exe()?)?;
+            info!(
+                "Init script installed, start it with \
+                 `/etc/init.d/zapret-ux enable && /etc/init.d/zapret-ux start`"
+            );
+            Ok(())
+        }
+    }
+}
+
+fn reject_dry_run(cli: &Cli, commands: &str) -> Result<()> {
+    if cli.dry_run {
+        bail!("{} can't be used with --dry-run", commands);
+    }
+    Ok(())
+}
+
+fn run_module_command(command: &ModuleCommand) -> Result<()> {
+    match command {
+        ModuleCommand::Build { output, binaries } => {
+            let files = module::files(module::Layout::Zip(binaries))?;
+            fs::write(output, module::zip(&files))?;
+            info!("Module written to {}", output.display());
+        }
+        ModuleCommand::Install { root } => {
+            check_root()?;
+            module::install(root, &std::env::current_exe()?)?;
+            info!("Module installed, reboot to apply");
+        }
+    }
+    Ok(())
+}
+
+/// Send `request` to the daemon. Hosts are edited in place when none is running
+fn run_request(cli: &Cli, request: Request) -> Result<()> {
+    reject_dry_run(cli, "daemon control commands")?;
+    check_root()?;
+    let config = config::load(&cli.config)?;
+    let lines = match control::request(config.daemon.socket_path.as_std_path(), &request)? {
+        Some(lines) => lines,
+        None => match request {
+            Request::Hosts { action, exclude } => {
+                let _lock = match action {
+                    HostsAction::List => None,
+                    _ => Some(lock::acquire(&config.lock)?),
+                };
+                hosts::execute(&config.nfqws.hostlists, &action, exclude)?
+            }
+            _ => bail!("No daemon is running"),
+        },
+    };
+    for line in lines {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Run `action` through the daemon when it is running, otherwise on the rules
+/// and nfqws directly
+fn run_action(cli: &Cli, action: Action) -> Result<()> {
+    if cli.dry_run {
+        return dry_run(cli, action);
+    }
+    check_root()?;
+    let config = config::load(&cli.config)?;
+    if let Some(request) = action.request()
+        && let Some(lines) = control::request(config.daemon.socket_path.as_std_path(), &request)?
+    {
+        for line in lines {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+    let runner = CommandRunner::new(Duration::from_secs(config.command_timeout_secs));
+    if config.autostart_enabled && action == Action::Autostart {
+        autostart::wait_ready(&config.autostart, &config.nfqws.nfqws_path, runner)?;
+    }
+    // Held until the command finishes
+    let _lock = match action {
+        Action::Status(_) => None,
+        _ => Some(lock::acquire(&config.lock)?),
+    };
+
+    let nfqws = build_nfqws(&config.nfqws, config.mark_supported, runner);
+    let xtables_lock = config.iptables.xtables_lock;
+    let firewall = dual_stack(
+        &config.iptables,
+        config.mark_supported,
+        &apps::scope(&config.apps)?,
+        config.iptables.fail_mode,
+        Some(&config.lock),
+        || IptablesCmdFactory::new(xtables_lock, runner),
+    );
+    let settings = Settings::new(&cli.config, &config);
+    let selection = profiles(&config, None).detect();
+    if let Some(status) = run_command(action, &firewall, &nfqws, &settings, &selection, &config)? {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+    }
+    Ok(())
+}
+
+/// Print the commands `action` would run, as JSON for `status --format json`
+fn dry_run(cli: &Cli, action: Action) -> Result<()> {
+    let config = config::load(&cli.config)?;
+    let log = CommandLog::new();
+    let runner = CommandRunner::new(Duration::from_secs(config.command_timeout_secs));
+    let (firewall, nfqws) = recording_bindings(&config, &log, Some(runner))?;
+    let settings = Settings::new(&cli.config, &config);
+    let selection = profiles(&config, None).detect();
+    let status = run_command(action, &firewall, &nfqws, &settings, &selection, &config)?;
+
+    let commands: Vec<String> = log.entries().iter().map(|argv| shell_line(argv)).collect();
+    if status.is_some() {
+        let document = serde_json::json!({ "dry_run": true, "commands": commands });
+        println!("{}", serde_json::to_string_pretty(&document)?);
+    } else {
+        println!("Dry run. Commands that would be executed:");
+        for line in commands {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+fn run_export(format: ExportFormat, output: Option<&Path>, config: &Config) -> Result<()> {
+    let log = CommandLog::new();
+    // Exported scripts may run on another iptables version, so they get no lock flags
+    let (firewall, nfqws) = recording_bindings(config, &log, None)?;
+    let selection = profiles(config, None).detect();
+    let exporter = Exporter {
+        iptables: &firewall,
+        nfqws: &nfqws,
+        log: &log,
+    };
+    let script = exporter.render(format, selection.ports, selection.opt)?;
+    match output {
+        Some(path) => {
+            fs::write(path, script)?;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+            println!("Script written to {}", path.display());
+        }
+        None => print!("{}", script),
+    }
+    Ok(())
+}
+
+fn run_supervisor(config: Config) -> Result<()> {
+    let runner = CommandRunner::new(Duration::from_secs(config.command_timeout_secs));
+    let nfqws = build_nfqws(&config.nfqws, config.mark_supported, runner);
+    let app_scope = apps::scope(&config.apps)?;
+    let xtables_lock = config.iptables.xtables_lock;
+    let factory = || IptablesCmdFactory::new(xtables_lock, runner);
+    let firewall = dual_stack(
+        &config.iptables,
+        config.mark_supported,
+        &app_scope,
+        config.iptables.fail_mode,
+        Some(&config.lock),
+        factory,
+    );
+    let fallback = (config.iptables.fail_mode == FailMode::Closed).then(|| {
+        dual_stack(
+            &config.iptables,
+            config.mark_supported,
+            &app_scope,
+            FailMode::Open,
+            Some(&config.lock),
+            factory,
+        )
+    });
+    let profiles = profiles(&config, None);
+    let selection = profiles.detect();
+    let supervisor = &config.supervisor;
+    Supervisor {
+        iptables: &firewall,
+        fallback: fallback.as_ref(),
+        nfqws: &nfqws,
+        profiles,
+        selection,
+        check_interval: Duration::from_millis(supervisor.check_interval_ms),
+        grace_period: Duration::from_millis(supervisor.grace_period_ms),
+        network_events: network_monitor(supervisor.network_events),
+        debounce: Duration::from_millis(supervisor.debounce_ms),
+        lock: &config.lock,
+    }
+    .run()
+}
+
+/// Serve control requests, rebuilding everything from the new config on reload
+fn run_daemon(path: &Path, mut config: Config) -> Result<()> {
+    let signals = daemon::Signals::new()?;
+    let listener = daemon::bind(config.daemon.socket_path.as_std_path())?;
+    let notifier = systemd::Notifier::from_env();
+    let (mut start, mut forced, mut recover) = (true, None, true);
+    loop {
+        let reload = with_daemon(path, &config, forced, |daemon| {
+            // Keep serving on failure so the config can be fixed and reloaded
+            let status = match start.then(|| daemon.boot(recover)) {
+                Some(Err(error)) => {
+                    error!(error = format!("{:#}", error), "Failed to start daemon");
+                    format!("Failed to start: {:#}", error)
+                }
+                _ => daemon.summary(),
+            };
+            notifier.ready(&status);
+            daemon.serve(&listener, &signals, &notifier)
+        })?;
+        let Some(reload) = reload else {
+            return Ok(());
+        };
+        info!("Config reloaded");
+        (config, start, forced, recover) = (reload.config, reload.start, reload.profile, false);
+    }
+}
+
+type CmdNfqws = Nfqws<NfqwsCmdFactory, PgrepFn, PkillFn>;
+type RecordingNfqws = Nfqws<NfqwsRecorderFactory, PgrepFn, PkillFn>;
+type CmdDaemon<'a> = Daemon<'a, DualStack, CmdNfqws>;
+
+/// Build the firewall, nfqws and the daemon state from `config` and run `action` with them
+fn with_daemon<R, A>(path: &Path, config: &Config, forced: Option<String>, action: A) -> Result<R>
+where
+    A: FnOnce(&mut CmdDaemon) -> Result<R>,
+{
+    let app_scope = apps::scope(&config.apps)?;
+    let runner = CommandRunner::new(Duration::from_secs(config.command_timeout_secs));
+    let nfqws = build_nfqws(&config.nfqws, config.mark_supported, runner);
+    let xtables_lock = config.iptables.xtables_lock;
+    let firewall = dual_stack(
+        &config.iptables,
+        config.mark_supported,
+        &app_scope,
+        config.iptables.fail_mode,
+        Some(&config.lock),
+        || IptablesCmdFactory::new(xtables_lock, runner),
+    );
+    let mut daemon = Daemon {
+        iptables: &firewall,
+        nfqws: &nfqws,
+        profiles: profiles(config, forced),
+        hostlists: &config.nfqws.hostlists,
+        config_path: path,
+        settings: Settings::new(path, config),
+        selection: None,
+        lock: &config.lock,
+    };
+    action(&mut daemon)
+}
+
+/// Serve the web UI, forwarding requests to the daemon when it is running
+#[cfg(feature = "webui")]
+fn run_webui(command: &WebuiCommand, path: &Path, config: &Config) -> Result<()> {
+    match command {
+        WebuiCommand::Serve { listen } => {
+            let socket_path = config.daemon.socket_path.as_std_path();
+            webui::serve(*listen, |request| {
+                if let Some(lines) = control::request(socket_path, &request)? {
+                    return Ok(lines);
+                }
+                if let Request::Profile { name: Some(_) } = request {
+                    bail!("Selecting a profile needs a running daemon");
+                }
+                // Pick up config edits made while serving
+                let config = config::load(path)?;
+                with_daemon(path, &config, None, |daemon| daemon.execute(request))
+            })
+        }
+        WebuiCommand::Export { dir, bin } => {
+            let bin = match bin {
+                Some(bin) => bin.clone(),
+                None => std::env::current_exe()?,
+            };
+            webui::export(dir, &bin, path)?;
+            println!("Web UI written to {}", dir.display());
+            Ok(())
+        }
+    }
+}
+
+fn profiles(config: &Config, forced: Option<String>) -> Profiles {
+    Profiles {
+        profiles: config.profiles.clone(),
+        ports: config.iptables.ports.clone(),
+        opt: config.nfqws.opt.clone(),
+        forced,
+    }
+}
+
+fn build_nfqws(config: &ConfigNfqws, mark_supported: bool, runner: CommandRunner) -> CmdNfqws {
+    Nfqws::new(
+        &config.nfqws_path,
+        &config.pgrep_path,
+        &config.pkill_path,
         mark_supported,
-        filter_mode,
+        config.filter_mode,
+        runner,
+    )
+    .with_hostlists(config.hostlists.clone())
+}
+
+/// Bindings recording their commands into `log`. iptables commands get the
+/// xtables lock flags when `lock_runner` is given to probe them with
+fn recording_bindings(
+    config: &Config,
+    log: &CommandLog,
+    lock_runner: Option<CommandRunner>,
+) -> Result<(DualStack<IptablesRecorderFactory>, RecordingNfqws)> {
+    let xtables_lock = config.iptables.xtables_lock;
+    let firewall = dual_stack(
+        &config.iptables,
+        config.mark_supported,
+        &apps::scope(&config.apps)?,
+        config.iptables.fail_mode,
+        None,
+        || {
+            let factory = IptablesRecorderFactory::new(log.clone());
+            match lock_runner {
+                Some(runner) => factory.with_xtables_lock(xtables_lock, runner),
+                None => factory,
+            }
+        },
     );
-    match cli.command {
-        Commands::Start => {
+    let runner = CommandRunner::new(Duration::from_secs(config.command_timeout_secs));
+    let recorder = NfqwsRecorderFactory::new(log.clone());
+    let nfqws = build_nfqws(&config.nfqws, config.mark_supported, runner)
+        .with_process_control(recorder.pgrep(), recorder.pkill())
+        .with_factory(recorder);
+    Ok((firewall, nfqws))
+}
+
+fn network_monitor(enabled: bool) -> Option<NetlinkMonitor> {
+    if !enabled {
+        return None;
+    }
+    NetlinkMonitor::new()
+        .inspect_err(|error| {
+            warn!(
+                error = format!("{:#}", error),
+                "Network events are unavailable, falling back to polling"
+            )
+        })
+        .ok()
+}
+
+/// iptables, plus ip6tables when configured, with the same options
+fn dual_stack<F, M>(
+    config: &ConfigIptables,
+    mark_supported: bool,
+    app_scope: &AppScope,
+    fail_mode: FailMode,
+    lock: Option<&ConfigLock>,
+    factory: M,
+) -> DualStack<F>
+where
+    F: IptablesBindingFactory,
+    M: Fn() -> F,
+{
+    let build = |path: &Utf8Path, family: Family| {
+        let iptables =
+            Iptables::with_factory(factory(), path, mark_supported, config.connbytes_supported)
+                .with_family(family)
+                .with_interfaces(config.interfaces.clone())
+                .with_apps(app_scope.clone())
+                .with_excluded_networks(config.excluded_networks.clone())
+                .with_tethering(config.tethering.clone())
+                .with_block_quic(config.block_quic.clone())
+                .with_dns_redirect(config.dns_redirect.clone())
+                .with_fail_mode(fail_mode);
+        // Recorded commands change nothing, so they must not be remembered
+        match lock {
+            Some(lock) => iptables.with_state_file(lock.rules_path(family)),
+            None => iptables,
+        }
+    };
+    DualStack::new(
+        build(&config.iptables_path, Family::V4),
+        config
+            .ip6tables_path
+            .as_deref()
+            .map(|path| build(path, Family::V6)),
+    )
+}
+
+/// Run `action` on the given bindings. Returns the status of
+/// `status --format json` for the caller to print
+fn run_command<FP, BS>(
+    action: Action,
+    iptables: &FP,
+    nfqws: &BS,
+    settings: &Settings,
+    selection: &Selection,
+    config: &Config,
+) -> Result<Option<status::Status>>
+where
+    FP: FirewallProvider,
+    BS: BypassSoftware,
+{
+    let (ports, opt) = (selection.ports.clone(), selection.opt.clone());
+    match action {
+        Action::Start => {
             println!("Starting daemon");
             iptables.setup_rules(ports)?;
             nfqws.run(opt)?;
         }
-        Commands::Stop => {
+        Action::Stop => {
             println!("Stoping daemon");
             iptables.clean_rules()?;
             nfqws.kill()?;
         }
-        Commands::Restart => {
+        Action::Restart => {
             println!("Restarting daemon");
             iptables.clean_rules()?;
             nfqws.kill()?;
             iptables.setup_rules(ports)?;
             nfqws.run(opt)?;
         }
-        Commands::Status => {
+        Action::Status(StatusFormat::Text) => {
             if nfqws.is_running()? {
                 println!("Daemon is running");
             } else {
                 println!("Daemon is not running");
             }
         }
-        Commands::Autostart => {
+        Action::Status(StatusFormat::Json) => {
+            return status::collect(iptables, nfqws, settings, selection, false).map(Some);
+        }
+        Action::Autostart => {
             println!("Зачем выпускать HL3 сегодня, когда есть завтра?");
-            if autostart_enabled {
-                iptables.setup_rules(ports)?;
-                nfqws.run(opt)?;
+            if config.autostart_enabled {
+                autostart::retry(
+                    &config.autostart,
+                    || {
+                        iptables.setup_rules(ports.clone())?;
+                        nfqws.run(opt.clone())
+                    },
+                    || {
+                        if let Err(error) = iptables.clean_rules() {
+                            warn!(error = format!("{:#}", error), "Failed to clean up rules");
+                        }
+                        if nfqws.is_running().unwrap_or(false) && nfqws.kill().is_err() {
+                            warn!("Failed to kill nfqws");
+                        }
+                    },
+                )?;
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn run_apps_command(command: &AppsCommand, config: Config, path: &Path) -> Result<()> {
+    // Held until the edited config is stored
+    let (_lock, mut config) = match command {
+        AppsCommand::List => (None, config),
+        _ => {
+            let lock = lock::acquire(&config.lock)?;
+            // Reload, another command may have stored the config while we waited
+            (Some(lock), config::load(path)?)
+        }
+    };
+    let apps = &mut config.apps;
+    match command {
+        AppsCommand::Add { packages } => {
+            for package in packages {
+                if !apps.packages.contains(package) {
+                    apps.packages.push(package.clone());
+                }
             }
         }
+        AppsCommand::Remove { packages } => {
+            apps.packages.retain(|package| !packages.contains(package));
+        }
+        AppsCommand::List => {
+            println!("Mode: {:?}", apps.mode);
+            let packages = apps::Packages::load(&apps.packages_list_path)?;
+            let users = apps::load_users(&apps.users_path);
+            for package in &apps.packages {
+                let uids = apps::resolve(&packages, &users, package);
+                if uids.is_empty() {
+                    println!("{} (not installed)", package);
+                } else {
+                    let uids: Vec<String> = uids.iter().map(u32::to_string).collect();
+                    println!("{} {}", package, uids.join(","));
+                }
+            }
+            return Ok(());
+        }
+    }
+    if config::is_uci(path) {
+        bail!(
+            "{} is a UCI config, edit apps.packages with `uci`",
+            path.display()
+        );
     }
+    confy::store_path(path, &config)?;
+    println!("Config updated, restart daemon to apply");
     Ok(())
 }
 
@@ -134,12 +872,21 @@ fn init_logger() {
         .with_writer(std::io::stderr)
         .with_ansi(true)
         .with_target(true)
+        .with_timer(timer.clone())
+        .compact()
+        .with_filter(env_filter);
+    // Served by `logs` regardless of RUST_LOG
+    let recent_layer = fmt::layer()
+        .with_writer(logs::RecentLogs)
+        .with_ansi(false)
+        .with_target(false)
         .with_timer(timer)
-        .compact();
+        .compact()
+        .with_filter(LevelFilter::INFO);
 
     tracing_subscriber::registry()
-        .with(env_filter)
         .with(fmt_layer)
+        .with(recent_layer)
         .init();
 
     tracing::info!("Logger is initialized");
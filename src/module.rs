@@ -0,0 +1,287 @@
+use crate::config::{ANDROID_LOCK_PATH, Config};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use nfqws::Hostlists;
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+const MODULE_ID: &str = "zapret-ux";
+/// Magisk/KernelSU data directory on the device
+pub const ADB_DIR: &str = "/data/adb";
+/// Settings and hostlists in `ADB_DIR`, kept across module upgrades
+const DATA_DIR_NAME: &str = "zapret-ux";
+
+const CUSTOMIZE: &str = include_str!("module/customize.sh");
+const SERVICE: &str = include_str!("module/service.sh");
+const ACTION: &str = include_str!("module/action.sh");
+const UNINSTALL: &str = include_str!("module/uninstall.sh");
+const UPDATE_BINARY: &str = include_str!("module/update-binary");
+
+/// 1980-01-01 00:00, the earliest zip timestamp. Keeps builds reproducible
+const DOS_TIME: u16 = 0;
+const DOS_DATE: u16 = (1 << 5) | 1;
+
+/// CPU architecture as named by the module installer (`$ARCH`)
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Arch {
+    Arm,
+    Arm64,
+    X86,
+    X64,
+}
+
+impl Arch {
+    fn name(self) -> String {
+        self.to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// Parse `ARCH=FILE` of `module build --binary`
+pub fn parse_binary(value: &str) -> Result<(Arch, PathBuf), String> {
+    let (arch, path) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected ARCH=FILE, got '{}'", value))?;
+    Ok((Arch::from_str(arch, true)?, PathBuf::from(path)))
+}
+
+/// File inside the module
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ModuleFile {
+    /// Path relative to the module root
+    pub path: String,
+    pub content: Vec<u8>,
+    pub mode: u32,
+}
+
+impl ModuleFile {
+    pub fn new<C: Into<Vec<u8>>>(path: &str, content: C, mode: u32) -> Self {
+        Self {
+            path: path.to_string(),
+            content: content.into(),
+            mode,
+        }
+    }
+}
+
+/// How zapret-ux binaries are laid out in the module
+pub enum Layout<'a> {
+    /// Flashable zip with a binary per architecture, picked by `customize.sh`
+    Zip(&'a [(Arch, PathBuf)]),
+    /// Module installed on this device
+    Installed(&'a Path),
+}
+
+/// Module files sorted by path
+pub fn files(layout: Layout) -> Result<Vec<ModuleFile>> {
+    let mut files = vec![
+        ModuleFile::new("module.prop", module_prop(), 0o644),
+        ModuleFile::new("service.sh", SERVICE, 0o755),
+        ModuleFile::new("action.sh", ACTION, 0o755),
+        ModuleFile::new("uninstall.sh", UNINSTALL, 0o755),
+    ];
+    #[cfg(feature = "webui")]
+    files.push(ModuleFile::new(
+        "webroot/index.html",
+        crate::webui::static_page(
+            &Path::new(ADB_DIR)
+                .join("modules")
+                .join(MODULE_ID)
+                .join("zapret-ux"),
+            &data_dir(Path::new(ADB_DIR)).join("config.toml"),
+        )?,
+        0o644,
+    ));
+
+    match layout {
+        Layout::Zip(binaries) => {
+            files.push(ModuleFile::new(
+                "META-INF/com/google/android/update-binary",
+                UPDATE_BINARY,
+                0o755,
+            ));
+            files.push(ModuleFile::new(
+                "META-INF/com/google/android/updater-script",
+                "#MAGISK\n",
+                0o644,
+            ));
+            files.push(ModuleFile::new("customize.sh", CUSTOMIZE, 0o755));
+            for (arch, path) in binaries {
+                let content =
+                    fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+                let path = format!("bin/{}/zapret-ux", arch.name());
+                files.push(ModuleFile::new(&path, content, 0o755));
+            }
+            for default in defaults()? {
+                let path = format!("defaults/{}", default.path);
+                files.push(ModuleFile::new(&path, default.content, default.mode));
+            }
+        }
+        Layout::Installed(binary) => {
+            let content =
+                fs::read(binary).with_context(|| format!("Failed to read {}", binary.display()))?;
+            files.push(ModuleFile::new("zapret-ux", content, 0o755));
+        }
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    files.dedup_by(|a, b| a.path == b.path);
+    Ok(files)
+}
+
+/// Default config and hostlists created in the data directory on first install
+pub fn defaults() -> Result<Vec<ModuleFile>> {
+    let config = toml::to_string_pretty(&device_config())?;
+    let mut files = vec![ModuleFile::new("config.toml", config, 0o600)];
+    files.extend(hostlists());
+    Ok(files)
+}
+
+/// Empty hostlists named like the default `nfqws.hostlists`
+pub fn hostlists() -> Vec<ModuleFile> {
+    vec![
+        ModuleFile::new(
+            "hosts.txt",
+            "# Hosts to bypass blocking for, one per line\n",
+            0o600,
+        ),
+        ModuleFile::new(
+            "hosts-exclude.txt",
+            "# Hosts never processed, one per line\n",
+            0o600,
+        ),
+        ModuleFile::new("hosts-auto.txt", "", 0o600),
+    ]
+}
+
+/// Default config with the device paths, whatever the build host is
+fn device_config() -> Config {
+    let data_dir = data_dir(Path::new(ADB_DIR));
+    let path = |name: &str| data_dir.join(name).display().to_string();
+    let mut config = Config::default();
+    config.nfqws.hostlists = Hostlists {
+        hosts: path("hosts.txt"),
+        exclude: path("hosts-exclude.txt"),
+        auto: path("hosts-auto.txt"),
+    };
+    config.daemon.socket_path = path("zapret-ux.sock").into();
+    config.lock.path = ANDROID_LOCK_PATH.into();
+    config.autostart.boot_completed = true;
+    config.autostart.xtables_lock_path = "/system/etc/xtables.lock".into();
+    config
+}
+
+fn data_dir(adb_dir: &Path) -> PathBuf {
+    adb_dir.join(DATA_DIR_NAME)
+}
+
+fn module_prop() -> String {
+    let version_code = env!("CARGO_PKG_VERSION_MAJOR").parse::<u32>().unwrap_or(0) * 10000
+        + env!("CARGO_PKG_VERSION_MINOR").parse::<u32>().unwrap_or(0) * 100
+        + env!("CARGO_PKG_VERSION_PATCH").parse::<u32>().unwrap_or(0);
+    format!(
+        "id={}\nname=zapret-ux\nversion=v{}\nversionCode={}\nauthor=NTeditor\n\
+         description=DPI bypass with nfqws, managed by zapret-ux\n",
+        MODULE_ID,
+        env!("CARGO_PKG_VERSION"),
+        version_code
+    )
+}
+
+/// Install the module from `binary`, active after reboot like a manager install.
+/// Existing settings in the data directory are kept
+pub fn install(adb_dir: &Path, binary: &Path) -> Result<()> {
+    let module_dir = adb_dir.join("modules_update").join(MODULE_ID);
+    if module_dir.exists() {
+        fs::remove_dir_all(&module_dir)
+            .with_context(|| format!("Failed to remove {}", module_dir.display()))?;
+    }
+    write_dir(&module_dir, &files(Layout::Installed(binary))?)?;
+
+    // Marks the installed module as updated in the manager until reboot
+    let installed_dir = adb_dir.join("modules").join(MODULE_ID);
+    fs::create_dir_all(&installed_dir)?;
+    fs::write(installed_dir.join("update"), "")?;
+
+    let data_dir = data_dir(adb_dir);
+    let missing: Vec<ModuleFile> = defaults()?
+        .into_iter()
+        .filter(|default| !data_dir.join(&default.path).exists())
+        .collect();
+    write_dir(&data_dir, &missing)?;
+    fs::set_permissions(&data_dir, fs::Permissions::from_mode(0o700))?;
+    Ok(())
+}
+
+/// Write files under `dir`, creating parent directories
+pub fn write_dir(dir: &Path, files: &[ModuleFile]) -> Result<()> {
+    for file in files {
+        let path = dir.join(&file.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        fs::write(&path, &file.content)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(file.mode))?;
+    }
+    Ok(())
+}
+
+/// Uncompressed zip with fixed timestamps, byte-identical for the same files
+pub fn zip(files: &[ModuleFile]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut central = Vec::new();
+    for file in files {
+        let offset = out.len() as u32;
+        let crc = crc32fast::hash(&file.content);
+        let size = file.content.len() as u32;
+        let name = file.path.as_bytes();
+
+        // Local file header: signature, version needed, flags, method (stored)
+        out.extend(0x04034b50u32.to_le_bytes());
+        out.extend(
+            [20u16, 0, 0, DOS_TIME, DOS_DATE]
+                .map(u16::to_le_bytes)
+                .concat(),
+        );
+        out.extend([crc, size, size].map(u32::to_le_bytes).concat());
+        out.extend([name.len() as u16, 0].map(u16::to_le_bytes).concat());
+        out.extend(name);
+        out.extend(&file.content);
+
+        // Central directory entry, made by unix (3) to keep the modes
+        central.extend(0x02014b50u32.to_le_bytes());
+        central.extend(
+            [(3 << 8) | 20, 20u16, 0, 0, DOS_TIME, DOS_DATE]
+                .map(u16::to_le_bytes)
+                .concat(),
+        );
+        central.extend([crc, size, size].map(u32::to_le_bytes).concat());
+        central.extend(
+            [name.len() as u16, 0, 0, 0, 0]
+                .map(u16::to_le_bytes)
+                .concat(),
+        );
+        central.extend(((0o100000 | file.mode) << 16).to_le_bytes());
+        central.extend(offset.to_le_bytes());
+        central.extend(name);
+    }
+
+    let central_offset = out.len() as u32;
+    let central_size = central.len() as u32;
+    out.extend(central);
+    out.extend(0x06054b50u32.to_le_bytes());
+    let count = files.len() as u16;
+    out.extend([0u16, 0, count, count].map(u16::to_le_bytes).concat());
+    out.extend(
+        [central_size, central_offset]
+            .map(u32::to_le_bytes)
+            .concat(),
+    );
+    out.extend(0u16.to_le_bytes());
+    out
+}
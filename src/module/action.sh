@@ -0,0 +1,13 @@
+#!/system/bin/sh
+# Action button in the Magisk/KernelSU manager: toggles zapret-ux
+
+MODDIR=${0%/*}
+ZAPRET_UX="$MODDIR/zapret-ux -c /data/adb/zapret-ux/config.toml"
+
+if $ZAPRET_UX status --format json 2>/dev/null | grep -q '"running": true'; then
+    echo "- Stopping zapret-ux"
+    $ZAPRET_UX stop 2>&1
+else
+    echo "- Starting zapret-ux"
+    $ZAPRET_UX start 2>&1
+fi
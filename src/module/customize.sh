@@ -0,0 +1,26 @@
+#!/system/bin/sh
+# Runs in the Magisk/KernelSU installer after the module is extracted to $MODPATH
+
+DATA_DIR=/data/adb/zapret-ux
+
+if [ ! -f "$MODPATH/bin/$ARCH/zapret-ux" ]; then
+    abort "! zapret-ux is not built for $ARCH"
+fi
+ui_print "- Installing zapret-ux for $ARCH"
+mv "$MODPATH/bin/$ARCH/zapret-ux" "$MODPATH/zapret-ux"
+rm -rf "$MODPATH/bin"
+
+# Keep user settings on upgrade
+mkdir -p "$DATA_DIR"
+for file in "$MODPATH"/defaults/*; do
+    name=$(basename "$file")
+    if [ ! -f "$DATA_DIR/$name" ]; then
+        ui_print "- Creating $DATA_DIR/$name"
+        cp "$file" "$DATA_DIR/$name"
+    fi
+done
+rm -rf "$MODPATH/defaults"
+
+set_perm_recursive "$MODPATH" 0 0 0755 0644
+set_perm "$MODPATH/zapret-ux" 0 0 0755
+set_perm_recursive "$DATA_DIR" 0 0 0700 0600
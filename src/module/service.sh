@@ -0,0 +1,11 @@
+#!/system/bin/sh
+# Starts zapret-ux after boot when autostart_enabled is set in the config
+
+MODDIR=${0%/*}
+DATA_DIR=/data/adb/zapret-ux
+
+until [ "$(getprop sys.boot_completed)" = "1" ]; do
+    sleep 1
+done
+
+"$MODDIR/zapret-ux" -c "$DATA_DIR/config.toml" autostart >"$DATA_DIR/autostart.log" 2>&1
@@ -0,0 +1,8 @@
+#!/system/bin/sh
+# Removes the rules and the settings when the module is uninstalled
+
+MODDIR=${0%/*}
+DATA_DIR=/data/adb/zapret-ux
+
+"$MODDIR/zapret-ux" -c "$DATA_DIR/config.toml" stop >/dev/null 2>&1
+rm -rf "$DATA_DIR"
@@ -0,0 +1,252 @@
+use anyhow::{Context, Result, bail};
+use rustix::{
+    fd::OwnedFd,
+    io::Errno,
+    net::{
+        AddressFamily, RecvFlags, SendFlags, SocketType, bind,
+        netdevice::index_to_name,
+        netlink::SocketAddrNetlink,
+        recv, sendto, socket,
+        sockopt::{Timeout, set_socket_timeout},
+    },
+};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{Duration, Instant},
+};
+use tracing::debug;
+
+/// rtnetlink multicast groups: links, IPv4/IPv6 addresses and routes
+const RTMGRP_LINK: u32 = 0x1;
+const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+const RTMGRP_IPV4_ROUTE: u32 = 0x40;
+const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+const RTMGRP_IPV6_ROUTE: u32 = 0x400;
+
+/// Length of `struct nlmsghdr`
+const NLMSG_HDRLEN: usize = 16;
+
+/// Subscription to link, address and route changes
+#[derive(Debug)]
+pub struct NetlinkMonitor {
+    fd: OwnedFd,
+}
+
+impl NetlinkMonitor {
+    pub fn new() -> Result<Self> {
+        let fd = socket(AddressFamily::NETLINK, SocketType::RAW, None)
+            .context("Failed to open rtnetlink socket")?;
+        let groups = RTMGRP_LINK
+            | RTMGRP_IPV4_IFADDR
+            | RTMGRP_IPV4_ROUTE
+            | RTMGRP_IPV6_IFADDR
+            | RTMGRP_IPV6_ROUTE;
+        bind(&fd, &SocketAddrNetlink::new(0, groups))
+            .context("Failed to subscribe to rtnetlink groups")?;
+        Ok(Self { fd })
+    }
+
+    /// Wait up to `timeout` for network changes. Returns the number of received
+    /// events, 0 on timeout
+    pub fn wait(&self, timeout: Duration) -> Result<usize> {
+        // Zero timeout means "block forever" for SO_RCVTIMEO
+        set_socket_timeout(
+            &self.fd,
+            Timeout::Recv,
+            Some(timeout.max(Duration::from_millis(1))),
+        )?;
+        let mut buf = [0u8; 8192];
+        match recv(&self.fd, &mut buf, RecvFlags::empty()) {
+            Ok((len, _)) => Ok(count_messages(&buf[..len])),
+            Err(Errno::AGAIN | Errno::INTR) => Ok(0),
+            // Kernel dropped events because we read too slowly, still a change
+            Err(Errno::NOBUFS) => Ok(1),
+            Err(error) => Err(error).context("Failed to read rtnetlink events"),
+        }
+    }
+
+    /// Wait for events to stop arriving for `quiet`, e.g. while an interface
+    /// gets its addresses and routes. Returns the number of drained events
+    pub fn settle(&self, quiet: Duration) -> Result<usize> {
+        let started = Instant::now();
+        let mut events = 0;
+        loop {
+            match self.wait(quiet)? {
+                0 => break,
+                count => events += count,
+            }
+        }
+        debug!(events, elapsed = ?started.elapsed(), "Network settled");
+        Ok(events)
+    }
+}
+
+/// Number of netlink messages in a datagram
+fn count_messages(mut buf: &[u8]) -> usize {
+    let mut count = 0;
+    while buf.len() >= NLMSG_HDRLEN {
+        let len = u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            break;
+        }
+        count += 1;
+        // Messages are 4-byte aligned, the last one may come without padding
+        buf = buf
+            .get(((len + 3) & !3).min(buf.len())..)
+            .unwrap_or_default();
+    }
+    count
+}
+
+/// Addresses routes are looked up for, any public address would do
+const PROBE_V4: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+const PROBE_V6: Ipv6Addr = Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111);
+
+const RTM_NEWROUTE: u16 = 24;
+const RTM_GETROUTE: u16 = 26;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x1;
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+/// Length of `struct rtmsg`
+const RTMSG_LEN: usize = 12;
+
+/// Network the internet traffic currently leaves through
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+pub struct Uplink {
+    pub interface: Option<String>,
+    /// IPv4 default gateway
+    pub gateway: Option<IpAddr>,
+    /// Whether there is an IPv6 route to the internet
+    pub ipv6: bool,
+}
+
+/// Route of a public address, like `ip route get`. Honours policy routing,
+/// so it works with the per-network routing tables of Android
+#[derive(Clone, PartialEq, Eq, Default, Debug)]
+struct Route {
+    interface: Option<String>,
+    gateway: Option<IpAddr>,
+}
+
+/// Detect the current uplink through rtnetlink route lookups
+pub fn uplink() -> Result<Uplink> {
+    let v4 = route_get(IpAddr::V4(PROBE_V4))?;
+    let v6 = route_get(IpAddr::V6(PROBE_V6))?;
+    let interface = v4
+        .as_ref()
+        .or(v6.as_ref())
+        .and_then(|route| route.interface.clone());
+    Ok(Uplink {
+        interface,
+        gateway: v4.and_then(|route| route.gateway),
+        ipv6: v6.is_some(),
+    })
+}
+
+/// Route to `destination`. `None` when it is unreachable
+fn route_get(destination: IpAddr) -> Result<Option<Route>> {
+    let (family, address) = match destination {
+        IpAddr::V4(address) => (AddressFamily::INET, address.octets().to_vec()),
+        IpAddr::V6(address) => (AddressFamily::INET6, address.octets().to_vec()),
+    };
+
+    let attr_len = 4 + address.len();
+    let len = NLMSG_HDRLEN + RTMSG_LEN + attr_len;
+    let mut request = Vec::with_capacity(len);
+    request.extend((len as u32).to_ne_bytes());
+    request.extend(RTM_GETROUTE.to_ne_bytes());
+    request.extend(NLM_F_REQUEST.to_ne_bytes());
+    request.extend(1u32.to_ne_bytes()); // sequence
+    request.extend(0u32.to_ne_bytes()); // port id, filled by the kernel
+    request.push(family.as_raw() as u8);
+    request.push((address.len() * 8) as u8); // destination prefix length
+    request.extend([0u8; RTMSG_LEN - 2]);
+    request.extend((attr_len as u16).to_ne_bytes());
+    request.extend(RTA_DST.to_ne_bytes());
+    request.extend(&address);
+
+    let fd = socket(AddressFamily::NETLINK, SocketType::RAW, None)
+        .context("Failed to open rtnetlink socket")?;
+    set_socket_timeout(&fd, Timeout::Recv, Some(Duration::from_secs(1)))?;
+    sendto(
+        &fd,
+        &request,
+        SendFlags::empty(),
+        &SocketAddrNetlink::new(0, 0),
+    )
+    .context("Failed to send rtnetlink route request")?;
+    let mut buf = [0u8; 4096];
+    let (len, _) =
+        recv(&fd, &mut buf, RecvFlags::empty()).context("Failed to read rtnetlink route")?;
+    let reply = &buf[..len];
+    if reply.len() < NLMSG_HDRLEN {
+        bail!("Truncated rtnetlink reply");
+    }
+
+    match u16::from_ne_bytes([reply[4], reply[5]]) {
+        RTM_NEWROUTE => {}
+        // Error code follows the header, e.g. -ENETUNREACH without a route
+        NLMSG_ERROR => return Ok(None),
+        message_type => bail!("Unexpected rtnetlink reply type {}", message_type),
+    }
+
+    let mut route = Route::default();
+    let mut attrs = reply.get(NLMSG_HDRLEN + RTMSG_LEN..).unwrap_or_default();
+    while attrs.len() >= 4 {
+        let attr_len = u16::from_ne_bytes([attrs[0], attrs[1]]) as usize;
+        let attr_type = u16::from_ne_bytes([attrs[2], attrs[3]]);
+        if attr_len < 4 || attr_len > attrs.len() {
+            break;
+        }
+        let payload = &attrs[4..attr_len];
+        match (attr_type, payload.len()) {
+            (RTA_OIF, 4) => {
+                let index = u32::from_ne_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                route.interface = interface_name(index);
+            }
+            (RTA_GATEWAY, 4) => {
+                route.gateway = Some(IpAddr::from(<[u8; 4]>::try_from(payload)?));
+            }
+            (RTA_GATEWAY, 16) => {
+                route.gateway = Some(IpAddr::from(<[u8; 16]>::try_from(payload)?));
+            }
+            _ => {}
+        }
+        attrs = attrs.get((attr_len + 3) & !3..).unwrap_or_default();
+    }
+    debug!(%destination, ?route, "Route lookup");
+    Ok(Some(route))
+}
+
+fn interface_name(index: u32) -> Option<String> {
+    let fd = socket(AddressFamily::INET, SocketType::DGRAM, None).ok()?;
+    index_to_name(&fd, index).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(len: u32) -> Vec<u8> {
+        let mut message = len.to_ne_bytes().to_vec();
+        message.resize(len as usize, 0);
+        message
+    }
+
+    #[test]
+    fn unaligned_last_message_is_counted() {
+        let mut buf = message(20);
+        buf.extend(message(17));
+        assert_eq!(count_messages(&buf), 2);
+        assert_eq!(count_messages(&message(17)), 1);
+    }
+
+    #[test]
+    fn truncated_message_is_ignored() {
+        let mut buf = message(16);
+        buf.extend(&message(32)[..20]);
+        assert_eq!(count_messages(&buf), 1);
+    }
+}
@@ -0,0 +1,56 @@
+use crate::{
+    config::Config,
+    module::{self, ModuleFile},
+    uci,
+};
+use anyhow::Result;
+use nfqws::Hostlists;
+use runner::shell_line;
+use std::path::Path;
+
+const INIT: &str = include_str!("openwrt/zapret-ux.init");
+const BIN_PLACEHOLDER: &str = "/*BIN*/";
+
+/// UCI package, the config file name in `/etc/config`
+const PACKAGE: &str = "zapret-ux";
+/// Hostlists directory, kept across sysupgrade with the rest of `/etc`
+const DATA_DIR: &str = "/etc/zapret-ux";
+
+/// procd init script running `bin` as the daemon
+pub fn init_script(bin: &Path) -> String {
+    INIT.replace(BIN_PLACEHOLDER, &shell_line(&[bin.display().to_string()]))
+}
+
+/// Default config with the OpenWrt paths
+fn default_config() -> Config {
+    let path = |name: &str| Path::new(DATA_DIR).join(name).display().to_string();
+    let mut config = Config::default();
+    config.nfqws.hostlists = Hostlists {
+        hosts: path("hosts.txt"),
+        exclude: path("hosts-exclude.txt"),
+        auto: path("hosts-auto.txt"),
+    };
+    config.daemon.socket_path = "/var/run/zapret-ux.sock".into();
+    config.lock.path = "/var/run/zapret-ux.lock".into();
+    config
+}
+
+/// Install the init script for `bin` under `root`, creating the UCI config
+/// and the hostlists when missing
+pub fn install(root: &Path, bin: &Path) -> Result<()> {
+    let init = ModuleFile::new(PACKAGE, init_script(bin), 0o755);
+    module::write_dir(&root.join("etc/init.d"), &[init])?;
+
+    let config_dir = root.join("etc/config");
+    if !config_dir.join(PACKAGE).exists() {
+        let config = ModuleFile::new(PACKAGE, uci::render(&default_config())?, 0o600);
+        module::write_dir(&config_dir, &[config])?;
+    }
+
+    let data_dir = root.join(DATA_DIR.trim_start_matches('/'));
+    let missing: Vec<ModuleFile> = module::hostlists()
+        .into_iter()
+        .filter(|hostlist| !data_dir.join(&hostlist.path).exists())
+        .collect();
+    module::write_dir(&data_dir, &missing)
+}
@@ -0,0 +1,113 @@
+use crate::{
+    config::{ConfigProfile, ProfileCondition},
+    netlink::{self, Uplink},
+};
+use iptables::PortSpec;
+use tracing::{info, warn};
+
+/// Ports and nfqws options in effect
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Selection {
+    /// Matched profile, `None` for the global settings
+    pub profile: Option<String>,
+    pub ports: Vec<PortSpec>,
+    pub opt: Vec<String>,
+}
+
+/// Picks the strategy for the current uplink
+#[derive(Debug)]
+pub struct Profiles {
+    pub profiles: Vec<ConfigProfile>,
+    pub ports: Vec<PortSpec>,
+    pub opt: Vec<String>,
+    /// Profile chosen by the user instead of uplink detection
+    pub forced: Option<String>,
+}
+
+impl Profiles {
+    /// Selection for the current uplink. Uses the global settings when no
+    /// profiles are configured or the uplink can't be detected
+    pub fn detect(&self) -> Selection {
+        if let Some(name) = &self.forced {
+            match self.find(name) {
+                Some(profile) => return self.selection(Some(profile)),
+                None => warn!(
+                    profile = name,
+                    "Forced profile does not exist, detecting uplink"
+                ),
+            }
+        }
+        if self.profiles.is_empty() {
+            return self.select(None);
+        }
+        match netlink::uplink() {
+            Ok(uplink) => {
+                info!(
+                    interface = uplink.interface,
+                    gateway = ?uplink.gateway,
+                    ipv6 = uplink.ipv6,
+                    "Detected uplink"
+                );
+                self.select(Some(&uplink))
+            }
+            Err(error) => {
+                warn!(
+                    error = format!("{:#}", error),
+                    "Failed to detect uplink, using global settings"
+                );
+                self.select(None)
+            }
+        }
+    }
+
+    /// First profile matching `uplink`, the global settings otherwise
+    pub fn select(&self, uplink: Option<&Uplink>) -> Selection {
+        let profile = uplink.and_then(|uplink| {
+            self.profiles
+                .iter()
+                .find(|profile| profile.when.matches(uplink))
+        });
+        self.selection(profile)
+    }
+
+    pub fn find(&self, name: &str) -> Option<&ConfigProfile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    fn selection(&self, profile: Option<&ConfigProfile>) -> Selection {
+        let selection = Selection {
+            profile: profile.map(|profile| profile.name.clone()),
+            ports: profile
+                .and_then(|profile| profile.ports.clone())
+                .unwrap_or_else(|| self.ports.clone()),
+            opt: profile
+                .and_then(|profile| profile.opt.clone())
+                .unwrap_or_else(|| self.opt.clone()),
+        };
+        info!(profile = selection.profile, "Selected profile");
+        selection
+    }
+}
+
+impl ProfileCondition {
+    pub fn matches(&self, uplink: &Uplink) -> bool {
+        let interface = match (&self.interface, &uplink.interface) {
+            (None, _) => true,
+            (Some(pattern), Some(name)) => interface_matches(pattern, name),
+            (Some(_), None) => false,
+        };
+        interface
+            && self
+                .gateway
+                .is_none_or(|gateway| uplink.gateway == Some(gateway))
+            && self.ipv6.is_none_or(|ipv6| uplink.ipv6 == ipv6)
+    }
+}
+
+/// iptables-style interface pattern: `wlan+` matches `wlan0`
+fn interface_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('+') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
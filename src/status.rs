@@ -0,0 +1,201 @@
+use crate::{config::Config, profiles::Selection};
+use anyhow::Result;
+use clap::ValueEnum;
+use iptables::FirewallProvider;
+use nfqws::{BypassSoftware, FilterMode};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum StatusFormat {
+    /// Human-readable summary
+    #[default]
+    Text,
+    /// Structured document for scripts and the WebUI
+    Json,
+}
+
+impl FromStr for StatusFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        <Self as ValueEnum>::from_str(s, false).map_err(|error| anyhow::anyhow!(error))
+    }
+}
+
+/// `status --format json` document
+#[derive(Serialize, Debug)]
+pub struct Status {
+    /// Whether nfqws is running
+    pub running: bool,
+    /// Whether the status was collected by a running daemon
+    pub daemon: bool,
+    /// Strategy profile in effect, `null` for the global settings
+    pub profile: Option<String>,
+    pub nfqws: Vec<Process>,
+    #[serde(flatten)]
+    pub settings: Settings,
+    pub rules: Vec<RuleState>,
+    /// Problems found while collecting the status, e.g. missing rules
+    pub inconsistencies: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Process {
+    pub pid: u32,
+    /// `null` when the process can't be inspected
+    pub uptime_secs: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RuleState {
+    pub iptables: String,
+    pub table: String,
+    pub chain: String,
+    /// Rule in iptables-save form
+    pub rule: String,
+    pub installed: bool,
+}
+
+/// Part of the status known from the config
+#[derive(Serialize, Clone, Debug)]
+pub struct Settings {
+    pub filter_mode: FilterMode,
+    pub capabilities: Capabilities,
+    pub config: ConfigFile,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Capabilities {
+    pub mark: bool,
+    pub connbytes: bool,
+    pub ipv6: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    /// SHA-256 of the file the settings were loaded from
+    pub sha256: Option<String>,
+}
+
+impl Settings {
+    pub fn new(path: &Path, config: &Config) -> Self {
+        Self {
+            filter_mode: config.nfqws.filter_mode,
+            capabilities: Capabilities {
+                mark: config.mark_supported,
+                connbytes: config.iptables.connbytes_supported,
+                ipv6: config.iptables.ip6tables_path.is_some(),
+            },
+            config: ConfigFile {
+                path: path.to_path_buf(),
+                sha256: sha256(path),
+            },
+        }
+    }
+}
+
+/// Inspect nfqws and the installed rules. `daemon` additionally reports
+/// config changes that were not reloaded
+pub fn collect<FP, BS>(
+    iptables: &FP,
+    nfqws: &BS,
+    settings: &Settings,
+    selection: &Selection,
+    daemon: bool,
+) -> Result<Status>
+where
+    FP: FirewallProvider,
+    BS: BypassSoftware,
+{
+    let nfqws: Vec<Process> = nfqws
+        .pids()?
+        .into_iter()
+        .map(|pid| Process {
+            pid,
+            uptime_secs: uptime_secs(pid),
+        })
+        .collect();
+    let rules: Vec<RuleState> = iptables
+        .rule_status(selection.ports.clone())?
+        .into_iter()
+        .map(|status| RuleState {
+            rule: status.rule.to_string(),
+            table: status.rule.table,
+            chain: status.rule.chain,
+            iptables: status.iptables,
+            installed: status.installed,
+        })
+        .collect();
+
+    let running = !nfqws.is_empty();
+    let installed = rules.iter().filter(|rule| rule.installed).count();
+    let mut inconsistencies = Vec::new();
+    if nfqws.len() > 1 {
+        inconsistencies.push(format!("{} nfqws processes are running", nfqws.len()));
+    }
+    if running && installed < rules.len() {
+        inconsistencies.push(format!(
+            "{} of {} rules are missing while nfqws is running",
+            rules.len() - installed,
+            rules.len()
+        ));
+    }
+    if !running && installed > 0 {
+        inconsistencies.push(format!(
+            "{} rules are installed while nfqws is not running",
+            installed
+        ));
+    }
+    if daemon && sha256(&settings.config.path) != settings.config.sha256 {
+        inconsistencies.push("Config changed since it was loaded, reload to apply".to_string());
+    }
+
+    Ok(Status {
+        running,
+        daemon,
+        profile: selection.profile.clone(),
+        nfqws,
+        settings: settings.clone(),
+        rules,
+        inconsistencies,
+    })
+}
+
+fn sha256(path: &Path) -> Option<String> {
+    let content = fs::read(path).ok()?;
+    let mut hex = String::new();
+    for byte in Sha256::digest(content) {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    Some(hex)
+}
+
+/// Time since the process started, from `/proc/<pid>/stat` and `/proc/uptime`
+fn uptime_secs(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // Fields after the parenthesized command name start at field 3 (state),
+    // start time is field 22
+    let start_ticks: u64 = stat
+        .rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .nth(19)?
+        .parse()
+        .ok()?;
+    let system_uptime: f64 = fs::read_to_string("/proc/uptime")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+    let started = start_ticks / rustix::param::clock_ticks_per_second();
+    Some((system_uptime as u64).saturating_sub(started))
+}
@@ -0,0 +1,190 @@
+use crate::{
+    config::ConfigLock,
+    lock,
+    netlink::NetlinkMonitor,
+    profiles::{Profiles, Selection},
+};
+use anyhow::Result;
+use iptables::FirewallProvider;
+use nfqws::BypassSoftware;
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::{error, info, warn};
+
+/// Keeps nfqws running and the rules in place. In fail-closed mode falls
+/// back to bypass rules when nfqws can't be restarted within the grace period
+pub struct Supervisor<'a, FP, BS>
+where
+    FP: FirewallProvider,
+    BS: BypassSoftware,
+{
+    pub iptables: &'a FP,
+    /// Fail-open rules installed while nfqws is down. `None` in fail-open mode
+    pub fallback: Option<&'a FP>,
+    pub nfqws: &'a BS,
+    pub profiles: Profiles,
+    /// Ports and nfqws options in effect, re-selected when the network changes
+    pub selection: Selection,
+    pub check_interval: Duration,
+    pub grace_period: Duration,
+    /// Re-apply rules on link, address and route changes. Polls only when `None`
+    pub network_events: Option<NetlinkMonitor>,
+    /// Quiet time after the last network event before rules are re-applied
+    pub debounce: Duration,
+    /// Held while setting up and during every round, not while sleeping
+    pub lock: &'a ConfigLock,
+}
+
+/// Supervisor state between checks
+#[derive(Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub struct Health {
+    /// When nfqws was first seen down
+    down_since: Option<Instant>,
+    /// Whether the fallback rules are installed
+    degraded: bool,
+}
+
+impl<FP, BS> Supervisor<'_, FP, BS>
+where
+    FP: FirewallProvider,
+    BS: BypassSoftware,
+{
+    /// Install rules, start nfqws and check it forever
+    pub fn run(&mut self) -> Result<()> {
+        info!("Starting supervisor");
+        {
+            let _lock = lock::acquire(self.lock)?;
+            self.iptables.setup_rules(self.selection.ports.clone())?;
+            if let Err(error) = self.nfqws.run(self.selection.opt.clone()) {
+                error!(error = format!("{:#}", error), "Failed to start nfqws");
+            }
+        }
+
+        let mut health = Health::default();
+        loop {
+            health = {
+                let _lock = lock::acquire(self.lock)?;
+                self.check(health)?
+            };
+            if self.wait_for_network_change()? {
+                let _lock = lock::acquire(self.lock)?;
+                let selection = self.profiles.detect();
+                if selection != self.selection {
+                    self.switch_profile(health, selection)?;
+                } else {
+                    info!("Network changed, re-applying rules");
+                    self.reapply(self.installed(health))?;
+                }
+            }
+        }
+    }
+
+    /// Sleep until the next check, returning early when the network changed
+    fn wait_for_network_change(&self) -> Result<bool> {
+        let Some(monitor) = &self.network_events else {
+            thread::sleep(self.check_interval);
+            return Ok(false);
+        };
+        let events = monitor.wait(self.check_interval)?;
+        if events == 0 {
+            return Ok(false);
+        }
+        let events = events + monitor.settle(self.debounce)?;
+        info!(events, "Received network events");
+        Ok(true)
+    }
+
+    /// Rules currently installed: the fallback ones while degraded
+    fn installed(&self, health: Health) -> &FP {
+        match self.fallback {
+            Some(fallback) if health.degraded => fallback,
+            _ => self.iptables,
+        }
+    }
+
+    /// Add the missing rules, leaving the ones in place untouched
+    fn reapply(&self, installed: &FP) -> Result<()> {
+        installed.update_rules(self.selection.ports.clone(), self.selection.ports.clone())?;
+        info!("Rules re-applied");
+        Ok(())
+    }
+
+    /// Replace rules and restart nfqws with the strategy of the new uplink
+    fn switch_profile(&mut self, health: Health, selection: Selection) -> Result<()> {
+        info!(
+            from = self.selection.profile,
+            to = selection.profile,
+            "Uplink changed, switching profile"
+        );
+        self.installed(health)
+            .update_rules(self.selection.ports.clone(), selection.ports.clone())?;
+        self.selection = selection;
+        if !health.degraded {
+            self.nfqws.kill()?;
+            if let Err(error) = self.nfqws.run(self.selection.opt.clone()) {
+                error!(error = format!("{:#}", error), "Failed to start nfqws");
+            }
+        }
+        Ok(())
+    }
+
+    /// One supervision round: nfqws first, then the installed rules
+    pub fn check(&self, health: Health) -> Result<Health> {
+        let health = self.check_nfqws(health)?;
+        self.check_rules(health)?;
+        Ok(health)
+    }
+
+    /// Re-apply rules removed by someone else (netd, firewall apps)
+    fn check_rules(&self, health: Health) -> Result<()> {
+        let installed = self.installed(health);
+        if installed.verify_rules(self.selection.ports.clone())? {
+            return Ok(());
+        }
+        warn!("Rules were changed externally, re-applying");
+        self.reapply(installed)
+    }
+
+    /// Restart nfqws when it is down and swap rules as needed
+    fn check_nfqws(&self, mut health: Health) -> Result<Health> {
+        if self.nfqws.is_running()? {
+            health.down_since = None;
+            if health.degraded {
+                info!("nfqws is back, restoring fail-closed rules");
+                if let Some(fallback) = self.fallback {
+                    fallback.clean_rules()?;
+                }
+                self.iptables.setup_rules(self.selection.ports.clone())?;
+                health.degraded = false;
+            }
+            return Ok(health);
+        }
+
+        let down_since = *health.down_since.get_or_insert_with(Instant::now);
+        warn!("nfqws is not running, restarting");
+        match self.nfqws.run(self.selection.opt.clone()) {
+            Ok(()) if self.nfqws.is_running()? => {
+                info!("nfqws restarted");
+                return self.check_nfqws(health);
+            }
+            Ok(()) => warn!("nfqws exited right after start"),
+            Err(error) => error!(error = format!("{:#}", error), "Failed to restart nfqws"),
+        }
+
+        if let Some(fallback) = self.fallback
+            && !health.degraded
+            && down_since.elapsed() >= self.grace_period
+        {
+            warn!(
+                grace_period = ?self.grace_period,
+                "nfqws is still down, installing fail-open rules"
+            );
+            self.iptables.clean_rules()?;
+            fallback.setup_rules(self.selection.ports.clone())?;
+            health.degraded = true;
+        }
+        Ok(health)
+    }
+}
@@ -0,0 +1,191 @@
+use anyhow::{Context, Result};
+use rustix::{
+    process,
+    time::{ClockId, clock_gettime},
+};
+use std::{
+    env,
+    ffi::OsStr,
+    fs, io,
+    os::unix::{
+        ffi::OsStrExt,
+        net::{SocketAddr, UnixDatagram},
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::{debug, warn};
+
+#[cfg(target_os = "android")]
+use std::os::android::net::SocketAddrExt;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+
+pub const UNIT_NAME: &str = "zapret-ux.service";
+/// Directory for units installed by the administrator
+pub const UNIT_DIR: &str = "/etc/systemd/system";
+
+/// Service manager notifications over `$NOTIFY_SOCKET` (the sd_notify protocol).
+/// Does nothing unless started by systemd with `Type=notify`
+#[derive(Default)]
+pub struct Notifier {
+    address: Option<SocketAddr>,
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        let address = env::var_os("NOTIFY_SOCKET").and_then(|path| match address(&path) {
+            Ok(address) => Some(address),
+            Err(error) => {
+                warn!(error = %error, "Invalid NOTIFY_SOCKET, systemd won't be notified");
+                None
+            }
+        });
+        Self {
+            watchdog: address
+                .as_ref()
+                .and(watchdog_timeout())
+                .map(|timeout| timeout / 2),
+            address,
+        }
+    }
+
+    /// How often to send `WATCHDOG=1`, half of `WatchdogSec=`
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    /// Startup is finished, `status` is shown by `systemctl status`
+    pub fn ready(&self, status: &str) {
+        self.notify(&format!("READY=1\nSTATUS={}", status));
+    }
+
+    pub fn status(&self, status: &str) {
+        self.notify(&format!("STATUS={}", status));
+    }
+
+    /// Config is being reloaded, followed by `ready` once done
+    pub fn reloading(&self) {
+        let now = clock_gettime(ClockId::Monotonic);
+        let usec = now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000;
+        self.notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", usec));
+    }
+
+    /// Service is shutting down
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    /// Service is healthy, resets the `WatchdogSec=` timer
+    pub fn watchdog(&self) {
+        self.notify("WATCHDOG=1");
+    }
+
+    fn notify(&self, state: &str) {
+        let Some(address) = &self.address else {
+            return;
+        };
+        debug!(state, "Notifying systemd");
+        if let Err(error) = UnixDatagram::unbound()
+            .and_then(|socket| socket.send_to_addr(state.as_bytes(), address))
+        {
+            warn!(error = %error, "Failed to notify systemd");
+        }
+    }
+}
+
+/// `@` starts an abstract socket name
+fn address(path: &OsStr) -> io::Result<SocketAddr> {
+    match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(path),
+    }
+}
+
+/// `WATCHDOG_USEC` meant for this process
+fn watchdog_timeout() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID")
+        && pid.parse::<i32>().ok() != Some(process::getpid().as_raw_nonzero().get())
+    {
+        return None;
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Unit running the daemon with `config`. Rules and nfqws are cleaned up by
+/// `ExecStopPost=` however the daemon exits, failing when nothing was installed
+pub fn unit(bin: &Path, config: &Path) -> String {
+    let command = |command: &str| {
+        [
+            bin.display().to_string(),
+            "-c".to_string(),
+            config.display().to_string(),
+            command.to_string(),
+        ]
+        .iter()
+        .map(|word| exec_word(word))
+        .collect::<Vec<_>>()
+        .join(" ")
+    };
+    format!(
+        "[Unit]
+Description=zapret-ux DPI bypass
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={start}
+ExecReload={reload}
+ExecStopPost=-{stop}
+Restart=on-failure
+RestartSec=5
+WatchdogSec=30
+
+[Install]
+WantedBy=multi-user.target
+",
+        start = command("daemon"),
+        reload = command("reload"),
+        stop = command("stop"),
+    )
+}
+
+/// Word of an `ExecStart=` command line. Words other than plain paths are
+/// double-quoted with C escapes, `%` specifiers and `$` variables are escaped
+fn exec_word(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/_-.,:+=@".contains(c));
+    if plain {
+        return word.to_string();
+    }
+    let mut quoted = String::from('"');
+    for c in word.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '%' => quoted.push_str("%%"),
+            '$' => quoted.push_str("$$"),
+            c if c.is_ascii_control() => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Write the unit into `dir`, returns its path
+pub fn install(dir: &Path, bin: &Path, config: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let path = dir.join(UNIT_NAME);
+    fs::write(&path, unit(bin, config))
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(path)
+}
@@ -0,0 +1,369 @@
+//! OpenWrt UCI config, read instead of TOML when the file is in UCI format.
+//!
+//! Options override the defaults. `config main` holds the top-level options,
+//! `config iptables`, `config nfqws`, ... the tables and `config iptables_xtables_lock`
+//! the nested ones. Every `config profile` adds a profile named after the section,
+//! with `interface`, `gateway` and `ipv6` matching the uplink. Ports are lists of
+//! `80/tcp` or `50000-50099/udp`, and an empty option clears a list:
+//!
+//! ```text
+//! config main 'main'
+//!     option mark_supported '1'
+//!
+//! config iptables 'iptables'
+//!     list ports '443/tcp'
+//!     option excluded_networks ''
+//!
+//! config profile 'wan6'
+//!     option interface 'pppoe-wan'
+//!     list opt '--dpi-desync=fake'
+//! ```
+
+use crate::config::{Config, ConfigProfile, ProfileCondition};
+use anyhow::{Context, Result, bail};
+use std::net::{IpAddr, Ipv4Addr};
+use toml::{Table, Value};
+
+/// Section holding the top-level options
+const MAIN: &str = "main";
+const PROFILE: &str = "profile";
+/// Options of `config profile` that go to its `when` condition
+const CONDITIONS: [&str; 3] = ["interface", "gateway", "ipv6"];
+
+struct Section {
+    kind: String,
+    name: Option<String>,
+    options: Vec<(String, UciValue)>,
+}
+
+enum UciValue {
+    Option(String),
+    List(Vec<String>),
+}
+
+impl UciValue {
+    fn items(&self) -> &[String] {
+        match self {
+            Self::Option(value) => std::slice::from_ref(value),
+            Self::List(values) => values,
+        }
+    }
+}
+
+/// Whether `text` starts like a UCI file rather than TOML
+pub fn is_uci(text: &str) -> bool {
+    text.lines()
+        .filter_map(|line| words(line).ok())
+        .find(|words| !words.is_empty())
+        .is_some_and(|words| {
+            matches!(words[0].as_str(), "config" | "package")
+                && words.get(1).is_some_and(|word| word != "=")
+        })
+}
+
+/// Parse UCI `text` on top of the default config
+pub fn parse_config(text: &str) -> Result<Config> {
+    let mut root = to_table(&Config::default())?;
+    let profile_schema = to_table(&ConfigProfile {
+        name: String::new(),
+        when: ProfileCondition {
+            interface: Some(String::new()),
+            gateway: Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            ipv6: Some(false),
+        },
+        ports: Some(Vec::new()),
+        opt: Some(Vec::new()),
+    })?;
+
+    for section in parse(text)? {
+        let kind = section.kind.as_str();
+        let context = || format!("Invalid section '{}'", kind);
+        if kind == MAIN {
+            fill(&mut root, &section).with_context(context)?;
+        } else if kind == PROFILE {
+            let profile = profile(&section, &profile_schema).with_context(context)?;
+            match root.get_mut("profiles") {
+                Some(Value::Array(profiles)) => profiles.push(Value::Table(profile)),
+                _ => bail!("Config has no profiles"),
+            }
+        } else {
+            let table = nested_table(&mut root, kind)
+                .with_context(|| format!("Unknown section type '{}'", kind))?;
+            fill(table, &section).with_context(context)?;
+        }
+    }
+    Value::Table(root).try_into().context("Invalid UCI config")
+}
+
+/// Render `config` as UCI, e.g. for a default `/etc/config` file
+pub fn render(config: &Config) -> Result<String> {
+    let root = to_table(config)?;
+    let mut out = String::new();
+    let mut tables = Vec::new();
+    render_section(&mut out, MAIN, None, &root, &mut tables);
+    while !tables.is_empty() {
+        for (kind, table) in std::mem::take(&mut tables) {
+            render_section(&mut out, &kind, None, &table, &mut tables);
+        }
+    }
+    if let Some(Value::Array(profiles)) = root.get("profiles") {
+        for profile in profiles.iter().filter_map(Value::as_table) {
+            let mut profile = profile.clone();
+            let name = profile
+                .remove("name")
+                .and_then(|name| name.as_str().map(quote));
+            if let Some(Value::Table(when)) = profile.remove("when") {
+                profile.extend(when);
+            }
+            render_section(&mut out, PROFILE, name, &profile, &mut Vec::new());
+        }
+    }
+    Ok(out)
+}
+
+fn render_section(
+    out: &mut String,
+    kind: &str,
+    name: Option<String>,
+    table: &Table,
+    nested: &mut Vec<(String, Table)>,
+) {
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    let name = name.unwrap_or_else(|| quote(kind));
+    out.push_str(&format!("config {} {}\n", kind, name));
+    for (key, value) in table {
+        match value {
+            Value::Table(table) if kind == MAIN => nested.push((key.clone(), table.clone())),
+            Value::Table(table) => nested.push((format!("{}_{}", kind, key), table.clone())),
+            Value::Array(items) if key == "ports" => {
+                for port in items.iter().filter_map(Value::as_table) {
+                    out.push_str(&format!("\tlist {} {}\n", key, quote(&render_port(port))));
+                }
+            }
+            // Profiles get their own sections
+            Value::Array(_) if kind == MAIN && key == "profiles" => {}
+            Value::Array(items) => {
+                for item in items {
+                    out.push_str(&format!("\tlist {} {}\n", key, quote(&scalar(item))));
+                }
+            }
+            value => out.push_str(&format!("\toption {} {}\n", key, quote(&scalar(value)))),
+        }
+    }
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        Value::Boolean(value) => if *value { "1" } else { "0" }.to_string(),
+        value => value.to_string(),
+    }
+}
+
+fn render_port(port: &Table) -> String {
+    let number = match port.get("port") {
+        Some(Value::Array(range)) => range.iter().map(scalar).collect::<Vec<String>>().join("-"),
+        Some(value) => scalar(value),
+        None => String::new(),
+    };
+    let protocol = port.get("protocol").map(scalar).unwrap_or_default();
+    format!("{}/{}", number, protocol)
+}
+
+/// Single-quote for UCI, which also joins adjacent quoted parts
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+fn to_table<T: serde::Serialize>(value: &T) -> Result<Table> {
+    match Value::try_from(value)? {
+        Value::Table(table) => Ok(table),
+        _ => bail!("Config is not a table"),
+    }
+}
+
+/// Table named `kind`, or `parent_child` for a table nested in another
+fn nested_table<'a>(root: &'a mut Table, kind: &str) -> Option<&'a mut Table> {
+    let path: Vec<String> = root
+        .iter()
+        .filter_map(|(key, value)| Some((key, value.as_table()?)))
+        .find_map(|(key, table)| {
+            if key == kind {
+                return Some(vec![key.clone()]);
+            }
+            let child = kind.strip_prefix(key.as_str())?.strip_prefix('_')?;
+            table
+                .get(child)
+                .is_some_and(Value::is_table)
+                .then(|| vec![key.clone(), child.to_string()])
+        })?;
+    path.iter()
+        .try_fold(root, |table, key| table.get_mut(key)?.as_table_mut())
+}
+
+fn profile(section: &Section, schema: &Table) -> Result<Table> {
+    let mut profile = Table::new();
+    let mut when = Table::new();
+    let when_schema = schema.get("when").and_then(Value::as_table);
+    for (key, value) in &section.options {
+        if CONDITIONS.contains(&key.as_str()) {
+            when.insert(
+                key.clone(),
+                typed(when_schema.and_then(|t| t.get(key)), value)?,
+            );
+        } else {
+            profile.insert(key.clone(), option(schema.get(key), key, value)?);
+        }
+    }
+    if !profile.contains_key("name") {
+        let name = section.name.clone().context("Profile has no name")?;
+        profile.insert("name".to_string(), Value::String(name));
+    }
+    profile.insert("when".to_string(), Value::Table(when));
+    Ok(profile)
+}
+
+/// Override options of `table` with the section ones, typed like the defaults
+fn fill(table: &mut Table, section: &Section) -> Result<()> {
+    for (key, value) in &section.options {
+        let value = option(table.get(key), key, value)?;
+        table.insert(key.clone(), value);
+    }
+    Ok(())
+}
+
+fn option(default: Option<&Value>, key: &str, value: &UciValue) -> Result<Value> {
+    if key == "ports" {
+        let ports = value.items().iter().map(|port| parse_port(port));
+        return Ok(Value::Array(ports.collect::<Result<_>>()?));
+    }
+    typed(default, value).with_context(|| format!("Invalid option '{}'", key))
+}
+
+fn typed(default: Option<&Value>, value: &UciValue) -> Result<Value> {
+    match (default, value) {
+        (Some(Value::Array(_)), UciValue::Option(value)) if value.is_empty() => {
+            Ok(Value::Array(Vec::new()))
+        }
+        (Some(Value::Array(items)), value) => {
+            let item = items.first();
+            let items = value.items().iter().map(|v| scalar_typed(item, v));
+            Ok(Value::Array(items.collect::<Result<_>>()?))
+        }
+        (_, UciValue::List(_)) => bail!("expected option, got list"),
+        (default, UciValue::Option(value)) => scalar_typed(default, value),
+    }
+}
+
+fn scalar_typed(default: Option<&Value>, value: &str) -> Result<Value> {
+    Ok(match default {
+        Some(Value::Boolean(_)) => Value::Boolean(match value {
+            "1" | "true" | "yes" | "on" | "enabled" => true,
+            "0" | "false" | "no" | "off" | "disabled" => false,
+            _ => bail!("expected boolean, got '{}'", value),
+        }),
+        Some(Value::Integer(_)) => Value::Integer(value.parse()?),
+        Some(Value::Float(_)) => Value::Float(value.parse()?),
+        Some(Value::Table(_)) => bail!("expected section, got '{}'", value),
+        _ => Value::String(value.to_string()),
+    })
+}
+
+/// `80/tcp` or `50000-50099/udp`
+fn parse_port(value: &str) -> Result<Value> {
+    let (port, protocol) = value
+        .split_once('/')
+        .with_context(|| format!("Invalid port '{}', expected PORT/PROTOCOL", value))?;
+    let number = |port: &str| -> Result<Value> {
+        let port: u16 = port
+            .parse()
+            .with_context(|| format!("Invalid port '{}'", value))?;
+        Ok(Value::Integer(port.into()))
+    };
+    let port = match port.split_once(['-', ':']) {
+        Some((start, end)) => Value::Array(vec![number(start)?, number(end)?]),
+        None => number(port)?,
+    };
+    let mut table = Table::new();
+    table.insert("port".to_string(), port);
+    table.insert("protocol".to_string(), Value::String(protocol.to_string()));
+    Ok(Value::Table(table))
+}
+
+fn parse(text: &str) -> Result<Vec<Section>> {
+    let mut sections: Vec<Section> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let context = || format!("Line {}", number + 1);
+        let words = words(line).with_context(context)?;
+        match words.as_slice() {
+            [] => {}
+            [keyword, ..] if keyword == "package" => {}
+            [keyword, kind] if keyword == "config" => sections.push(Section {
+                kind: kind.clone(),
+                name: None,
+                options: Vec::new(),
+            }),
+            [keyword, kind, name] if keyword == "config" => sections.push(Section {
+                kind: kind.clone(),
+                name: Some(name.clone()),
+                options: Vec::new(),
+            }),
+            [keyword, key, value] if keyword == "option" || keyword == "list" => {
+                let section = sections
+                    .last_mut()
+                    .with_context(|| format!("{}: {} outside of a section", context(), keyword))?;
+                let existing = section.options.iter_mut().find(|(k, _)| k == key);
+                match (keyword.as_str(), existing) {
+                    ("list", Some((_, UciValue::List(values)))) => values.push(value.clone()),
+                    ("list", _) => section
+                        .options
+                        .push((key.clone(), UciValue::List(vec![value.clone()]))),
+                    (_, Some((_, existing))) => *existing = UciValue::Option(value.clone()),
+                    (_, None) => section
+                        .options
+                        .push((key.clone(), UciValue::Option(value.clone()))),
+                }
+            }
+            _ => bail!("{}: invalid statement '{}'", context(), line.trim()),
+        }
+    }
+    Ok(sections)
+}
+
+/// Split a line into shell-like words. Quoted parts are joined with adjacent ones
+fn words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        match chars.peek() {
+            None | Some('#') => return Ok(words),
+            Some(_) => {}
+        }
+        let mut word = String::new();
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            match c {
+                '\'' => loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => bail!("unterminated quote"),
+                    }
+                },
+                '"' => loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => word.push(chars.next().context("unterminated quote")?),
+                        Some(c) => word.push(c),
+                        None => bail!("unterminated quote"),
+                    }
+                },
+                '\\' => word.push(chars.next().context("trailing backslash")?),
+                c => word.push(c),
+            }
+        }
+        words.push(word);
+    }
+}
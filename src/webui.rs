@@ -0,0 +1,193 @@
+use crate::control::Request;
+use anyhow::{Context, Result, bail};
+use runner::shell_line;
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    time::Duration,
+};
+use tracing::{info, warn};
+
+const INDEX: &str = include_str!("webui/index.html");
+/// Replaced with the CLI command line in static exports
+const CLI_PLACEHOLDER: &str = "/*CLI*/null";
+/// Header the page sends with every request. Browsers can't add it to
+/// cross-origin requests without a CORS preflight, which is never allowed
+const REQUEST_HEADER: &str = "x-zapret-ux";
+const MAX_BODY: usize = 64 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parsed HTTP request
+struct HttpRequest {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Serve the UI on `listen`, a loopback address. Requests posted by the page
+/// to `/api/request` are control protocol lines run with `dispatch`
+pub fn serve<D>(listen: SocketAddr, mut dispatch: D) -> Result<()>
+where
+    D: FnMut(Request) -> Result<Vec<String>>,
+{
+    // Requests are unauthenticated and run as root
+    if !listen.ip().is_loopback() {
+        bail!("Web UI must listen on a loopback address, got {}", listen);
+    }
+    let listener =
+        TcpListener::bind(listen).with_context(|| format!("Failed to listen on {}", listen))?;
+    info!(%listen, "Serving web UI");
+    for stream in listener.incoming() {
+        let result = stream
+            .context("Failed to accept connection")
+            .and_then(|stream| handle(stream, &mut dispatch));
+        if let Err(error) = result {
+            warn!(
+                error = format!("{:#}", error),
+                "Failed to serve web UI client"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Write the UI as a static page running the CLI through `ksu.exec`
+pub fn export(dir: &Path, bin: &Path, config: &Path) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let path = dir.join("index.html");
+    fs::write(&path, static_page(bin, config)?)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+/// UI page running `bin -c config <request>` for every request
+pub fn static_page(bin: &Path, config: &Path) -> Result<String> {
+    let cli = shell_line(&[
+        bin.display().to_string(),
+        "-c".to_string(),
+        config.display().to_string(),
+    ]);
+    Ok(INDEX.replace(CLI_PLACEHOLDER, &serde_json::to_string(&cli)?))
+}
+
+fn handle<D>(stream: TcpStream, dispatch: &mut D) -> Result<()>
+where
+    D: FnMut(Request) -> Result<Vec<String>>,
+{
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let (status, content_type, body) = match read_request(&stream) {
+        Ok(request) => route(&request, dispatch),
+        Err(error) => ("400 Bad Request", "text/plain", format!("{:#}", error)),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}; charset=utf-8\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    Ok(())
+}
+
+fn route<D>(request: &HttpRequest, dispatch: &mut D) -> (&'static str, &'static str, String)
+where
+    D: FnMut(Request) -> Result<Vec<String>>,
+{
+    // Reject DNS rebinding: only loopback names may reach the UI
+    if !request.header("host").is_some_and(is_loopback_host) {
+        return ("403 Forbidden", "text/plain", "Forbidden host".to_string());
+    }
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/" | "/index.html") => ("200 OK", "text/html", INDEX.to_string()),
+        ("POST", "/api/request") => {
+            if request.header(REQUEST_HEADER) != Some("1") {
+                return ("403 Forbidden", "text/plain", "Missing header".to_string());
+            }
+            let response = request.body.trim().parse().and_then(|control: Request| {
+                info!(request = %control, "Web UI request");
+                dispatch(control)
+            });
+            match response {
+                Ok(lines) => ("200 OK", "text/plain", lines.join("\n")),
+                Err(error) => (
+                    "500 Internal Server Error",
+                    "text/plain",
+                    format!("{:#}", error),
+                ),
+            }
+        }
+        (_, "/" | "/index.html" | "/api/request") => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed".to_string(),
+        ),
+        _ => ("404 Not Found", "text/plain", "Not found".to_string()),
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Result<HttpRequest> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        bail!("Invalid request line '{}'", line.trim());
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            bail!("Invalid header '{}'", header);
+        };
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+
+    let mut request = HttpRequest {
+        method,
+        path,
+        headers,
+        body: String::new(),
+    };
+    let length: usize = match request.header("content-length") {
+        Some(length) => length.parse().context("Invalid Content-Length")?,
+        None => 0,
+    };
+    if length > MAX_BODY {
+        bail!("Request body is too large");
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    request.body = String::from_utf8(body).context("Request body is not UTF-8")?;
+    Ok(request)
+}
+
+fn is_loopback_host(host: &str) -> bool {
+    let name = match host.rsplit_once(':') {
+        // Port after a name or an IPv4 address, IPv6 addresses are bracketed
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => host,
+    };
+    matches!(name, "localhost" | "127.0.0.1" | "[::1]")
+}
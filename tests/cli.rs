@@ -0,0 +1,1267 @@
+mod common;
+
+use common::*;
+use rustix::process::{Pid, Signal, kill_process};
+use std::process::Command;
+
+const LOCK: &str = "iptables --wait 1 --wait-interval 1000";
+const LOCK6: &str = "ip6tables --wait 1 --wait-interval 1000";
+const MATCHES: &str = "--match mark ! --mark 0x40000000/0x40000000 \
+    --match connbytes --connbytes 1:6 --connbytes-dir original --connbytes-mode packets \
+    --jump NFQUEUE --queue-num 200 --queue-bypass";
+const NFQWS: &str = "nfqws --debug 1 --daemon --qnum 200 --uid 0:0 --dpi-desync-fwmark 0x40000000 --filter-tcp=80 --dpi-desync=fake";
+
+fn start_commands() -> Vec<String> {
+    vec![
+        format!("{LOCK} --table mangle --new ZAPRET_UX"),
+        format!(
+            "{LOCK} --table mangle --append ZAPRET_UX --protocol tcp --match tcp --dport 80 {MATCHES}"
+        ),
+        format!(
+            "{LOCK} --table mangle --append ZAPRET_UX --protocol udp --match udp --dport 443 {MATCHES}"
+        ),
+        format!("{LOCK} --table mangle --insert POSTROUTING --jump ZAPRET_UX"),
+        NFQWS.to_string(),
+    ]
+}
+
+fn stop_commands() -> Vec<String> {
+    vec![
+        format!("{LOCK} --table mangle --delete POSTROUTING --jump ZAPRET_UX"),
+        format!("{LOCK} --table mangle --flush ZAPRET_UX"),
+        format!("{LOCK} --table mangle --delete-chain ZAPRET_UX"),
+        "pkill nfqws".to_string(),
+    ]
+}
+
+#[test]
+fn start_status_stop() {
+    let sandbox = Sandbox::new("start_status_stop");
+    let Some(output) = sandbox.run(&["start"]) else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(sandbox.commands(), start_commands());
+    assert!(sandbox.nfqws_running());
+
+    let output = sandbox.run(&["status"]).unwrap();
+    assert_eq!(stdout(&output), "Daemon is running\n");
+
+    sandbox.clear_log();
+    let output = sandbox.run(&["stop"]).unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(sandbox.commands(), stop_commands());
+    assert!(!sandbox.nfqws_running());
+
+    let output = sandbox.run(&["status"]).unwrap();
+    assert_eq!(stdout(&output), "Daemon is not running\n");
+}
+
+#[test]
+fn status_json_reports_rules_and_inconsistencies() {
+    let sandbox = Sandbox::new("status_json_reports_rules_and_inconsistencies");
+    let Some(output) = sandbox.run(&["start"]) else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let status = status_json(&sandbox);
+    assert_eq!(status["running"], true);
+    assert_eq!(status["daemon"], false);
+    assert_eq!(status["nfqws"][0]["pid"], 4242);
+    assert_eq!(status["filter_mode"], "none");
+    assert_eq!(status["capabilities"]["mark"], true);
+    assert_eq!(status["capabilities"]["ipv6"], false);
+    assert_eq!(status["config"]["sha256"].as_str().unwrap().len(), 64);
+    let rules = status["rules"].as_array().unwrap();
+    assert!(
+        rules.iter().all(|rule| rule["installed"] == true),
+        "{:#?}",
+        rules
+    );
+    assert!(
+        rules
+            .iter()
+            .any(|rule| rule["rule"] == "-t mangle -A POSTROUTING -j ZAPRET_UX")
+    );
+    assert_eq!(status["inconsistencies"], serde_json::json!([]));
+
+    // Simulate netd rebuilding the mangle table
+    std::fs::write(sandbox.state_dir().join("iptables.jumps"), "").unwrap();
+    let status = status_json(&sandbox);
+    assert_eq!(
+        status["inconsistencies"][0],
+        format!(
+            "1 of {} rules are missing while nfqws is running",
+            rules.len()
+        )
+    );
+}
+
+fn status_json(sandbox: &Sandbox) -> serde_json::Value {
+    let output = sandbox.run(&["status", "--format", "json"]).unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    serde_json::from_str(&stdout(&output)).unwrap()
+}
+
+#[test]
+fn restart_replaces_rules() {
+    let sandbox = Sandbox::new("restart_replaces_rules");
+    let Some(output) = sandbox.run(&["start"]) else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    sandbox.clear_log();
+    let output = sandbox.run(&["restart"]).unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let mut expected = stop_commands();
+    expected.extend(start_commands());
+    assert_eq!(sandbox.commands(), expected);
+}
+
+#[test]
+fn stop_removes_rules_of_previous_config() {
+    let sandbox = Sandbox::new("stop_removes_rules_of_previous_config");
+    sandbox.append_config("\n[iptables.tethering]\nenabled = true\ndownstream = [\"ap+\"]\n");
+    let Some(output) = sandbox.run(&["start"]) else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+    let rules = sandbox.dir().join("zapret-ux-ip.rules.json");
+    assert!(rules.exists());
+
+    sandbox.edit_config("enabled = true", "enabled = false");
+    sandbox.clear_log();
+    let output = sandbox.run(&["stop"]).unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let commands = sandbox.commands();
+    for line in [
+        format!("{LOCK} --table mangle --delete FORWARD --in-interface ap+ --jump ZAPRET_UX_FWD"),
+        format!("{LOCK} --table mangle --delete-chain ZAPRET_UX_FWD"),
+    ] {
+        assert!(commands.contains(&line), "{} not in {:#?}", line, commands);
+    }
+    assert!(!rules.exists());
+}
+
+#[test]
+fn second_start_fails_with_existing_chain() {
+    let sandbox = Sandbox::new("second_start_fails_with_existing_chain");
+    let Some(output) = sandbox.run(&["start"]) else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = sandbox.run(&["start"]).unwrap();
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("Chain already exists"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn stop_tolerates_missing_chain() {
+    let sandbox = Sandbox::new("stop_tolerates_missing_chain");
+    std::fs::write(sandbox.state_dir().join("nfqws.running"), "").unwrap();
+    let Some(output) = sandbox.run(&["stop"]) else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        sandbox.commands(),
+        [
+            format!("{LOCK} --table mangle --delete POSTROUTING --jump ZAPRET_UX"),
+            format!("{LOCK} --table mangle --flush ZAPRET_UX"),
+            "pkill nfqws".to_string(),
+        ]
+    );
+}
+
+#[test]
+fn nfqws_failure_is_reported() {
+    let mut sandbox = Sandbox::new("nfqws_failure_is_reported");
+    sandbox.env("FAKE_NFQWS_FAIL", "1");
+    let Some(output) = sandbox.run(&["start"]) else {
+        return;
+    };
+    assert!(!output.status.success());
+    assert!(!sandbox.nfqws_running());
+    assert_eq!(sandbox.commands().last().map(String::as_str), Some(NFQWS));
+}
+
+#[test]
+fn xtables_lock_contention_is_retried() {
+    let mut sandbox = Sandbox::new("xtables_lock_contention_is_retried");
+    sandbox.env("FAKE_LOCK_FAILS", "2");
+    let Some(output) = sandbox.run(&["start"]) else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+    let mut expected = vec![start_commands()[0].clone(); 2];
+    expected.extend(start_commands());
+    assert_eq!(sandbox.commands(), expected);
+}
+
+/// Holds the global lock from the test, like a concurrent `zapret-ux start` would
+#[test]
+fn mutating_commands_wait_for_lock() {
+    let sandbox = Sandbox::new("mutating_commands_wait_for_lock");
+    let path = sandbox.dir().join("zapret-ux.lock");
+    std::fs::write(&path, "999 zapret-ux start\n").unwrap();
+    let lock = std::fs::File::open(&path).unwrap();
+    rustix::fs::flock(&lock, rustix::fs::FlockOperation::LockExclusive).unwrap();
+
+    sandbox.edit_config("wait_secs = 5", "wait_secs = 0");
+    let Some(output) = sandbox.run(&["start"]) else {
+        return;
+    };
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("is held by pid 999 (zapret-ux start)"),
+        "{}",
+        stderr(&output)
+    );
+    assert_eq!(sandbox.commands(), Vec::<String>::new());
+    let status = sandbox.run(&["status"]).unwrap();
+    assert!(status.status.success(), "{}", stderr(&status));
+    let apps = sandbox.run(&["apps", "add", "org.example"]).unwrap();
+    assert!(!apps.status.success());
+    let config = std::fs::read_to_string(sandbox.config_path()).unwrap();
+    assert!(!config.contains("org.example"), "{config}");
+
+    sandbox.edit_config("wait_secs = 0", "wait_secs = 5");
+    sandbox.clear_log();
+    let mut start = sandbox.spawn(&["start"]).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    assert!(start.try_wait().unwrap().is_none());
+    drop(lock);
+    assert!(start.wait().unwrap().success());
+    assert_eq!(sandbox.commands(), start_commands());
+    let holder = std::fs::read_to_string(&path).unwrap();
+    assert!(holder.contains(" start"), "{holder}");
+}
+
+#[test]
+fn hung_iptables_times_out() {
+    let mut sandbox = Sandbox::new("hung_iptables_times_out");
+    sandbox.edit_config("command_timeout_secs = 5", "command_timeout_secs = 1");
+    sandbox.env("FAKE_SLEEP", "10");
+
+    let Some(output) = sandbox.run(&["start"]) else {
+        return;
+    };
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("timed out"), "{}", stderr(&output));
+}
+
+#[test]
+fn dry_run_executes_nothing() {
+    let sandbox = Sandbox::new("dry_run_executes_nothing");
+    let output = Command::new(bin())
+        .arg("-c")
+        .arg(sandbox.config_path())
+        .args(["--dry-run", "start"])
+        .env("FAKE_STATE", sandbox.state_dir())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    // Only the version is probed to print the lock flags iptables would get
+    assert_eq!(sandbox.log(), ["iptables --version"]);
+    assert!(!sandbox.nfqws_running());
+    assert!(!sandbox.dir().join("zapret-ux-ip.rules.json").exists());
+    let fakes = format!("{}/", Sandbox::fakes_dir().display());
+    let printed = stdout(&output);
+    let lines: Vec<&str> = printed
+        .lines()
+        .skip_while(|line| *line != "Dry run. Commands that would be executed:")
+        .skip(1)
+        .map(|line| line.strip_prefix(&fakes).unwrap_or(line))
+        .collect();
+    assert_eq!(lines, start_commands(), "{}", printed);
+
+    let output = Command::new(bin())
+        .arg("-c")
+        .arg(sandbox.config_path())
+        .args(["--dry-run", "status", "--format", "json"])
+        .env("FAKE_STATE", sandbox.state_dir())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let status: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(status["dry_run"], true);
+    let commands: Vec<&str> = status["commands"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|command| command.as_str().unwrap())
+        .collect();
+    let ran = |line: &str| commands.iter().any(|command| command.ends_with(line));
+    assert!(ran("/pgrep nfqws"), "{:#?}", commands);
+    assert!(
+        ran(&format!(
+            "/{LOCK} --table mangle --check POSTROUTING --jump ZAPRET_UX"
+        )),
+        "{:#?}",
+        commands
+    );
+}
+
+/// Scripts of every export format, compared with `tests/golden/export.<format>`
+#[test]
+fn export_formats_match_golden_files() {
+    let sandbox = Sandbox::new("export_formats_match_golden_files");
+    sandbox.edit_config(
+        "excluded_networks = []",
+        r#"excluded_networks = ["10.0.0.0/8"]"#,
+    );
+    sandbox.edit_config(
+        r#""--dpi-desync=fake"]"#,
+        r#""--dpi-desync=fake", "--dpi-desync-fake-http=GET / HTTP/1.1", "--hostlist-domains=it's.example"]"#,
+    );
+    sandbox.append_config("\n[iptables.block_quic]\nenabled = true\n");
+    let golden = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    for format in ["sh", "iptables-restore", "nft"] {
+        let Some(output) = sandbox.run(&["export", "--format", format]) else {
+            return;
+        };
+        assert!(output.status.success(), "{}", stderr(&output));
+        let expected = std::fs::read_to_string(golden.join(format!("export.{format}")))
+            .unwrap()
+            .replace("{FAKES}", &Sandbox::fakes_dir().display().to_string())
+            .replace("{VERSION}", env!("CARGO_PKG_VERSION"));
+        assert_eq!(stdout(&output), expected, "format: {format}");
+    }
+    assert_eq!(sandbox.commands(), Vec::<String>::new());
+}
+
+#[test]
+fn apps_are_resolved_for_every_user() {
+    let sandbox = Sandbox::new("apps_are_resolved_for_every_user");
+    let packages_list = sandbox.dir().join("packages.list");
+    std::fs::write(
+        &packages_list,
+        "com.google.android.youtube 10123 0 /data/user/0/com.google.android.youtube default:targetSdkVersion=34 3003\n\
+         com.discord 10456 0 /data/user/0/com.discord default:targetSdkVersion=34 3003\n",
+    )
+    .unwrap();
+    let users = sandbox.dir().join("users");
+    std::fs::create_dir_all(users.join("0")).unwrap();
+    std::fs::write(users.join("10.xml"), "").unwrap();
+    sandbox.append_config(&format!(
+        "[apps]\nmode = \"include\"\npackages_list_path = \"{}\"\nusers_path = \"{}\"\n",
+        packages_list.display(),
+        users.display(),
+    ));
+
+    let Some(output) = sandbox.run(&["apps", "add", "com.google.android.youtube", "org.missing"])
+    else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = sandbox.run(&["apps", "list"]).unwrap();
+    assert_eq!(
+        stdout(&output),
+        "Mode: Include\ncom.google.android.youtube 10123,1010123\norg.missing (not installed)\n"
+    );
+    let output = sandbox
+        .run(&["--dry-run", "apps", "remove", "org.missing"])
+        .unwrap();
+    assert!(
+        stderr(&output).contains("apps add and remove can't be used with --dry-run"),
+        "{}",
+        stderr(&output)
+    );
+    let output = sandbox.run(&["apps", "remove", "org.missing"]).unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = sandbox.run(&["start"]).unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let commands = sandbox.commands();
+    assert_eq!(commands.len(), 7, "{:#?}", commands);
+    assert!(
+        commands[1].contains("--match owner --uid-owner 10123 --jump NFQUEUE"),
+        "{}",
+        commands[1]
+    );
+    assert!(
+        commands[2].contains("--match owner --uid-owner 1010123 --jump NFQUEUE"),
+        "{}",
+        commands[2]
+    );
+}
+
+#[test]
+fn include_mode_without_packages_processes_nothing() {
+    let sandbox = Sandbox::new("include_mode_without_packages_processes_nothing");
+    sandbox.append_config("[apps]\nmode = \"include\"\n");
+    let Some(output) = sandbox.run(&["start"]) else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+    let commands = sandbox.commands();
+    assert!(
+        !commands.iter().any(|line| line.contains("NFQUEUE")),
+        "{:#?}",
+        commands
+    );
+
+    let output = sandbox.run(&["stop"]).unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn default_networks_are_excluded_for_both_families() {
+    let sandbox = Sandbox::new("default_networks_are_excluded_for_both_families");
+    let ip6tables = Sandbox::fakes_dir().join("ip6tables");
+    sandbox.edit_config(
+        "excluded_networks = []",
+        &format!("ip6tables_path = \"{}\"", ip6tables.display()),
+    );
+    let Some(output) = sandbox.run(&["start"]) else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+    let commands = sandbox.commands();
+    let v4 =
+        format!("{LOCK} --table mangle --append ZAPRET_UX --destination 10.0.0.0/8 --jump RETURN");
+    let v6 =
+        format!("{LOCK6} --table mangle --append ZAPRET_UX --destination fe80::/10 --jump RETURN");
+    assert!(commands.contains(&v4), "{:#?}", commands);
+    assert!(commands.contains(&v6), "{:#?}", commands);
+    assert!(
+        !commands
+            .iter()
+            .any(|line| line.starts_with("iptables ") && line.contains("fe80::")),
+        "{:#?}",
+        commands
+    );
+    assert_eq!(commands.last().map(String::as_str), Some(NFQWS));
+
+    sandbox.clear_log();
+    let output = sandbox.run(&["stop"]).unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        sandbox
+            .commands()
+            .contains(&format!("{LOCK6} --table mangle --delete-chain ZAPRET_UX"))
+    );
+}
+
+#[test]
+fn supervisor_falls_back_to_fail_open_rules() {
+    let mut sandbox = Sandbox::new("supervisor_falls_back_to_fail_open_rules");
+    sandbox.edit_config(
+        "excluded_networks = []",
+        "excluded_networks = []\nfail_mode = \"closed\"",
+    );
+    sandbox.edit_config(
+        "command_timeout_secs = 5",
+        "command_timeout_secs = 5\n\n[supervisor]\ncheck_interval_ms = 10\ngrace_period_ms = 0",
+    );
+    sandbox.env("FAKE_NFQWS_FAIL", "1");
+    let Some(mut child) = sandbox.spawn(&["supervise"]) else {
+        return;
+    };
+    let closed = "--append ZAPRET_UX --protocol tcp --match tcp --dport 80";
+    let reinstalled =
+        sandbox.wait_for_log(|line| line.contains(closed) && line.ends_with("--queue-bypass"));
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert!(reinstalled, "{:#?}", sandbox.commands());
+
+    let commands = sandbox.commands();
+    assert!(
+        commands[1].contains(closed) && commands[1].ends_with("--queue-num 200"),
+        "{:#?}",
+        commands
+    );
+    assert!(
+        commands.contains(&format!("{LOCK} --table mangle --delete-chain ZAPRET_UX")),
+        "{:#?}",
+        commands
+    );
+}
+
+#[test]
+fn watch_reapplies_flushed_jump() {
+    let sandbox = Sandbox::new("watch_reapplies_flushed_jump");
+    sandbox.edit_config(
+        "command_timeout_secs = 5",
+        "command_timeout_secs = 5\n\n[supervisor]\ncheck_interval_ms = 10",
+    );
+    let Some(mut child) = sandbox.spawn(&["watch"]) else {
+        return;
+    };
+    let jump = format!("{LOCK} --table mangle --check POSTROUTING --jump ZAPRET_UX");
+    assert!(sandbox.wait_for_log(|line| line == jump));
+
+    // Simulate netd rebuilding the mangle table
+    std::fs::write(sandbox.state_dir().join("iptables.jumps"), "").unwrap();
+    sandbox.clear_log();
+    let insert = format!("{LOCK} --table mangle --insert POSTROUTING 1 --jump ZAPRET_UX");
+    let repaired = sandbox.wait_for_log(|line| line == insert);
+    child.kill().unwrap();
+    child.wait().unwrap();
+    let commands = sandbox.commands();
+    assert!(repaired, "{:#?}", commands);
+    // Only the jump is restored, the chain keeps processing traffic
+    assert!(
+        !commands.iter().any(|line| line.contains("--flush")),
+        "{:#?}",
+        commands
+    );
+}
+
+/// Brings loopback up inside a fresh network namespace while watch is running
+#[test]
+fn watch_reapplies_rules_on_network_change() {
+    if which("ip").is_none() || !unshare_available(&["--user", "--map-root-user", "--net"]) {
+        eprintln!("Skipping: ip or network namespaces are unavailable");
+        return;
+    }
+    let sandbox = Sandbox::new("watch_reapplies_rules_on_network_change");
+    sandbox.edit_config(
+        "command_timeout_secs = 5",
+        "command_timeout_secs = 5\n\n[supervisor]\ncheck_interval_ms = 60000\ndebounce_ms = 50",
+    );
+    let script = format!(
+        "{bin} -c {config} watch 2>/dev/null &
+        sleep 0.5
+        ip link set lo up
+        sleep 1
+        kill $!",
+        bin = bin(),
+        config = sandbox.config_path().display(),
+    );
+    let output = Command::new("unshare")
+        .args(["--user", "--map-root-user", "--net", "sh", "-c", &script])
+        .env("FAKE_STATE", sandbox.state_dir())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    // Checked in the first round, on the event and in the round after it,
+    // nothing is reinstalled
+    let jump = format!("{LOCK} --table mangle --check POSTROUTING --jump ZAPRET_UX");
+    let commands = sandbox.commands();
+    assert_eq!(
+        commands.iter().filter(|line| **line == jump).count(),
+        3,
+        "{:#?}",
+        commands
+    );
+    assert!(
+        !commands.iter().any(|line| line.contains("--flush")),
+        "{:#?}",
+        commands
+    );
+}
+
+#[test]
+fn daemon_serves_cli_requests() {
+    let sandbox = Sandbox::new("daemon_serves_cli_requests");
+    sandbox.append_config(&format!(
+        "[nfqws.hostlists]\nhosts = \"{dir}/hosts.txt\"\nexclude = \"{dir}/exclude.txt\"\n",
+        dir = sandbox.dir().display()
+    ));
+    std::fs::write(sandbox.dir().join("hosts.txt"), "# blocked\nexample.com\n").unwrap();
+    let Some(mut daemon) = sandbox.spawn(&["daemon"]) else {
+        return;
+    };
+    assert!(sandbox.wait_for_log(|line| line.starts_with("nfqws ")));
+    assert!(sandbox.wait_for_log(|_| sandbox.dir().join("zapret-ux.sock").exists()));
+
+    let status = sandbox.run(&["status"]).unwrap();
+    assert_eq!(
+        stdout(&status),
+        "Daemon is running\n",
+        "{}",
+        stderr(&status)
+    );
+    let add = sandbox
+        .run(&["hosts", "add", "Example.com", "example.org"])
+        .unwrap();
+    assert_eq!(stdout(&add), "Added 1 hosts\n", "{}", stderr(&add));
+    let list = sandbox.run(&["hosts", "list"]).unwrap();
+    assert_eq!(stdout(&list), "example.com\nexample.org\n");
+
+    let stop = sandbox.run(&["stop"]).unwrap();
+    assert!(stop.status.success(), "{}", stderr(&stop));
+    assert!(!sandbox.nfqws_running());
+    let second_stop = sandbox.run(&["stop"]).unwrap();
+    assert!(!second_stop.status.success());
+    assert!(
+        stderr(&second_stop).contains("Daemon: "),
+        "{}",
+        stderr(&second_stop)
+    );
+
+    sandbox.edit_config("--dpi-desync=fake", "--dpi-desync=split");
+    let status = status_json(&sandbox);
+    assert_eq!(status["daemon"], true);
+    assert_eq!(
+        status["inconsistencies"],
+        serde_json::json!(["Config changed since it was loaded, reload to apply"])
+    );
+    let reload = sandbox.run(&["reload"]).unwrap();
+    assert!(reload.status.success(), "{}", stderr(&reload));
+    // Started by the daemon rather than next to it
+    let start = sandbox.run(&["autostart"]).unwrap();
+    assert!(start.status.success(), "{}", stderr(&start));
+    assert_eq!(stdout(&start), "Starting daemon\n", "{}", stderr(&start));
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+
+    let commands = sandbox.commands();
+    assert!(
+        commands.last().unwrap().ends_with("--dpi-desync=split"),
+        "{:#?}",
+        commands
+    );
+    assert_eq!(
+        std::fs::read_to_string(sandbox.dir().join("hosts.txt")).unwrap(),
+        "# blocked\nexample.com\nexample.org\n"
+    );
+}
+
+#[test]
+fn daemon_drops_silent_clients() {
+    let sandbox = Sandbox::new("daemon_drops_silent_clients");
+    let Some(mut daemon) = sandbox.spawn(&["daemon"]) else {
+        return;
+    };
+    let socket = sandbox.dir().join("zapret-ux.sock");
+    assert!(sandbox.wait_for_log(|line| line.starts_with("nfqws ")));
+    assert!(sandbox.wait_for_log(|_| socket.exists()));
+
+    let _silent = std::os::unix::net::UnixStream::connect(&socket).unwrap();
+    let started = std::time::Instant::now();
+    let status = sandbox.run(&["status"]).unwrap();
+    assert_eq!(
+        stdout(&status),
+        "Daemon is running\n",
+        "{}",
+        stderr(&status)
+    );
+    assert!(started.elapsed() < std::time::Duration::from_secs(3));
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+}
+
+#[test]
+fn daemon_replaces_only_stale_sockets() {
+    let sandbox = Sandbox::new("daemon_replaces_only_stale_sockets");
+    let socket = sandbox.dir().join("zapret-ux.sock");
+    std::fs::write(&socket, "not a socket").unwrap();
+    let Some(output) = sandbox.run(&["daemon"]) else {
+        return;
+    };
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("is not a socket, refusing to replace it"),
+        "{}",
+        stderr(&output)
+    );
+    assert_eq!(std::fs::read_to_string(&socket).unwrap(), "not a socket");
+
+    // Left behind by a daemon that crashed
+    std::fs::remove_file(&socket).unwrap();
+    drop(std::os::unix::net::UnixListener::bind(&socket).unwrap());
+    let mut daemon = sandbox.spawn(&["daemon"]).unwrap();
+    assert!(sandbox.wait_for_log(|line| line.starts_with("nfqws ")));
+    let status = sandbox.run(&["status"]).unwrap();
+    assert_eq!(
+        stdout(&status),
+        "Daemon is running\n",
+        "{}",
+        stderr(&status)
+    );
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+}
+
+#[test]
+fn daemon_cleans_up_on_sigterm() {
+    let sandbox = Sandbox::new("daemon_cleans_up_on_sigterm");
+    let Some(mut daemon) = sandbox.spawn(&["daemon"]) else {
+        return;
+    };
+    assert!(sandbox.wait_for_log(|line| line.starts_with("nfqws ")));
+    assert!(sandbox.wait_for_log(|_| sandbox.dir().join("zapret-ux.sock").exists()));
+
+    sandbox.clear_log();
+    let pid = Pid::from_child(&daemon);
+    kill_process(pid, Signal::TERM).unwrap();
+    let status = daemon.wait().unwrap();
+    assert!(status.success(), "{}", status);
+    assert_eq!(sandbox.commands(), stop_commands());
+    assert!(!sandbox.nfqws_running());
+}
+
+#[test]
+fn daemon_forces_profile_until_auto() {
+    let sandbox = Sandbox::new("daemon_forces_profile_until_auto");
+    sandbox.append_config(
+        r#"[[profiles]]
+name = "alt"
+when = { interface = "no-such-link" }
+opt = ["--profile=alt"]
+"#,
+    );
+    let Some(mut daemon) = sandbox.spawn(&["daemon"]) else {
+        return;
+    };
+    assert!(sandbox.wait_for_log(|line| line.starts_with("nfqws ")));
+    assert!(sandbox.wait_for_log(|_| sandbox.dir().join("zapret-ux.sock").exists()));
+
+    let list = sandbox.run(&["profile"]).unwrap();
+    assert_eq!(stdout(&list), "* auto\n  alt\n", "{}", stderr(&list));
+    let unknown = sandbox.run(&["profile", "missing"]).unwrap();
+    assert!(stderr(&unknown).contains("Unknown profile 'missing'"));
+
+    sandbox.clear_log();
+    let force = sandbox.run(&["profile", "alt"]).unwrap();
+    assert_eq!(stdout(&force), "Profile: alt\n", "{}", stderr(&force));
+    assert!(sandbox.log().last().unwrap().ends_with("--profile=alt"));
+    // Forced profile survives a reload
+    sandbox.clear_log();
+    assert!(sandbox.run(&["reload"]).unwrap().status.success());
+    assert!(sandbox.wait_for_log(|line| line.ends_with("--profile=alt")));
+    let list = sandbox.run(&["profile"]).unwrap();
+    assert_eq!(stdout(&list), "  auto\n* alt\n");
+
+    let logs = sandbox.run(&["logs", "-n", "1000"]).unwrap();
+    assert!(
+        stdout(&logs).contains("Received request request=profile alt"),
+        "{}",
+        stdout(&logs)
+    );
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+}
+
+/// Receives sd_notify datagrams on a socket passed as `NOTIFY_SOCKET`
+#[test]
+fn daemon_notifies_systemd() {
+    let mut sandbox = Sandbox::new("daemon_notifies_systemd");
+    let notify_path = sandbox.dir().join("notify.sock");
+    let notify = std::os::unix::net::UnixDatagram::bind(&notify_path).unwrap();
+    notify
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    sandbox
+        .env("NOTIFY_SOCKET", notify_path.to_str().unwrap())
+        .env("WATCHDOG_USEC", "200000");
+    let Some(mut daemon) = sandbox.spawn(&["daemon"]) else {
+        return;
+    };
+    let receive = |expected: &str| {
+        let mut buf = [0; 256];
+        for _ in 0..20 {
+            let len = notify.recv(&mut buf).expect(expected);
+            let state = String::from_utf8_lossy(&buf[..len]).into_owned();
+            if state == expected {
+                return;
+            }
+        }
+        panic!("{} was not received", expected);
+    };
+    receive("READY=1\nSTATUS=Running");
+    receive("WATCHDOG=1");
+
+    // Dead nfqws stops the watchdog so systemd restarts the service
+    std::fs::remove_file(sandbox.state_dir().join("nfqws.running")).unwrap();
+    receive("STATUS=nfqws is not running");
+    std::fs::write(sandbox.state_dir().join("nfqws.running"), "").unwrap();
+    receive("WATCHDOG=1");
+
+    let stop = sandbox.run(&["stop"]).unwrap();
+    assert!(stop.status.success(), "{}", stderr(&stop));
+    receive("STATUS=Stopped");
+    daemon.kill().unwrap();
+    daemon.wait().unwrap();
+}
+
+#[test]
+fn systemd_install_writes_unit() {
+    let sandbox = Sandbox::new("systemd_install_writes_unit");
+    let dir = sandbox.dir().join("units");
+    let Some(output) = sandbox.run(&["systemd", "install", "--dir", dir.to_str().unwrap()]) else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+    let unit = std::fs::read_to_string(dir.join("zapret-ux.service")).unwrap();
+    let command = format!("{} -c {}", bin(), sandbox.config_path().display());
+    assert!(unit.contains("Type=notify\n"), "{unit}");
+    assert!(unit.contains("WatchdogSec="), "{unit}");
+    assert!(
+        unit.contains(&format!("ExecStart={command} daemon\n")),
+        "{unit}"
+    );
+    assert!(
+        unit.contains(&format!("ExecReload={command} reload\n")),
+        "{unit}"
+    );
+    assert!(
+        unit.contains(&format!("ExecStopPost=-{command} stop\n")),
+        "{unit}"
+    );
+
+    // Quoted in the ExecStart= syntax, not the shell one
+    let config = sandbox.dir().join("it's \"%h\" $HOME.toml");
+    std::fs::copy(sandbox.config_path(), &config).unwrap();
+    let output = root_command()
+        .unwrap()
+        .args(["-c", config.to_str().unwrap()])
+        .args(["systemd", "install", "--dir", dir.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let unit = std::fs::read_to_string(dir.join("zapret-ux.service")).unwrap();
+    let config = format!(
+        "\"{}/it's \\\"%%h\\\" $$HOME.toml\"",
+        sandbox.dir().display()
+    );
+    assert!(
+        unit.contains(&format!("ExecStart={} -c {config} daemon\n", bin())),
+        "{unit}"
+    );
+}
+
+#[test]
+fn reload_requires_daemon() {
+    let sandbox = Sandbox::new("reload_requires_daemon");
+    let Some(output) = sandbox.run(&["reload"]) else {
+        return;
+    };
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("No daemon is running"));
+}
+
+fn autostart_sandbox(name: &str) -> Sandbox {
+    let sandbox = Sandbox::new(name);
+    sandbox.append_config(&format!(
+        "[autostart]\ntimeout_secs = 5\npoll_interval_ms = 20\ndefault_route = false\n\
+         xtables_lock_path = \"{}/xtables.lock\"\nretries = 1\nbackoff_ms = 1\n",
+        sandbox.dir().display()
+    ));
+    sandbox
+}
+
+#[test]
+fn autostart_waits_for_readiness() {
+    let sandbox = autostart_sandbox("autostart_waits_for_readiness");
+    let nfqws = sandbox.dir().join("nfqws");
+    sandbox.edit_config(
+        &format!("{}/nfqws", Sandbox::fakes_dir().display()),
+        nfqws.to_str().unwrap(),
+    );
+    let lock = std::fs::File::create(sandbox.dir().join("xtables.lock")).unwrap();
+    rustix::fs::flock(&lock, rustix::fs::FlockOperation::LockExclusive).unwrap();
+    let Some(mut autostart) = sandbox.spawn(&["autostart"]) else {
+        return;
+    };
+
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    std::os::unix::fs::symlink(Sandbox::fakes_dir().join("nfqws"), &nfqws).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    assert!(autostart.try_wait().unwrap().is_none());
+    assert_eq!(sandbox.commands(), Vec::<String>::new());
+
+    drop(lock);
+    assert!(autostart.wait().unwrap().success());
+    assert_eq!(sandbox.commands(), start_commands());
+}
+
+#[test]
+fn autostart_times_out_naming_conditions() {
+    let sandbox = autostart_sandbox("autostart_times_out_naming_conditions");
+    sandbox.edit_config("timeout_secs = 5", "timeout_secs = 0");
+    sandbox.edit_config(
+        &format!("{}/nfqws", Sandbox::fakes_dir().display()),
+        "/nonexistent/nfqws",
+    );
+    let Some(output) = sandbox.run(&["autostart"]) else {
+        return;
+    };
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("Timed out after 0s waiting for: nfqws binary"),
+        "{}",
+        stderr(&output)
+    );
+    assert_eq!(sandbox.commands(), Vec::<String>::new());
+}
+
+#[test]
+fn autostart_gives_up_on_hanging_getprop() {
+    let mut sandbox = autostart_sandbox("autostart_gives_up_on_hanging_getprop");
+    sandbox.edit_config("command_timeout_secs = 5", "command_timeout_secs = 1");
+    sandbox.edit_config(
+        "timeout_secs = 5",
+        "timeout_secs = 0\nboot_completed = true",
+    );
+    let path = format!(
+        "{}:{}",
+        Sandbox::fakes_dir().display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+    sandbox.env("PATH", &path).env("FAKE_GETPROP_SLEEP", "30");
+    let started = std::time::Instant::now();
+    let Some(output) = sandbox.run(&["autostart"]) else {
+        return;
+    };
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("Timed out after 0s waiting for: boot completed"),
+        "{}",
+        stderr(&output)
+    );
+    assert_eq!(sandbox.commands(), ["getprop sys.boot_completed"]);
+}
+
+#[test]
+fn autostart_retries_setup() {
+    let mut sandbox = autostart_sandbox("autostart_retries_setup");
+    // Outlasts the xtables lock retries of the first attempt only
+    sandbox.env("FAKE_LOCK_FAILS", "4");
+    let Some(output) = sandbox.run(&["autostart"]) else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Autostart failed, retrying"));
+    assert!(sandbox.nfqws_running());
+    assert!(sandbox.commands().ends_with(&start_commands()));
+
+    let mut sandbox = autostart_sandbox("autostart_retries_setup");
+    sandbox.env("FAKE_NFQWS_FAIL", "1");
+    let output = sandbox.run(&["autostart"]).unwrap();
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("Autostart failed after 2 attempts"),
+        "{}",
+        stderr(&output)
+    );
+    let nfqws_runs = sandbox
+        .commands()
+        .iter()
+        .filter(|line| line.starts_with("nfqws "))
+        .count();
+    assert_eq!(nfqws_runs, 2);
+}
+
+/// Same settings as the sandbox TOML config, in OpenWrt UCI format
+#[test]
+fn uci_config_is_read() {
+    let sandbox = Sandbox::new("uci_config_is_read");
+    let config = format!(
+        "package zapret-ux
+
+config main 'main'
+\toption mark_supported '1'
+\toption autostart_enabled 'yes'
+\toption command_timeout_secs '5'
+
+config iptables 'iptables'
+\toption iptables_path '{fakes}/iptables'
+\toption connbytes_supported '1'
+\toption excluded_networks ''
+\tlist ports '80/tcp'
+\tlist ports '443/udp'
+
+config iptables_xtables_lock 'iptables_xtables_lock'
+\toption wait '1'
+\toption wait_interval '1000'
+\toption retries '3'
+\toption backoff_ms '1'
+
+# Comments and double quotes are allowed
+config nfqws 'nfqws'
+\toption nfqws_path \"{fakes}/nfqws\"
+\toption pgrep_path '{fakes}/pgrep'
+\toption pkill_path '{fakes}/pkill'
+\toption filter_mode 'none'
+\tlist opt '--filter-tcp=80'
+\tlist opt '--dpi-desync=fake'
+
+config daemon 'daemon'
+\toption socket_path '{dir}/zapret-ux.sock'
+
+config lock 'lock'
+\toption path '{dir}/zapret-ux.lock'
+",
+        fakes = Sandbox::fakes_dir().display(),
+        dir = sandbox.dir().display(),
+    );
+    std::fs::write(sandbox.config_path(), config).unwrap();
+    let Some(output) = sandbox.run(&["start"]) else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(sandbox.commands(), start_commands());
+
+    let apps = sandbox.run(&["apps", "add", "org.example"]).unwrap();
+    assert!(!apps.status.success());
+    assert!(
+        stderr(&apps).contains("is a UCI config"),
+        "{}",
+        stderr(&apps)
+    );
+}
+
+#[test]
+fn openwrt_install_writes_init_script() {
+    let sandbox = Sandbox::new("openwrt_install_writes_init_script");
+    let root = sandbox.dir().join("root");
+    let Some(output) = sandbox.run(&["openwrt", "install", "--root", root.to_str().unwrap()])
+    else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+    let init = std::fs::read_to_string(root.join("etc/init.d/zapret-ux")).unwrap();
+    assert!(init.starts_with("#!/bin/sh /etc/rc.common\n"), "{init}");
+    assert!(init.contains(&format!("PROG={}\n", bin())), "{init}");
+    assert!(init.contains("procd_set_param respawn"), "{init}");
+    assert!(
+        init.contains("procd_add_reload_trigger \"zapret-ux\""),
+        "{init}"
+    );
+    assert!(root.join("etc/zapret-ux/hosts.txt").exists());
+
+    // The generated UCI config loads back to the defaults
+    let config = root.join("etc/config/zapret-ux");
+    let output = Command::new(bin())
+        .args(["--dry-run", "-c", config.to_str().unwrap(), "start"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains("--dport 50000:50099"),
+        "{}",
+        stdout(&output)
+    );
+    assert!(
+        std::fs::read_to_string(&config)
+            .unwrap()
+            .contains("option socket_path '/var/run/zapret-ux.sock'")
+    );
+}
+
+#[test]
+fn module_build_is_reproducible() {
+    let sandbox = Sandbox::new("module_build_is_reproducible");
+    let dir = sandbox.dir();
+    std::fs::write(dir.join("arm64"), "arm64 binary").unwrap();
+    std::fs::write(dir.join("x64"), "x64 binary").unwrap();
+    let build = |output: &str| {
+        let output = Command::new(bin())
+            .args(["module", "build", "--output", output])
+            .args(["--binary", "arm64=arm64", "--binary", "x64=x64"])
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", stderr(&output));
+    };
+    build("first.zip");
+    build("second.zip");
+    let first = std::fs::read(dir.join("first.zip")).unwrap();
+    assert_eq!(first, std::fs::read(dir.join("second.zip")).unwrap());
+
+    if which("unzip").is_none() {
+        eprintln!("Skipping zip listing: unzip is unavailable");
+        return;
+    }
+    let listing = Command::new("unzip")
+        .args(["-Z1", "first.zip"])
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    assert!(listing.status.success(), "{}", stderr(&listing));
+    let entries: Vec<String> = stdout(&listing).lines().map(String::from).collect();
+    for entry in [
+        "META-INF/com/google/android/update-binary",
+        "META-INF/com/google/android/updater-script",
+        "bin/arm64/zapret-ux",
+        "bin/x64/zapret-ux",
+        "customize.sh",
+        "defaults/config.toml",
+        "defaults/hosts.txt",
+        "module.prop",
+        "service.sh",
+        "action.sh",
+        "uninstall.sh",
+    ] {
+        assert!(
+            entries.iter().any(|e| e == entry),
+            "{entry}: {:#?}",
+            entries
+        );
+    }
+    let mut sorted = entries.clone();
+    sorted.sort();
+    assert_eq!(entries, sorted);
+
+    let prop = Command::new("unzip")
+        .args(["-p", "first.zip", "module.prop"])
+        .current_dir(dir)
+        .output()
+        .unwrap();
+    assert!(stdout(&prop).contains(&format!("version=v{}", env!("CARGO_PKG_VERSION"))));
+}
+
+#[test]
+fn module_install_keeps_settings() {
+    let sandbox = Sandbox::new("module_install_keeps_settings");
+    let root = sandbox.dir().join("adb");
+    std::fs::create_dir_all(root.join("zapret-ux")).unwrap();
+    std::fs::write(root.join("zapret-ux/hosts.txt"), "example.com\n").unwrap();
+    let Some(output) = sandbox.run(&["module", "install", "--root", root.to_str().unwrap()]) else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let module = root.join("modules_update/zapret-ux");
+    for file in [
+        "module.prop",
+        "service.sh",
+        "action.sh",
+        "uninstall.sh",
+        "zapret-ux",
+    ] {
+        assert!(module.join(file).exists(), "{file} is missing");
+    }
+    assert!(!module.join("customize.sh").exists());
+    assert_eq!(
+        std::fs::read(module.join("zapret-ux")).unwrap(),
+        std::fs::read(bin()).unwrap()
+    );
+    assert!(root.join("modules/zapret-ux/update").exists());
+
+    let data = root.join("zapret-ux");
+    assert_eq!(
+        std::fs::read_to_string(data.join("hosts.txt")).unwrap(),
+        "example.com\n"
+    );
+    let config = std::fs::read_to_string(data.join("config.toml")).unwrap();
+    assert!(
+        config.contains("/data/adb/zapret-ux/hosts-exclude.txt"),
+        "{config}"
+    );
+    assert!(
+        config.contains("path = \"/dev/zapret-ux.lock\""),
+        "{config}"
+    );
+}
+
+/// Adds a default route through loopback between two starts in a fresh network namespace
+#[test]
+fn profile_is_selected_by_uplink() {
+    if which("ip").is_none() || !unshare_available(&["--user", "--map-root-user", "--net"]) {
+        eprintln!("Skipping: ip or network namespaces are unavailable");
+        return;
+    }
+    let sandbox = Sandbox::new("profile_is_selected_by_uplink");
+    sandbox.append_config(
+        r#"[[profiles]]
+name = "mobile"
+when = { interface = "rmnet+" }
+opt = ["--profile=mobile"]
+
+[[profiles]]
+name = "loopback"
+when = { interface = "lo", ipv6 = false }
+ports = [{ port = 8080, protocol = "tcp" }]
+opt = ["--profile=loopback"]
+"#,
+    );
+    let script = format!(
+        "set -e
+        {bin} -c {config} start
+        {bin} -c {config} stop
+        ip link set lo up
+        ip route add default dev lo
+        {bin} -c {config} start",
+        bin = bin(),
+        config = sandbox.config_path().display(),
+    );
+    let output = Command::new("unshare")
+        .args(["--user", "--map-root-user", "--net", "sh", "-c", &script])
+        .env("FAKE_STATE", sandbox.state_dir())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let commands = sandbox.commands();
+    let nfqws: Vec<&String> = commands
+        .iter()
+        .filter(|line| line.starts_with("nfqws "))
+        .collect();
+    assert_eq!(nfqws.len(), 2, "{:#?}", commands);
+    assert!(nfqws[0].contains("--dpi-desync=fake"), "{:#?}", nfqws);
+    assert!(nfqws[1].contains("--profile=loopback"), "{:#?}", nfqws);
+    assert!(
+        commands
+            .iter()
+            .any(|line| line.contains("--dport 8080") && line.contains("--append ZAPRET_UX")),
+        "{:#?}",
+        commands
+    );
+}
+
+/// Runs start/stop against the real iptables inside an unprivileged user+network namespace
+#[test]
+fn real_iptables_in_network_namespace() {
+    let Some(iptables) = which("iptables") else {
+        eprintln!("Skipping: iptables is not installed");
+        return;
+    };
+    if !unshare_available(&["--user", "--map-root-user", "--net"]) {
+        eprintln!("Skipping: user and network namespaces are unavailable");
+        return;
+    }
+
+    let sandbox = Sandbox::new("real_iptables_in_network_namespace");
+    let fake_iptables = Sandbox::fakes_dir().join("iptables");
+    sandbox.edit_config(&fake_iptables.display().to_string(), &iptables);
+
+    let script = format!(
+        "set -e
+        {bin} -c {config} start
+        {iptables} -t mangle -S ZAPRET_UX
+        {iptables} -t mangle -S POSTROUTING
+        {bin} -c {config} stop
+        ! {iptables} -t mangle -S ZAPRET_UX 2>/dev/null",
+        bin = bin(),
+        config = sandbox.config_path().display(),
+    );
+    let output = Command::new("unshare")
+        .args(["--user", "--map-root-user", "--net", "sh", "-c", &script])
+        .env("FAKE_STATE", sandbox.state_dir())
+        .output()
+        .unwrap();
+    if stderr(&output).contains("Permission denied (you must be root)")
+        || stderr(&output).contains("can't initialize iptables table")
+    {
+        eprintln!("Skipping: iptables is not usable in the namespace");
+        return;
+    }
+    assert!(output.status.success(), "{}", stderr(&output));
+    let rules = stdout(&output);
+    assert!(
+        rules.contains("-A ZAPRET_UX -p tcp -m tcp --dport 80"),
+        "{}",
+        rules
+    );
+    assert!(rules.contains("-A POSTROUTING -j ZAPRET_UX"), "{}", rules);
+}
+
+fn which(program: &str) -> Option<String> {
+    let output = Command::new("sh")
+        .args(["-c", &format!("command -v {}", program)])
+        .output()
+        .ok()?;
+    let path = stdout(&output).trim().to_string();
+    (output.status.success() && !path.is_empty()).then_some(path)
+}
@@ -0,0 +1,207 @@
+//! Harness running the real zapret-ux binary against stand-in executables from `tests/fakes`
+
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Child, Command, Output, Stdio},
+};
+
+const BIN: &str = env!("CARGO_BIN_EXE_zapret-ux");
+
+/// Isolated directory with a config pointing at the fake binaries and their state
+pub struct Sandbox {
+    dir: PathBuf,
+    env: Vec<(String, String)>,
+}
+
+impl Sandbox {
+    pub fn new(name: &str) -> Self {
+        let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("state")).unwrap();
+        let sandbox = Self {
+            dir,
+            env: Vec::new(),
+        };
+        sandbox.write_config();
+        sandbox
+    }
+
+    pub fn fakes_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fakes")
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn state_dir(&self) -> PathBuf {
+        self.dir.join("state")
+    }
+
+    pub fn config_path(&self) -> PathBuf {
+        self.dir.join("config.toml")
+    }
+
+    /// Write config using the fake binaries
+    pub fn write_config(&self) {
+        let fakes = Self::fakes_dir();
+        let config = format!(
+            r#"mark_supported = true
+autostart_enabled = true
+command_timeout_secs = 5
+
+[iptables]
+iptables_path = "{fakes}/iptables"
+connbytes_supported = true
+excluded_networks = []
+ports = [
+    {{ port = 80, protocol = "tcp" }},
+    {{ port = 443, protocol = "udp" }},
+]
+
+[iptables.xtables_lock]
+wait = 1
+wait_interval = 1000
+retries = 3
+backoff_ms = 1
+
+[nfqws]
+nfqws_path = "{fakes}/nfqws"
+pgrep_path = "{fakes}/pgrep"
+pkill_path = "{fakes}/pkill"
+filter_mode = "none"
+opt = ["--filter-tcp=80", "--dpi-desync=fake"]
+
+[daemon]
+socket_path = "{dir}/zapret-ux.sock"
+
+[lock]
+path = "{dir}/zapret-ux.lock"
+wait_secs = 5
+"#,
+            fakes = fakes.display(),
+            dir = self.dir.display(),
+        );
+        fs::write(self.config_path(), config).unwrap();
+    }
+
+    /// Replace text in the config
+    pub fn edit_config(&self, from: &str, to: &str) {
+        let config = fs::read_to_string(self.config_path()).unwrap();
+        assert!(config.contains(from), "'{}' is not in config", from);
+        fs::write(self.config_path(), config.replace(from, to)).unwrap();
+    }
+
+    /// Append text (usually a new table) to the config
+    pub fn append_config(&self, text: &str) {
+        let mut config = fs::read_to_string(self.config_path()).unwrap();
+        config.push('\n');
+        config.push_str(text);
+        fs::write(self.config_path(), config).unwrap();
+    }
+
+    /// Set environment variable for the fake binaries
+    pub fn env(&mut self, key: &str, value: &str) -> &mut Self {
+        self.env.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Run zapret-ux with the sandbox config. Returns `None` when root
+    /// (or a user namespace to fake it) is not available
+    pub fn run(&self, args: &[&str]) -> Option<Output> {
+        Some(self.command(args)?.output().unwrap())
+    }
+
+    /// Start zapret-ux in the background, e.g. for long-running commands
+    pub fn spawn(&self, args: &[&str]) -> Option<Child> {
+        let mut cmd = self.command(args)?;
+        cmd.stdout(Stdio::null()).stderr(Stdio::null());
+        Some(cmd.spawn().unwrap())
+    }
+
+    /// Wait until the fakes log a line satisfying `predicate`
+    pub fn wait_for_log<P: Fn(&str) -> bool>(&self, predicate: P) -> bool {
+        for _ in 0..500 {
+            if self.log().iter().any(|line| predicate(line)) {
+                return true;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        false
+    }
+
+    fn command(&self, args: &[&str]) -> Option<Command> {
+        let mut cmd = root_command()?;
+        cmd.arg("-c")
+            .arg(self.config_path())
+            .args(args)
+            .env("FAKE_STATE", self.state_dir())
+            .env("RUST_LOG", "info");
+        for (key, value) in &self.env {
+            cmd.env(key, value);
+        }
+        Some(cmd)
+    }
+
+    /// Commands executed by the fakes, in order
+    pub fn log(&self) -> Vec<String> {
+        fs::read_to_string(self.state_dir().join("log"))
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    /// Commands executed by the fakes without the version probes
+    pub fn commands(&self) -> Vec<String> {
+        self.log()
+            .into_iter()
+            .filter(|line| !line.ends_with("--version"))
+            .collect()
+    }
+
+    pub fn clear_log(&self) {
+        let _ = fs::remove_file(self.state_dir().join("log"));
+    }
+
+    pub fn nfqws_running(&self) -> bool {
+        self.state_dir().join("nfqws.running").exists()
+    }
+}
+
+/// Command starting zapret-ux as root, directly or inside a user namespace
+pub fn root_command() -> Option<Command> {
+    if rustix::process::geteuid().is_root() {
+        return Some(Command::new(BIN));
+    }
+    if !unshare_available(&["--user", "--map-root-user"]) {
+        eprintln!("Skipping: not root and user namespaces are unavailable");
+        return None;
+    }
+    let mut cmd = Command::new("unshare");
+    cmd.args(["--user", "--map-root-user", BIN]);
+    Some(cmd)
+}
+
+pub fn unshare_available(flags: &[&str]) -> bool {
+    Command::new("unshare")
+        .args(flags)
+        .arg("true")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+pub fn bin() -> &'static str {
+    BIN
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
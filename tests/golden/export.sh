@@ -0,0 +1,33 @@
+#!/bin/sh
+# Generated by zapret-ux {VERSION} (format: sh)
+set -e
+
+start() {
+    {FAKES}/iptables --table mangle --new ZAPRET_UX
+    {FAKES}/iptables --table filter --new ZAPRET_UX_QUIC
+    {FAKES}/iptables --table mangle --append ZAPRET_UX --destination 10.0.0.0/8 --jump RETURN
+    {FAKES}/iptables --table mangle --append ZAPRET_UX --protocol tcp --match tcp --dport 80 --match mark ! --mark 0x40000000/0x40000000 --match connbytes --connbytes 1:6 --connbytes-dir original --connbytes-mode packets --jump NFQUEUE --queue-num 200 --queue-bypass
+    {FAKES}/iptables --table mangle --append ZAPRET_UX --protocol udp --match udp --dport 443 --match mark ! --mark 0x40000000/0x40000000 --match connbytes --connbytes 1:6 --connbytes-dir original --connbytes-mode packets --jump NFQUEUE --queue-num 200 --queue-bypass
+    {FAKES}/iptables --table filter --append ZAPRET_UX_QUIC --destination 10.0.0.0/8 --jump RETURN
+    {FAKES}/iptables --table filter --append ZAPRET_UX_QUIC --protocol udp --match udp --dport 443 --jump REJECT
+    {FAKES}/iptables --table filter --insert OUTPUT --jump ZAPRET_UX_QUIC
+    {FAKES}/iptables --table mangle --insert POSTROUTING --jump ZAPRET_UX
+    {FAKES}/nfqws --debug 1 --daemon --qnum 200 --uid 0:0 --dpi-desync-fwmark 0x40000000 --filter-tcp=80 --dpi-desync=fake '--dpi-desync-fake-http=GET / HTTP/1.1' '--hostlist-domains=it'\''s.example'
+}
+
+stop() {
+    {FAKES}/iptables --table mangle --delete POSTROUTING --jump ZAPRET_UX || true
+    {FAKES}/iptables --table filter --delete OUTPUT --jump ZAPRET_UX_QUIC || true
+    {FAKES}/iptables --table mangle --flush ZAPRET_UX || true
+    {FAKES}/iptables --table mangle --delete-chain ZAPRET_UX || true
+    {FAKES}/iptables --table filter --flush ZAPRET_UX_QUIC || true
+    {FAKES}/iptables --table filter --delete-chain ZAPRET_UX_QUIC || true
+    {FAKES}/pkill nfqws || true
+}
+
+case "$1" in
+    start) start ;;
+    stop) stop ;;
+    restart) stop; start ;;
+    *) echo "Usage: $0 {start|stop|restart}" >&2; exit 1 ;;
+esac
@@ -0,0 +1,142 @@
+#![cfg(feature = "webui")]
+
+mod common;
+
+use common::*;
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+/// Send raw HTTP request, returning the status line and the body
+fn http(port: u16, request: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_string(), body.to_string())
+}
+
+fn post(port: u16, host: &str, header: bool, body: &str) -> (String, String) {
+    let header = if header { "X-Zapret-Ux: 1\r\n" } else { "" };
+    http(
+        port,
+        &format!(
+            "POST /api/request HTTP/1.1\r\nHost: {}\r\n{}Content-Length: {}\r\n\r\n{}",
+            host,
+            header,
+            body.len(),
+            body
+        ),
+    )
+}
+
+#[test]
+fn webui_serves_page_and_requests() {
+    let sandbox = Sandbox::new("webui_serves_page_and_requests");
+    sandbox.append_config(&format!(
+        "[nfqws.hostlists]\nhosts = \"{}/hosts.txt\"\n",
+        sandbox.dir().display()
+    ));
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let listen = format!("127.0.0.1:{}", port);
+    let Some(mut webui) = sandbox.spawn(&["webui", "serve", "--listen", &listen]) else {
+        return;
+    };
+    for _ in 0..500 {
+        if TcpStream::connect(&listen).is_ok() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    let (status, page) = http(port, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(page.contains("const CLI = /*CLI*/null;"));
+
+    let host = format!("localhost:{}", port);
+    assert_eq!(
+        post(port, &host, false, "status").0,
+        "HTTP/1.1 403 Forbidden"
+    );
+    assert_eq!(
+        post(port, "evil.example:80", true, "status").0,
+        "HTTP/1.1 403 Forbidden"
+    );
+    assert_eq!(
+        post(port, &host, true, "status"),
+        (
+            "HTTP/1.1 200 OK".to_string(),
+            "Daemon is not running".to_string()
+        )
+    );
+    assert_eq!(
+        post(port, &host, true, "hosts add example.com").1,
+        "Added 1 hosts"
+    );
+    let (status, error) = post(port, &host, true, "profile alt");
+    assert_eq!(status, "HTTP/1.1 500 Internal Server Error");
+    assert_eq!(error, "Selecting a profile needs a running daemon");
+    webui.kill().unwrap();
+    webui.wait().unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(sandbox.dir().join("hosts.txt")).unwrap(),
+        "example.com\n"
+    );
+}
+
+#[test]
+fn webui_refuses_non_loopback_address() {
+    let sandbox = Sandbox::new("webui_refuses_non_loopback_address");
+    let Some(output) = sandbox.run(&["webui", "serve", "--listen", "0.0.0.0:8787"]) else {
+        return;
+    };
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("Web UI must listen on a loopback address, got 0.0.0.0:8787"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn webui_serve_rejects_dry_run() {
+    let sandbox = Sandbox::new("webui_serve_rejects_dry_run");
+    let Some(output) = sandbox.run(&["--dry-run", "webui", "serve"]) else {
+        return;
+    };
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("webui serve can't be used with --dry-run"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn webui_export_calls_cli() {
+    let sandbox = Sandbox::new("webui_export_calls_cli");
+    let webroot = sandbox.dir().join("webroot");
+    let Some(output) = sandbox.run(&[
+        "webui",
+        "export",
+        webroot.to_str().unwrap(),
+        "--bin",
+        "/data/adb/modules/zapret-ux/zapret-ux",
+    ]) else {
+        return;
+    };
+    assert!(output.status.success(), "{}", stderr(&output));
+    let page = std::fs::read_to_string(webroot.join("index.html")).unwrap();
+    let cli = format!(
+        "const CLI = \"/data/adb/modules/zapret-ux/zapret-ux -c {}\";",
+        sandbox.config_path().display()
+    );
+    assert!(page.contains(&cli), "{}", page);
+}